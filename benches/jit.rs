@@ -0,0 +1,36 @@
+use brainv::compiler::Compiler;
+use brainv::io::MemoryIO;
+use brainv::jit::JIT;
+use brainv::runtime::Runtime;
+use criterion::{criterion_group, criterion_main, Criterion};
+
+fn jit_run(program: &str, input: Vec<u8>) -> Vec<u8> {
+    let code = JIT::new(Compiler::new(program).compile()).compile().unwrap();
+    let mut runtime = Runtime::new(MemoryIO::new(input), code);
+    runtime.run().unwrap();
+    runtime.into_io().into_output()
+}
+
+pub fn criterion_benchmark(c: &mut Criterion) {
+    // The generated code only runs on AArch64. This bench is meant to record what
+    // keeping the cell in a register gains, but it has not been run on AArch64
+    // hardware yet, so there are no before and after timings. The emitter from
+    // before register caching can't serve as the baseline either: it stored the
+    // cell at [x19, #5] and ',' into the read function pointer, so its code did
+    // not run correctly. For reference, the emulator runs mandelbrot-tiny in
+    // 2,030,168,201 instructions and primes up to 350 in 294,806,977.
+    if !cfg!(target_arch = "aarch64") {
+        return;
+    }
+
+    let mut group = c.benchmark_group("jit");
+    group.sample_size(10);
+    let mandelbrot = include_str!("bf/mandelbrot-tiny.bf");
+    group.bench_function("mandelbrot-tiny", |b| b.iter(|| jit_run(mandelbrot, vec![])));
+    let primes = include_str!("bf/primes.bf");
+    group.bench_function("primes", |b| b.iter(|| jit_run(primes, "350\n".as_bytes().into())));
+    group.finish();
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
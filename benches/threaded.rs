@@ -0,0 +1,23 @@
+use brainv::compiler::Compiler;
+use brainv::io::MemoryIO;
+use brainv::threaded::Threaded;
+use criterion::{criterion_group, criterion_main, Criterion};
+
+fn threaded_run(program: &str, input: Vec<u8>) -> Vec<u8> {
+    let mut threaded = Threaded::new(MemoryIO::new(input), Compiler::new(program).compile());
+    threaded.run();
+    threaded.into_io().into_output()
+}
+
+pub fn criterion_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("threaded");
+    group.sample_size(10);
+    let mandelbrot = include_str!("bf/mandelbrot-tiny.bf");
+    group.bench_function("mandelbrot-tiny", |b| b.iter(|| threaded_run(mandelbrot, vec![])));
+    let primes = include_str!("bf/primes.bf");
+    group.bench_function("primes", |b| b.iter(|| threaded_run(primes, "350\n".as_bytes().into())));
+    group.finish();
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
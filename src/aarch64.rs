@@ -0,0 +1,275 @@
+// AArch64 instruction encodings used by the JIT
+//
+// Only the handful of instructions the JIT emits, each returning the 32-bit
+// instruction word. Registers are plain numbers, 31 is sp or zr depending on
+// the instruction.
+
+pub(crate) const SP: u32 = 31;
+
+/// Register assignment inside generated code
+pub(crate) mod reg {
+    /// Current tape cell pointer
+    pub const TAPE: u32 = 19;
+    /// Runtime pointer, first argument to the IO functions
+    pub const RT: u32 = 20;
+    pub const WRITE_FN: u32 = 21;
+    pub const READ_FN: u32 = 22;
+    /// Cached value of the current cell
+    pub const CELL: u32 = 23;
+    /// Next free byte of the output buffer
+    pub const OUT: u32 = 24;
+    /// End of the output buffer
+    pub const OUT_END: u32 = 25;
+    /// Start of the tape
+    pub const TAPE_START: u32 = 26;
+    /// Remaining fuel in metered code
+    pub const FUEL: u32 = 27;
+    /// End of the tape
+    pub const TAPE_END: u32 = 28;
+    /// Intra-procedure-call scratch registers, used for indirect branches
+    pub const IP0: u32 = 16;
+    pub const IP1: u32 = 17;
+}
+
+/// ldrb wT, [xN, #imm]
+pub(crate) const fn ldrb(rt: u32, rn: u32, imm: u32) -> u32 {
+    0x39400000 | (imm << 10) | (rn << 5) | rt
+}
+
+/// strb wT, [xN, #imm]
+pub(crate) const fn strb(rt: u32, rn: u32, imm: u32) -> u32 {
+    0x39000000 | (imm << 10) | (rn << 5) | rt
+}
+
+/// strb wT, [xN], #imm
+pub(crate) const fn strb_post(rt: u32, rn: u32, imm: i32) -> u32 {
+    0x38000400 | (((imm as u32) & 0x1FF) << 12) | (rn << 5) | rt
+}
+
+/// ldr xT, [xN, #imm]
+pub(crate) const fn ldr_x(rt: u32, rn: u32, imm: u32) -> u32 {
+    0xF9400000 | ((imm / 8) << 10) | (rn << 5) | rt
+}
+
+/// str xT, [xN, #imm]
+pub(crate) const fn str_x(rt: u32, rn: u32, imm: u32) -> u32 {
+    0xF9000000 | ((imm / 8) << 10) | (rn << 5) | rt
+}
+
+/// add xD, xN, #imm
+pub(crate) const fn add_x(rd: u32, rn: u32, imm: u32) -> u32 {
+    0x91000000 | (imm << 10) | (rn << 5) | rd
+}
+
+/// sub xD, xN, #imm
+pub(crate) const fn sub_x(rd: u32, rn: u32, imm: u32) -> u32 {
+    0xD1000000 | (imm << 10) | (rn << 5) | rd
+}
+
+/// subs xD, xN, #imm
+pub(crate) const fn subs_x(rd: u32, rn: u32, imm: u32) -> u32 {
+    0xF1000000 | (imm << 10) | (rn << 5) | rd
+}
+
+/// add wD, wN, #imm
+pub(crate) const fn add_w(rd: u32, rn: u32, imm: u32) -> u32 {
+    0x11000000 | (imm << 10) | (rn << 5) | rd
+}
+
+/// sub wD, wN, #imm
+pub(crate) const fn sub_w(rd: u32, rn: u32, imm: u32) -> u32 {
+    0x51000000 | (imm << 10) | (rn << 5) | rd
+}
+
+/// and wD, wN, #0xff
+pub(crate) const fn uxtb_w(rd: u32, rn: u32) -> u32 {
+    0x12001C00 | (rn << 5) | rd
+}
+
+/// cbz wT, <offset>, offset in bytes from this instruction
+pub(crate) const fn cbz_w(rt: u32, offset: i64) -> u32 {
+    0x34000000 | ((((offset / 4) as u32) & 0x7FFFF) << 5) | rt
+}
+
+/// cbnz wT, <offset>, offset in bytes from this instruction
+pub(crate) const fn cbnz_w(rt: u32, offset: i64) -> u32 {
+    0x35000000 | ((((offset / 4) as u32) & 0x7FFFF) << 5) | rt
+}
+
+/// cmp xN, xM
+pub(crate) const fn cmp_x(rn: u32, rm: u32) -> u32 {
+    0xEB00001F | (rm << 16) | (rn << 5)
+}
+
+/// b.cond <offset>, offset in bytes from this instruction
+const fn b_cond(cond: u32, offset: i64) -> u32 {
+    0x54000000 | ((((offset / 4) as u32) & 0x7FFFF) << 5) | cond
+}
+
+/// b.hs <offset>, unsigned higher or same
+pub(crate) const fn b_hs(offset: i64) -> u32 {
+    b_cond(0x2, offset)
+}
+
+/// b.lo <offset>, unsigned lower
+pub(crate) const fn b_lo(offset: i64) -> u32 {
+    b_cond(0x3, offset)
+}
+
+/// b.hi <offset>, unsigned higher
+pub(crate) const fn b_hi(offset: i64) -> u32 {
+    b_cond(0x8, offset)
+}
+
+/// b.pl <offset>, positive or zero
+pub(crate) const fn b_pl(offset: i64) -> u32 {
+    b_cond(0x5, offset)
+}
+
+/// cbz xT, <offset>, offset in bytes from this instruction
+pub(crate) const fn cbz_x(rt: u32, offset: i64) -> u32 {
+    0xB4000000 | ((((offset / 4) as u32) & 0x7FFFF) << 5) | rt
+}
+
+/// cbnz xT, <offset>, offset in bytes from this instruction
+pub(crate) const fn cbnz_x(rt: u32, offset: i64) -> u32 {
+    0xB5000000 | ((((offset / 4) as u32) & 0x7FFFF) << 5) | rt
+}
+
+/// blr xN
+pub(crate) const fn blr(rn: u32) -> u32 {
+    0xD63F0000 | (rn << 5)
+}
+
+/// ret
+pub(crate) const fn ret() -> u32 {
+    0xD65F03C0
+}
+
+/// stp xA, xB, [sp, #offset]! when pre_index, stp xA, xB, [sp, #offset] otherwise
+pub(crate) const fn stp_x(rt: u32, rt2: u32, offset: i32, pre_index: bool) -> u32 {
+    let base = if pre_index { 0xA9800000 } else { 0xA9000000 };
+    base | ((((offset / 8) as u32) & 0x7F) << 15) | (rt2 << 10) | (SP << 5) | rt
+}
+
+/// ldp xA, xB, [sp], #offset when post_index, ldp xA, xB, [sp, #offset] otherwise
+pub(crate) const fn ldp_x(rt: u32, rt2: u32, offset: i32, post_index: bool) -> u32 {
+    let base = if post_index { 0xA8C00000 } else { 0xA9400000 };
+    base | ((((offset / 8) as u32) & 0x7F) << 15) | (rt2 << 10) | (SP << 5) | rt
+}
+
+/// b <offset>, offset in bytes from this instruction
+pub(crate) const fn b(offset: i64) -> u32 {
+    0x14000000 | (((offset / 4) as u32) & 0x3FFFFFF)
+}
+
+/// bl <offset>, offset in bytes from this instruction
+pub(crate) const fn bl(offset: i64) -> u32 {
+    0x94000000 | (((offset / 4) as u32) & 0x3FFFFFF)
+}
+
+/// br xN
+pub(crate) const fn br(rn: u32) -> u32 {
+    0xD61F0000 | (rn << 5)
+}
+
+/// adr xD, #0, the address of this instruction
+pub(crate) const fn adr_here(rd: u32) -> u32 {
+    0x10000000 | rd
+}
+
+/// movz xD, #imm, lsl #(16 * hw)
+pub(crate) const fn movz_x(rd: u32, imm: u16, hw: u32) -> u32 {
+    0xD2800000 | (hw << 21) | ((imm as u32) << 5) | rd
+}
+
+/// movk xD, #imm, lsl #(16 * hw)
+pub(crate) const fn movk_x(rd: u32, imm: u16, hw: u32) -> u32 {
+    0xF2800000 | (hw << 21) | ((imm as u32) << 5) | rd
+}
+
+/// movz xD, #imm; movk xD, ... (x3), loading any 64-bit value in a fixed size
+pub(crate) const fn mov_x_imm64(rd: u32, imm: u64) -> [u32; 4] {
+    [
+        movz_x(rd, imm as u16, 0),
+        movk_x(rd, (imm >> 16) as u16, 1),
+        movk_x(rd, (imm >> 32) as u16, 2),
+        movk_x(rd, (imm >> 48) as u16, 3),
+    ]
+}
+
+/// add xD, xN, xM
+pub(crate) const fn add_x_reg(rd: u32, rn: u32, rm: u32) -> u32 {
+    0x8B000000 | (rm << 16) | (rn << 5) | rd
+}
+
+/// sub xD, xN, xM
+pub(crate) const fn sub_x_reg(rd: u32, rn: u32, rm: u32) -> u32 {
+    0xCB000000 | (rm << 16) | (rn << 5) | rd
+}
+
+/// add xD, xN, xM, lsr #shift
+pub(crate) const fn add_x_lsr(rd: u32, rn: u32, rm: u32, shift: u32) -> u32 {
+    0x8B400000 | (rm << 16) | (shift << 10) | (rn << 5) | rd
+}
+
+/// sub xD, xN, xM, lsr #shift
+pub(crate) const fn sub_x_lsr(rd: u32, rn: u32, rm: u32, shift: u32) -> u32 {
+    0xCB400000 | (rm << 16) | (shift << 10) | (rn << 5) | rd
+}
+
+/// rbit xD, xN
+pub(crate) const fn rbit_x(rd: u32, rn: u32) -> u32 {
+    0xDAC00000 | (rn << 5) | rd
+}
+
+/// clz xD, xN
+pub(crate) const fn clz_x(rd: u32, rn: u32) -> u32 {
+    0xDAC01000 | (rn << 5) | rd
+}
+
+/// ld1 {vT.16b}, [xN]
+pub(crate) const fn ld1_16b(vt: u32, rn: u32) -> u32 {
+    0x4C407000 | (rn << 5) | vt
+}
+
+/// cmeq vD.16b, vN.16b, #0
+pub(crate) const fn cmeq_zero_16b(vd: u32, vn: u32) -> u32 {
+    0x4E209800 | (vn << 5) | vd
+}
+
+/// and vD.16b, vN.16b, vM.16b
+pub(crate) const fn and_16b(vd: u32, vn: u32, vm: u32) -> u32 {
+    0x4E201C00 | (vm << 16) | (vn << 5) | vd
+}
+
+/// shrn vD.8b, vN.8h, #4, packs a 16-byte compare result into 4 bits per byte
+pub(crate) const fn shrn_8b_4(vd: u32, vn: u32) -> u32 {
+    0x0F0C8400 | (vn << 5) | vd
+}
+
+/// fmov xD, dN
+pub(crate) const fn fmov_x_d(rd: u32, vn: u32) -> u32 {
+    0x9E660000 | (vn << 5) | rd
+}
+
+/// movi vD with 0xff in the lowest (or highest) byte of every group of stride
+/// bytes, stride is 2, 4 or 8
+pub(crate) const fn movi_lane_mask(vd: u32, stride: u8, highest: bool) -> u32 {
+    let base = match (stride, highest) {
+        (2, false) => 0x4F0787E0, // movi vD.8h, #0xff
+        (2, true) => 0x4F07A7E0,  // movi vD.8h, #0xff, lsl #8
+        (4, false) => 0x4F0707E0, // movi vD.4s, #0xff
+        (4, true) => 0x4F0767E0,  // movi vD.4s, #0xff, lsl #24
+        (8, false) => 0x6F00E420, // movi vD.2d, #0xff
+        (8, true) => 0x6F04E400,  // movi vD.2d, #0xff00000000000000
+        _ => panic!("no lane mask for this stride"),
+    };
+    base | vd
+}
+
+/// Whether a byte offset fits a branch with an immediate of the given width
+pub(crate) const fn branch_in_range(offset: i64, imm_bits: u32) -> bool {
+    let limit = 1i64 << (imm_bits + 1);
+    offset >= -limit && offset < limit
+}
@@ -0,0 +1,205 @@
+// GNU assembler source for a program
+//
+// Writes the optimized Ops as assembly for review and for trying things out with
+// the system toolchain. The output is a whole program for Linux: main points a
+// callee-saved register at a zeroed tape in .bss, runs the program and returns
+// 0, with '.' and ',' going through putchar and getchar of the C library. Every
+// Op gets a comment with the source it came from, and loops get the labels
+// loop_<offset>_start and loop_<offset>_end after the offset of their '['.
+//
+// Unlike the JIT there are no tape bounds checks, so the tape pointer must stay
+// on the tape of TAPE_CELLS cells. At the end of the input ',' leaves the cell
+// as it is.
+
+use std::fmt::Write;
+
+use crate::bytecode::TAPE_CELLS;
+use crate::vm::Op;
+
+/// Longest source snippet shown in a comment, longer ones are cut short
+const SNIPPET_LEN: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    X86_64,
+    Aarch64,
+}
+
+impl Target {
+    /// The target of this host, if there is one for it
+    pub fn host() -> Option<Self> {
+        match std::env::consts::ARCH {
+            "x86_64" => Some(Self::X86_64),
+            "aarch64" => Some(Self::Aarch64),
+            _ => None,
+        }
+    }
+
+    fn comment(self) -> &'static str {
+        match self {
+            Self::X86_64 => "#",
+            Self::Aarch64 => "//",
+        }
+    }
+}
+
+/// Assembly for the Ops from Compiler::compile_with_offsets of source
+pub fn emit(source: &str, code: &[Op], offsets: &[usize], target: Target) -> String {
+    assert_eq!(code.len(), offsets.len(), "Need one source offset per op");
+    let mut asm = String::new();
+    let comment = target.comment();
+    let _ = writeln!(asm, "{comment} Generated by brainv {}", env!("CARGO_PKG_VERSION"));
+    asm.push_str(prologue(target));
+
+    for (index, &op) in code.iter().enumerate() {
+        if let Op::Nop = op {
+            continue;
+        }
+        // Ops start at increasing offsets, except for a Nop sharing one with the next op
+        let end = offsets[index + 1..].iter().copied().find(|&end| end > offsets[index]).unwrap_or(source.len());
+        let _ = writeln!(asm, "    {comment} {}", snippet(&source[offsets[index]..end]));
+        let label = match op {
+            Op::JmpIfZ(_) => offsets[index],
+            Op::JmpIfNZ(start) => offsets[start as usize],
+            _ => 0,
+        };
+        let lines = match target {
+            Target::X86_64 => x86_64(op, label),
+            Target::Aarch64 => aarch64(op, label),
+        };
+        for line in lines {
+            if line.ends_with(':') {
+                let _ = writeln!(asm, "{line}");
+            } else {
+                let _ = writeln!(asm, "    {line}");
+            }
+        }
+    }
+
+    asm.push_str(&epilogue(target));
+    asm
+}
+
+/// The commands in a piece of source, without the comments between them
+fn snippet(source: &str) -> String {
+    let commands: String = source.chars().filter(|c| "+-<>[].,".contains(*c)).collect();
+    if commands.len() > SNIPPET_LEN {
+        format!("{}...", &commands[..SNIPPET_LEN])
+    } else {
+        commands
+    }
+}
+
+fn prologue(target: Target) -> &'static str {
+    match target {
+        // The tape pointer lives in rbx, pushing it also aligns the stack for calls
+        Target::X86_64 => concat!(
+            "    .text\n",
+            "    .globl main\n",
+            "    .type main, @function\n",
+            "main:\n",
+            "    pushq %rbx\n",
+            "    leaq tape(%rip), %rbx\n",
+        ),
+        // The tape pointer lives in x19
+        Target::Aarch64 => concat!(
+            "    .text\n",
+            "    .globl main\n",
+            "    .type main, %function\n",
+            "    .p2align 2\n",
+            "main:\n",
+            "    stp x29, x30, [sp, #-32]!\n",
+            "    mov x29, sp\n",
+            "    str x19, [sp, #16]\n",
+            "    adrp x19, tape\n",
+            "    add x19, x19, :lo12:tape\n",
+        ),
+    }
+}
+
+fn epilogue(target: Target) -> String {
+    let (ret, note) = match target {
+        Target::X86_64 => ("    xorl %eax, %eax\n    popq %rbx\n    ret\n", "@progbits"),
+        Target::Aarch64 => ("    mov w0, #0\n    ldr x19, [sp, #16]\n    ldp x29, x30, [sp], #32\n    ret\n", "%progbits"),
+    };
+    format!(
+        "{ret}    .size main, .-main\n\
+         \n    .bss\n    .p2align 4\ntape:\n    .zero {TAPE_CELLS}\n\
+         \n    .section .note.GNU-stack,\"\",{note}\n"
+    )
+}
+
+/// Instructions for an op, label is the source offset of the loop for jumps
+fn x86_64(op: Op, label: usize) -> Vec<String> {
+    match op {
+        Op::Nop => vec![],
+        Op::Inc(n) => vec![format!("addb ${n}, (%rbx)")],
+        Op::Dec(n) => vec![format!("subb ${n}, (%rbx)")],
+        Op::MovR(n) => vec![format!("addq ${n}, %rbx")],
+        Op::MovL(n) => vec![format!("subq ${n}, %rbx")],
+        Op::JmpIfZ(_) => vec![
+            "cmpb $0, (%rbx)".to_string(),
+            format!("je loop_{label}_end"),
+            format!("loop_{label}_start:"),
+        ],
+        Op::JmpIfNZ(_) => vec![
+            "cmpb $0, (%rbx)".to_string(),
+            format!("jne loop_{label}_start"),
+            format!("loop_{label}_end:"),
+        ],
+        Op::ScanR(n) | Op::ScanL(n) => vec![
+            "1:".to_string(),
+            "cmpb $0, (%rbx)".to_string(),
+            "je 2f".to_string(),
+            format!("{} ${n}, %rbx", if let Op::ScanR(_) = op { "addq" } else { "subq" }),
+            "jmp 1b".to_string(),
+            "2:".to_string(),
+        ],
+        Op::Print => vec!["movzbl (%rbx), %edi".to_string(), "call putchar@PLT".to_string()],
+        Op::Read => vec![
+            "call getchar@PLT".to_string(),
+            "cmpl $-1, %eax".to_string(),
+            "je 1f".to_string(),
+            "movb %al, (%rbx)".to_string(),
+            "1:".to_string(),
+        ],
+    }
+}
+
+/// Instructions for an op, label is the source offset of the loop for jumps
+fn aarch64(op: Op, label: usize) -> Vec<String> {
+    let add = |instr: &str, n: u8| vec!["ldrb w0, [x19]".to_string(), format!("{instr} w0, w0, #{n}"), "strb w0, [x19]".to_string()];
+    match op {
+        Op::Nop => vec![],
+        Op::Inc(n) => add("add", n),
+        Op::Dec(n) => add("sub", n),
+        Op::MovR(n) => vec![format!("add x19, x19, #{n}")],
+        Op::MovL(n) => vec![format!("sub x19, x19, #{n}")],
+        Op::JmpIfZ(_) => vec![
+            "ldrb w0, [x19]".to_string(),
+            format!("cbz w0, loop_{label}_end"),
+            format!("loop_{label}_start:"),
+        ],
+        Op::JmpIfNZ(_) => vec![
+            "ldrb w0, [x19]".to_string(),
+            format!("cbnz w0, loop_{label}_start"),
+            format!("loop_{label}_end:"),
+        ],
+        Op::ScanR(n) | Op::ScanL(n) => vec![
+            "1:".to_string(),
+            "ldrb w0, [x19]".to_string(),
+            "cbz w0, 2f".to_string(),
+            format!("{} x19, x19, #{n}", if let Op::ScanR(_) = op { "add" } else { "sub" }),
+            "b 1b".to_string(),
+            "2:".to_string(),
+        ],
+        Op::Print => vec!["ldrb w0, [x19]".to_string(), "bl putchar".to_string()],
+        Op::Read => vec![
+            "bl getchar".to_string(),
+            "cmn w0, #1".to_string(),
+            "b.eq 1f".to_string(),
+            "strb w0, [x19]".to_string(),
+            "1:".to_string(),
+        ],
+    }
+}
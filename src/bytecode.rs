@@ -0,0 +1,192 @@
+// Compiled bytecode files
+//
+// A .bfc file holds the optimized Ops of a program together with where each one
+// starts in the source, so it can be run without parsing and optimizing the
+// source again. The header records the cell width and tape size the program was
+// compiled for, and files that don't match this build are rejected on load.
+//
+// Format v1, integers are little endian:
+//   magic      b"BFC\0"
+//   version    u16
+//   cell bits  u8
+//   reserved   u8, zero
+//   tape cells u32, cells on the tape of the fixed size backends
+//   op count   u32
+//   ops        op count * (tag u8, argument u16), so at most MAX_OPS of them
+//   offsets    op count * u32, the source offset of each op
+//   checksum   u32, CRC-32 of everything before it
+
+use crate::vm::Op;
+
+const MAGIC: &[u8; 4] = b"BFC\0";
+const VERSION: u16 = 1;
+const HEADER_SIZE: usize = 16;
+const OP_SIZE: usize = 3;
+
+/// Width of a cell, the only one the backends implement
+pub const CELL_BITS: u8 = 8;
+/// Cells on the tape of the JIT backends, the Vm's tape grows as needed
+pub const TAPE_CELLS: u32 = 30000;
+/// Longest program a file can hold, jump targets are 16 bits like in Op
+pub const MAX_OPS: usize = u16::MAX as usize + 1;
+
+#[derive(Debug, Clone)]
+pub struct Bytecode {
+    pub code: Vec<Op>,
+    /// Where each op starts in the source, as returned by Compiler::compile_with_offsets
+    pub offsets: Vec<usize>,
+    pub cell_bits: u8,
+    pub tape_cells: u32,
+}
+
+impl Bytecode {
+    /// Fails for programs from_bytes would reject, so no file is written that can't be loaded
+    pub fn new(code: Vec<Op>, offsets: Vec<usize>) -> Result<Self, String> {
+        assert_eq!(code.len(), offsets.len(), "Every op needs a source offset");
+        if code.len() > MAX_OPS {
+            return Err(format!("the program has {} ops, bytecode files hold at most {MAX_OPS}", code.len()));
+        }
+        check_jumps(&code)?;
+        Ok(Self { code, offsets, cell_bits: CELL_BITS, tape_cells: TAPE_CELLS })
+    }
+
+    /// Whether the bytes start like a bytecode file rather than source
+    pub fn is_bytecode(bytes: &[u8]) -> bool {
+        bytes.starts_with(MAGIC)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + self.code.len() * (OP_SIZE + 4) + 4);
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.push(self.cell_bits);
+        bytes.push(0);
+        bytes.extend_from_slice(&self.tape_cells.to_le_bytes());
+        bytes.extend_from_slice(&(self.code.len() as u32).to_le_bytes());
+        for &op in &self.code {
+            let (tag, argument) = encode(op);
+            bytes.push(tag);
+            bytes.extend_from_slice(&argument.to_le_bytes());
+        }
+        for &offset in &self.offsets {
+            bytes.extend_from_slice(&(offset as u32).to_le_bytes());
+        }
+        let checksum = crc32(&bytes);
+        bytes.extend_from_slice(&checksum.to_le_bytes());
+        bytes
+    }
+
+    /// Load a file written by to_bytes, checking that it is intact and runs on this build
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        if !Self::is_bytecode(bytes) {
+            return Err("not a brainv bytecode file".to_string());
+        }
+        if bytes.len() < HEADER_SIZE + 4 {
+            return Err("bytecode file is truncated".to_string());
+        }
+        let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+        if version != VERSION {
+            return Err(format!("bytecode version {version} is not supported, expected {VERSION}"));
+        }
+
+        let (content, checksum) = bytes.split_at(bytes.len() - 4);
+        let checksum = u32::from_le_bytes(checksum.try_into().unwrap());
+        if crc32(content) != checksum {
+            return Err("bytecode checksum does not match, the file is corrupted".to_string());
+        }
+
+        let cell_bits = content[6];
+        if cell_bits != CELL_BITS {
+            return Err(format!("bytecode was compiled for {cell_bits}-bit cells, only {CELL_BITS}-bit cells are supported"));
+        }
+        let tape_cells = read_u32(content, 8);
+        if tape_cells != TAPE_CELLS {
+            return Err(format!("bytecode was compiled for a tape of {tape_cells} cells, expected {TAPE_CELLS}"));
+        }
+        let count = read_u32(content, 12) as usize;
+        if content.len() != HEADER_SIZE + count * (OP_SIZE + 4) {
+            return Err(format!("bytecode size does not match its {count} ops"));
+        }
+
+        let ops = &content[HEADER_SIZE..HEADER_SIZE + count * OP_SIZE];
+        let code = ops
+            .chunks_exact(OP_SIZE)
+            .enumerate()
+            .map(|(index, op)| {
+                decode(op[0], u16::from_le_bytes([op[1], op[2]]))
+                    .ok_or_else(|| format!("invalid op tag {} at index {index}", op[0]))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let offsets = (0..count).map(|index| read_u32(content, HEADER_SIZE + count * OP_SIZE + index * 4) as usize).collect();
+        check_jumps(&code)?;
+
+        Ok(Self { code, offsets, cell_bits, tape_cells })
+    }
+}
+
+fn encode(op: Op) -> (u8, u16) {
+    match op {
+        Op::Nop => (0, 0),
+        Op::Inc(n) => (1, n as u16),
+        Op::Dec(n) => (2, n as u16),
+        Op::MovR(n) => (3, n as u16),
+        Op::MovL(n) => (4, n as u16),
+        Op::JmpIfZ(target) => (5, target),
+        Op::JmpIfNZ(target) => (6, target),
+        Op::ScanR(n) => (7, n as u16),
+        Op::ScanL(n) => (8, n as u16),
+        Op::Print => (9, 0),
+        Op::Read => (10, 0),
+    }
+}
+
+fn decode(tag: u8, argument: u16) -> Option<Op> {
+    let byte = || u8::try_from(argument).ok();
+    Some(match tag {
+        0 => Op::Nop,
+        1 => Op::Inc(byte()?),
+        2 => Op::Dec(byte()?),
+        3 => Op::MovR(byte()?),
+        4 => Op::MovL(byte()?),
+        5 => Op::JmpIfZ(argument),
+        6 => Op::JmpIfNZ(argument),
+        7 => Op::ScanR(byte()?),
+        8 => Op::ScanL(byte()?),
+        9 => Op::Print,
+        10 => Op::Read,
+        _ => return None,
+    })
+}
+
+/// The backends trust the jump targets, so both ends of every loop have to point at each other
+fn check_jumps(code: &[Op]) -> Result<(), String> {
+    for (index, &op) in code.iter().enumerate() {
+        let matched = match op {
+            Op::JmpIfZ(target) => matches!(code.get(target as usize), Some(&Op::JmpIfNZ(back)) if back as usize == index),
+            Op::JmpIfNZ(target) => matches!(code.get(target as usize), Some(&Op::JmpIfZ(back)) if back as usize == index),
+            // Strides of 0 never move off a non-zero cell
+            Op::ScanR(0) | Op::ScanL(0) => false,
+            _ => true,
+        };
+        if !matched {
+            return Err(format!("invalid {op} at index {index}"));
+        }
+    }
+    Ok(())
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+/// CRC-32 as used by zip and PNG
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
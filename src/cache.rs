@@ -0,0 +1,262 @@
+// On-disk cache of JIT code
+//
+// Generated machine code is stored in a directory, one file per key. A key
+// covers the program, the backend, the options that change the generated code,
+// the brainv version and the JIT ABI version, so a new build never picks up code
+// from an old one. Every entry repeats its whole key, including the program
+// itself, and ends in a checksum. Entries that don't match their key or are
+// damaged are deleted when they are loaded, and count as stale in the stats
+// until then.
+//
+// Entry format, integers are little endian:
+//   magic        b"BFJC"
+//   version      u16, of this format
+//   reserved     u16, zero
+//   key          u32 length, then the key text
+//   program      u32 length, then the program the code was compiled from
+//   code         u32 length, then the machine code
+//   symbol count u32
+//   symbols      symbol count * (u16 name length, name, u32 offset, u32 length)
+//   checksum     u32, CRC-32 of everything before it
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::bytecode::crc32;
+use crate::jit::{ABI_VERSION, Symbol};
+
+const MAGIC: &[u8; 4] = b"BFJC";
+const VERSION: u16 = 2;
+const EXTENSION: &str = "bfjit";
+
+/// Identifies the code generated for a program
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheKey {
+    text: String,
+    program: Vec<u8>,
+}
+
+impl CacheKey {
+    /// The program is what the code was compiled from, source or bytecode. The
+    /// options are anything else that changes the generated code, e.g. metering.
+    pub fn new(program: &[u8], backend: &str, options: &str) -> Self {
+        let text = format!(
+            "brainv {}\nabi {ABI_VERSION}\nbackend {backend}\noptions {options}\nprogram {:016x} {}",
+            env!("CARGO_PKG_VERSION"),
+            fnv1a(program),
+            program.len()
+        );
+        Self { text, program: program.to_vec() }
+    }
+
+    fn file_name(&self) -> String {
+        format!("{:016x}.{EXTENSION}", fnv1a(self.text.as_bytes()))
+    }
+
+    /// Whether the key was made by this build of brainv
+    fn is_current(text: &str) -> bool {
+        let mut lines = text.lines();
+        lines.next() == Some(&format!("brainv {}", env!("CARGO_PKG_VERSION")))
+            && lines.next() == Some(&format!("abi {ABI_VERSION}"))
+    }
+}
+
+/// Code loaded from the cache
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CachedCode {
+    pub code: Vec<u8>,
+    pub symbols: Vec<Symbol>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub bytes: u64,
+    /// Entries from other brainv versions or damaged ones, which will never be used again
+    pub stale: usize,
+}
+
+pub struct CodeCache {
+    dir: PathBuf,
+}
+
+impl CodeCache {
+    /// A cache in the given directory, created when the first entry is stored
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// $BRAINV_CACHE_DIR, or brainv in the user's cache directory
+    pub fn default_dir() -> Option<PathBuf> {
+        let var = |name| std::env::var_os(name).filter(|value| !value.is_empty()).map(PathBuf::from);
+        if let Some(dir) = var("BRAINV_CACHE_DIR") {
+            return Some(dir);
+        }
+        let base = if cfg!(windows) {
+            var("LOCALAPPDATA")
+        } else {
+            var("XDG_CACHE_HOME").or_else(|| var("HOME").map(|home| home.join(".cache")))
+        };
+        base.map(|base| base.join("brainv"))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The code stored for the key, deleting the entry if it turns out to be invalid
+    pub fn load(&self, key: &CacheKey) -> Option<CachedCode> {
+        let path = self.dir.join(key.file_name());
+        let bytes = fs::read(&path).ok()?;
+        match parse_entry(&bytes) {
+            Some(entry) if entry.text == key.text && entry.program == key.program => Some(entry.code),
+            // Damaged, or another key or program with the same hash
+            _ => {
+                let _ = fs::remove_file(&path);
+                None
+            }
+        }
+    }
+
+    pub fn store(&self, key: &CacheKey, code: &[u8], symbols: &[Symbol]) -> io::Result<()> {
+        let mut bytes = vec![];
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.extend_from_slice(&0u16.to_le_bytes());
+        bytes.extend_from_slice(&(key.text.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(key.text.as_bytes());
+        bytes.extend_from_slice(&(key.program.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&key.program);
+        bytes.extend_from_slice(&(code.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(code);
+        bytes.extend_from_slice(&(symbols.len() as u32).to_le_bytes());
+        for symbol in symbols {
+            bytes.extend_from_slice(&(symbol.name.len() as u16).to_le_bytes());
+            bytes.extend_from_slice(symbol.name.as_bytes());
+            bytes.extend_from_slice(&(symbol.offset as u32).to_le_bytes());
+            bytes.extend_from_slice(&(symbol.len as u32).to_le_bytes());
+        }
+        let checksum = crc32(&bytes);
+        bytes.extend_from_slice(&checksum.to_le_bytes());
+
+        fs::create_dir_all(&self.dir)?;
+        // Write under a name of our own and rename, so a concurrent run never reads half an entry
+        let path = self.dir.join(key.file_name());
+        let temp = path.with_extension(format!("{EXTENSION}.{}", std::process::id()));
+        fs::write(&temp, bytes)?;
+        fs::rename(&temp, &path).inspect_err(|_| {
+            let _ = fs::remove_file(&temp);
+        })
+    }
+
+    /// Delete every entry, returning how many there were
+    pub fn clear(&self) -> io::Result<usize> {
+        let mut removed = 0;
+        for path in self.entries()? {
+            fs::remove_file(path)?;
+            removed += 1;
+        }
+        Ok(removed)
+    }
+
+    pub fn stats(&self) -> io::Result<CacheStats> {
+        let mut stats = CacheStats::default();
+        for path in self.entries()? {
+            let bytes = fs::read(&path)?;
+            stats.entries += 1;
+            stats.bytes += bytes.len() as u64;
+            if !parse_entry(&bytes).is_some_and(|entry| CacheKey::is_current(&entry.text)) {
+                stats.stale += 1;
+            }
+        }
+        Ok(stats)
+    }
+
+    fn entries(&self) -> io::Result<Vec<PathBuf>> {
+        let dir = match fs::read_dir(&self.dir) {
+            Ok(dir) => dir,
+            // Nothing was stored yet
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err),
+        };
+        let mut paths = vec![];
+        for entry in dir {
+            let path = entry?.path();
+            if path.extension().is_some_and(|extension| extension == EXTENSION) {
+                paths.push(path);
+            }
+        }
+        Ok(paths)
+    }
+}
+
+/// An entry as it is stored
+struct Entry {
+    text: String,
+    program: Vec<u8>,
+    code: CachedCode,
+}
+
+/// None if the entry is damaged or of another format version
+fn parse_entry(bytes: &[u8]) -> Option<Entry> {
+    let (content, checksum) = bytes.split_at_checked(bytes.len().checked_sub(4)?)?;
+    if crc32(content) != u32::from_le_bytes(checksum.try_into().ok()?) {
+        return None;
+    }
+
+    let mut reader = Reader { bytes: content };
+    if reader.take(4)? != MAGIC || reader.u16()? != VERSION {
+        return None;
+    }
+    reader.u16()?;
+    let len = reader.u32()? as usize;
+    let text = String::from_utf8(reader.take(len)?.to_vec()).ok()?;
+    let len = reader.u32()? as usize;
+    let program = reader.take(len)?.to_vec();
+    let len = reader.u32()? as usize;
+    let code = reader.take(len)?.to_vec();
+    let count = reader.u32()?;
+    let mut symbols = vec![];
+    for _ in 0..count {
+        let len = reader.u16()? as usize;
+        let name = String::from_utf8(reader.take(len)?.to_vec()).ok()?;
+        let offset = reader.u32()? as usize;
+        let len = reader.u32()? as usize;
+        symbols.push(Symbol { name, offset, len });
+    }
+    if !reader.bytes.is_empty() {
+        return None;
+    }
+    Some(Entry { text, program, code: CachedCode { code, symbols } })
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let (taken, rest) = self.bytes.split_at_checked(len)?;
+        self.bytes = rest;
+        Some(taken)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.take(2)?.try_into().ok()?))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+}
+
+/// 64-bit FNV-1a, stable across Rust versions unlike the std hashers
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for &byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
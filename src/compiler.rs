@@ -12,9 +12,16 @@ impl<'a> Compiler<'a> {
     }
 
     pub fn compile(&self) -> Vec<Op> {
+        self.compile_with_offsets().0
+    }
+
+    /// Like compile, but also returns the offset in the source each op starts at
+    pub fn compile_with_offsets(&self) -> (Vec<Op>, Vec<usize>) {
         let mut code = vec![];
+        let mut offsets = vec![];
 
         let mut last_instruction = Op::Nop;
+        let mut last_offset = 0;
 
         let mut left_bracket_stack = vec![];
 
@@ -26,6 +33,8 @@ impl<'a> Compiler<'a> {
                         last_instruction = Op::Inc(num + 1);
                     } else {
                         code.push(last_instruction);
+                        offsets.push(last_offset);
+                        last_offset = i;
 
                         last_instruction = Op::Inc(1);
                     }
@@ -35,6 +44,8 @@ impl<'a> Compiler<'a> {
                         last_instruction = Op::Dec(num + 1);
                     } else {
                         code.push(last_instruction);
+                        offsets.push(last_offset);
+                        last_offset = i;
 
                         last_instruction = Op::Dec(1);
                     }
@@ -44,6 +55,8 @@ impl<'a> Compiler<'a> {
                         last_instruction = Op::MovR(num + 1);
                     } else {
                         code.push(last_instruction);
+                        offsets.push(last_offset);
+                        last_offset = i;
 
                         last_instruction = Op::MovR(1);
                     }
@@ -53,17 +66,41 @@ impl<'a> Compiler<'a> {
                         last_instruction = Op::MovL(num + 1);
                     } else {
                         code.push(last_instruction);
+                        offsets.push(last_offset);
+                        last_offset = i;
 
                         last_instruction = Op::MovL(1);
                     }
                 },
                 '[' => {
                     code.push(last_instruction);
+                    offsets.push(last_offset);
+                    last_offset = i;
                     left_bracket_stack.push(code.len());
                     last_instruction = Op::JmpIfZ(0);
                 },
                 ']' => {
+                    let left_bracket_index = *left_bracket_stack.last().expect("Unmatched ']'");
+                    // A loop of a single move is a scan for a zero cell, the JmpIfZ is dropped
+                    if left_bracket_index + 1 == code.len() {
+                        if let Op::MovR(num) = last_instruction {
+                            left_bracket_stack.pop();
+                            code.pop();
+                            last_offset = offsets.pop().unwrap();
+                            last_instruction = Op::ScanR(num);
+                            continue;
+                        }
+                        if let Op::MovL(num) = last_instruction {
+                            left_bracket_stack.pop();
+                            code.pop();
+                            last_offset = offsets.pop().unwrap();
+                            last_instruction = Op::ScanL(num);
+                            continue;
+                        }
+                    }
                     code.push(last_instruction);
+                    offsets.push(last_offset);
+                    last_offset = i;
                     // backpatch the left bracket
                     let left_bracket_index = left_bracket_stack.pop().expect("Unmatched ']'");
                     last_instruction = Op::JmpIfNZ(left_bracket_index as u16);
@@ -71,10 +108,14 @@ impl<'a> Compiler<'a> {
                 },
                 '.' => {
                     code.push(last_instruction);
+                    offsets.push(last_offset);
+                    last_offset = i;
                     last_instruction = Op::Print;
                 },
                 ',' => {
                     code.push(last_instruction);
+                    offsets.push(last_offset);
+                    last_offset = i;
                     last_instruction = Op::Read;
                 },
                 _ => {}
@@ -83,7 +124,8 @@ impl<'a> Compiler<'a> {
             
         }
         code.push(last_instruction);
+        offsets.push(last_offset);
 
-        code
+        (code, offsets)
     }
 }
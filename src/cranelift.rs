@@ -0,0 +1,358 @@
+// Cranelift backend
+//
+// Lowers the same Ops as the JIT to Cranelift IR and lets Cranelift generate
+// code for whatever host it runs on. The function follows the runtime ABI of
+// JIT::compile, so it shares the header, output buffer and IO trampolines with
+// the hand-written backend and can be measured against it. It is never metered.
+
+use crate::io::IO;
+use crate::jit::{header, status};
+use crate::runtime::{self, RunError, RunSummary};
+use crate::vm::Op;
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::ir::{AbiParam, Block, InstBuilder, MemFlags, SigRef, Signature, Value, types};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{Linkage, Module, default_libcall_names};
+
+/// Programs with more IO Ops than this get the single pass register allocator.
+/// The default one generates much better code, but every IO Op is a call that
+/// splits the live ranges around it, which makes it take quadratic time.
+const BACKTRACKING_IO_LIMIT: usize = 1000;
+
+pub struct CraneliftJIT {
+    code: Vec<Op>,
+}
+
+impl CraneliftJIT {
+    pub fn new(code: Vec<Op>) -> Self {
+        Self { code }
+    }
+
+    /// Generate code for the host
+    pub fn compile(&self) -> Result<CraneliftCode, String> {
+        let mut flags = settings::builder();
+        flags.set("opt_level", "speed").map_err(|err| err.to_string())?;
+        // The verifier takes quadratic time on large programs, which are all one function
+        flags.set("enable_verifier", "false").map_err(|err| err.to_string())?;
+        let io_ops = self.code.iter().filter(|op| matches!(op, Op::Print | Op::Read)).count();
+        if io_ops > BACKTRACKING_IO_LIMIT {
+            flags.set("regalloc_algorithm", "single_pass").map_err(|err| err.to_string())?;
+        }
+        let isa = cranelift_native::builder()?
+            .finish(settings::Flags::new(flags))
+            .map_err(|err| err.to_string())?;
+        let mut module = JITModule::new(JITBuilder::with_isa(isa, default_libcall_names()));
+
+        let ptr = module.target_config().pointer_type();
+        let mut ctx = module.make_context();
+        // fn(tape_ptr, rt_ptr, flush_output, read_char) -> status
+        for _ in 0..4 {
+            ctx.func.signature.params.push(AbiParam::new(ptr));
+        }
+        ctx.func.signature.returns.push(AbiParam::new(types::I64));
+        let id = module
+            .declare_function("bf_program", Linkage::Export, &ctx.func.signature)
+            .map_err(|err| err.to_string())?;
+
+        let mut builder_ctx = FunctionBuilderContext::new();
+        let builder = FunctionBuilder::new(&mut ctx.func, &mut builder_ctx);
+        let call_conv = module.isa().default_call_conv();
+        Lowering::new(builder, ptr, call_conv).lower(&self.code)?;
+
+        module.define_function(id, &mut ctx).map_err(|err| format!("{err:?}"))?;
+        module.finalize_definitions().map_err(|err| err.to_string())?;
+        module.clear_context(&mut ctx);
+        let entry = module.get_finalized_function(id);
+        Ok(CraneliftCode { module: Some(module), entry })
+    }
+}
+
+/// Code generated by Cranelift, freed when dropped
+pub struct CraneliftCode {
+    module: Option<JITModule>,
+    entry: *const u8,
+}
+
+impl CraneliftCode {
+    /// Run the code with the tape pointer at the cell tp, like Executable::call
+    pub fn call<I: IO>(&self, io: &mut I, tape: &mut [u8], tp: usize) -> Result<RunSummary, RunError> {
+        unsafe { runtime::call_code(self.entry, io, tape, tp, u64::MAX, None) }
+    }
+}
+
+impl Drop for CraneliftCode {
+    fn drop(&mut self) {
+        if let Some(module) = self.module.take() {
+            unsafe { module.free_memory() };
+        }
+    }
+}
+
+/// Builds the function one Op at a time
+struct Lowering<'a> {
+    b: FunctionBuilder<'a>,
+    /// Tape pointer and output cursor
+    tape: Variable,
+    out: Variable,
+    /// The current cell, only written to the tape before the pointer moves or
+    /// the function returns
+    cell: Variable,
+    rt: Value,
+    flush_fn: Value,
+    read_fn: Value,
+    flush_sig: SigRef,
+    read_sig: SigRef,
+    out_end: Value,
+    tape_start: Value,
+    tape_end: Value,
+}
+
+impl<'a> Lowering<'a> {
+    fn new(mut b: FunctionBuilder<'a>, ptr: types::Type, call_conv: cranelift_codegen::isa::CallConv) -> Self {
+        let entry = b.create_block();
+        b.append_block_params_for_function_params(entry);
+        b.switch_to_block(entry);
+        b.seal_block(entry);
+        let params = b.block_params(entry).to_vec();
+
+        // flush_output(rt_ptr, cursor) -> cursor and read_char(rt_ptr) -> u8
+        let mut flush_sig = Signature::new(call_conv);
+        flush_sig.params.extend([AbiParam::new(ptr), AbiParam::new(ptr)]);
+        flush_sig.returns.push(AbiParam::new(ptr));
+        let mut read_sig = Signature::new(call_conv);
+        read_sig.params.push(AbiParam::new(ptr));
+        read_sig.returns.push(AbiParam::new(types::I8));
+        let flush_sig = b.import_signature(flush_sig);
+        let read_sig = b.import_signature(read_sig);
+
+        let rt = params[1];
+        let flags = MemFlags::trusted();
+        let out_start = b.ins().load(ptr, flags, rt, header::OUT_START as i32);
+        let out_end = b.ins().load(ptr, flags, rt, header::OUT_END as i32);
+        let tape_start = b.ins().load(ptr, flags, rt, header::TAPE_START as i32);
+        let tape_end = b.ins().load(ptr, flags, rt, header::TAPE_END as i32);
+
+        let tape = Variable::from_u32(0);
+        let out = Variable::from_u32(1);
+        let cell = Variable::from_u32(2);
+        b.declare_var(tape, ptr);
+        b.declare_var(out, ptr);
+        b.declare_var(cell, types::I8);
+        b.def_var(tape, params[0]);
+        b.def_var(out, out_start);
+        let value = b.ins().load(types::I8, flags, params[0], 0);
+        b.def_var(cell, value);
+
+        Self {
+            b,
+            tape,
+            out,
+            cell,
+            rt,
+            flush_fn: params[2],
+            read_fn: params[3],
+            flush_sig,
+            read_sig,
+            out_end,
+            tape_start,
+            tape_end,
+        }
+    }
+
+    fn lower(mut self, code: &[Op]) -> Result<(), String> {
+        // Open loops as (body, after the loop)
+        let mut loops: Vec<(Block, Block)> = vec![];
+        for op in code {
+            match *op {
+                Op::Nop => {}
+                Op::Inc(n) => {
+                    let cell = self.load_cell();
+                    let cell = self.b.ins().iadd_imm(cell, n as i64);
+                    self.store_cell(cell);
+                }
+                Op::Dec(n) => {
+                    let cell = self.load_cell();
+                    let cell = self.b.ins().iadd_imm(cell, -(n as i64));
+                    self.store_cell(cell);
+                }
+                Op::MovR(n) => self.move_tape(n as i64),
+                Op::MovL(n) => self.move_tape(-(n as i64)),
+                Op::ScanR(n) => self.scan(n as i64),
+                Op::ScanL(n) => self.scan(-(n as i64)),
+                Op::Print => {
+                    let out = self.b.use_var(self.out);
+                    let full = self.b.ins().icmp(IntCC::UnsignedGreaterThanOrEqual, out, self.out_end);
+                    let flush = self.b.create_block();
+                    let store = self.b.create_block();
+                    self.b.ins().brif(full, flush, &[], store, &[]);
+                    self.b.seal_block(flush);
+                    self.b.switch_to_block(flush);
+                    self.flush_output();
+                    self.b.ins().jump(store, &[]);
+                    self.b.seal_block(store);
+                    self.b.switch_to_block(store);
+
+                    let cell = self.load_cell();
+                    let out = self.b.use_var(self.out);
+                    self.b.ins().store(MemFlags::trusted(), cell, out, 0);
+                    let out = self.b.ins().iadd_imm(out, 1);
+                    self.b.def_var(self.out, out);
+                }
+                Op::Read => {
+                    // Output has to be visible before the program waits for input
+                    self.flush_output();
+                    let call = self.b.ins().call_indirect(self.read_sig, self.read_fn, &[self.rt]);
+                    let byte = self.b.inst_results(call)[0];
+                    // A failed read leaves the cell as it was
+                    self.exit_on_io_error();
+                    self.store_cell(byte);
+                }
+                Op::JmpIfZ(_) => {
+                    let body = self.b.create_block();
+                    let after = self.b.create_block();
+                    let cell = self.load_cell();
+                    self.b.ins().brif(cell, body, &[], after, &[]);
+                    // The body is also entered from the back-edge, sealed once that exists
+                    self.b.switch_to_block(body);
+                    loops.push((body, after));
+                }
+                Op::JmpIfNZ(_) => {
+                    let (body, after) = loops.pop().ok_or("Unmatched ']'")?;
+                    let cell = self.load_cell();
+                    self.b.ins().brif(cell, body, &[], after, &[]);
+                    self.b.seal_block(body);
+                    self.b.seal_block(after);
+                    self.b.switch_to_block(after);
+                }
+            }
+        }
+        if !loops.is_empty() {
+            return Err("Unmatched '['".to_string());
+        }
+
+        // The status is still OK unless the final flush failed
+        self.flush_output();
+        self.sync_cell();
+        self.leave();
+
+        self.b.finalize();
+        Ok(())
+    }
+
+    fn load_cell(&mut self) -> Value {
+        self.b.use_var(self.cell)
+    }
+
+    fn store_cell(&mut self, cell: Value) {
+        self.b.def_var(self.cell, cell);
+    }
+
+    /// Write the current cell to the tape
+    fn sync_cell(&mut self) {
+        let cell = self.b.use_var(self.cell);
+        let tape = self.b.use_var(self.tape);
+        self.b.ins().store(MemFlags::trusted(), cell, tape, 0);
+    }
+
+    /// Read the current cell from the tape after the pointer moved
+    fn reload_cell(&mut self) {
+        let tape = self.b.use_var(self.tape);
+        let cell = self.b.ins().load(types::I8, MemFlags::trusted(), tape, 0);
+        self.b.def_var(self.cell, cell);
+    }
+
+    fn move_tape(&mut self, offset: i64) {
+        self.sync_cell();
+        let tape = self.b.use_var(self.tape);
+        let tape = self.b.ins().iadd_imm(tape, offset);
+        self.b.def_var(self.tape, tape);
+        self.check_tape(tape);
+        self.reload_cell();
+    }
+
+    /// Leave with a tape fault unless the tape pointer is on the tape
+    fn check_tape(&mut self, tape: Value) {
+        let below = self.b.ins().icmp(IntCC::UnsignedLessThan, tape, self.tape_start);
+        let above = self.b.ins().icmp(IntCC::UnsignedGreaterThanOrEqual, tape, self.tape_end);
+        let outside = self.b.ins().bor(below, above);
+        self.leave_if(outside, Some(status::TAPE_FAULT));
+    }
+
+    /// Move the tape pointer by the stride until it points at a zero cell
+    fn scan(&mut self, stride: i64) {
+        self.sync_cell();
+        let top = self.b.create_block();
+        let step = self.b.create_block();
+        let done = self.b.create_block();
+        self.b.ins().jump(top, &[]);
+        self.b.switch_to_block(top);
+        let tape = self.b.use_var(self.tape);
+        self.check_tape(tape);
+        let cell = self.b.ins().load(types::I8, MemFlags::trusted(), tape, 0);
+        self.b.ins().brif(cell, step, &[], done, &[]);
+        self.b.seal_block(step);
+        self.b.switch_to_block(step);
+        let tape = self.b.use_var(self.tape);
+        let tape = self.b.ins().iadd_imm(tape, stride);
+        self.b.def_var(self.tape, tape);
+        self.b.ins().jump(top, &[]);
+        self.b.seal_block(top);
+        self.b.seal_block(done);
+        self.b.switch_to_block(done);
+        self.reload_cell();
+    }
+
+    /// Hand the buffered output to the runtime and leave if that failed
+    fn flush_output(&mut self) {
+        let out = self.b.use_var(self.out);
+        let call = self.b.ins().call_indirect(self.flush_sig, self.flush_fn, &[self.rt, out]);
+        let out = self.b.inst_results(call)[0];
+        self.b.def_var(self.out, out);
+        self.exit_on_io_error();
+    }
+
+    /// Leave with the status set by an IO function if it failed
+    fn exit_on_io_error(&mut self) {
+        let status = self.b.ins().load(types::I64, MemFlags::trusted(), self.rt, header::STATUS as i32);
+        self.leave_if(status, None);
+    }
+
+    /// Leave if the condition holds, setting the status if one is given, and
+    /// carry on in a new block otherwise. A status is only given for tape faults,
+    /// where the pointer is off the tape and the cell was written back before the
+    /// move. Those still hand over the output written so far, anything else
+    /// writes back the cell first
+    fn leave_if(&mut self, condition: Value, status: Option<u64>) {
+        // Every exit returns on its own, a single shared exit block with thousands
+        // of predecessors makes Cranelift's compile time quadratic
+        let exit = self.b.create_block();
+        let ok = self.b.create_block();
+        self.b.set_cold_block(exit);
+        self.b.ins().brif(condition, exit, &[], ok, &[]);
+        self.b.seal_block(exit);
+        self.b.switch_to_block(exit);
+        if let Some(status) = status {
+            let status = self.b.ins().iconst(types::I64, status as i64);
+            self.b.ins().store(MemFlags::trusted(), status, self.rt, header::STATUS as i32);
+            // A failing flush replaces the status with its own
+            let out = self.b.use_var(self.out);
+            self.b.ins().call_indirect(self.flush_sig, self.flush_fn, &[self.rt, out]);
+        } else {
+            self.sync_cell();
+        }
+        self.leave();
+        self.b.seal_block(ok);
+        self.b.switch_to_block(ok);
+    }
+
+    /// Store the tape offset and return the status from the header
+    fn leave(&mut self) {
+        let tape = self.b.use_var(self.tape);
+        let offset = self.b.ins().isub(tape, self.tape_start);
+        self.b.ins().store(MemFlags::trusted(), offset, self.rt, header::TAPE_OFFSET as i32);
+        let status = self.b.ins().load(types::I64, MemFlags::trusted(), self.rt, header::STATUS as i32);
+        self.b.ins().return_(&[status]);
+    }
+}
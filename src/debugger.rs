@@ -0,0 +1,223 @@
+// Time-travel debugger on top of the Vm
+//
+// Every forward step records what it is about to overwrite in an undo log, so
+// execution can be rewound one instruction at a time. Input bytes consumed by
+// a rewound `,` are queued up again and output of a rewound `.` is not printed
+// twice, which keeps replaying forward deterministic.
+//
+// The log keeps the last DEFAULT_HISTORY_LIMIT steps unless told otherwise, so
+// long runs don't use up memory. Older steps can't be undone or searched.
+
+use std::collections::{BTreeSet, VecDeque};
+
+use crate::io::IO;
+use crate::vm::{Op, Vm};
+
+/// State changed by a single instruction, besides the program counter
+#[derive(Debug, Clone, Copy)]
+enum Change {
+    None,
+    /// A cell was overwritten by `+`/`-`
+    Cell { tp: usize, old: u8 },
+    /// The tape pointer was moved
+    Pointer { old: usize },
+    /// A byte was read into a cell
+    Input { tp: usize, old: u8, byte: u8 },
+    /// A byte was written to the output
+    Output,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct UndoEntry {
+    pc: usize,
+    change: Change,
+}
+
+/// Steps kept in the undo log by default, a step takes 32 bytes
+pub const DEFAULT_HISTORY_LIMIT: usize = 1_000_000;
+
+/// Where and when a cell was last written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CellWrite {
+    /// Number of the step that performed the write, starting at 0
+    pub step: usize,
+    /// Index of the instruction that performed the write
+    pub pc: usize,
+    /// Value of the cell before the write
+    pub old: u8,
+}
+
+/// Why `continue_forward`/`continue_reverse` stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    Breakpoint(usize),
+    /// The program finished (forward) or the start of the history was reached (reverse)
+    End,
+}
+
+pub struct Debugger<I: IO> {
+    vm: Vm<I>,
+    log: VecDeque<UndoEntry>,
+    history_limit: usize,
+    // Steps dropped from the front of the log, to number the ones still in it
+    forgotten: usize,
+    breakpoints: BTreeSet<usize>,
+    // Input bytes given back by reverse steps, the next `,` takes the last one
+    replay_input: Vec<u8>,
+    // Number of `.` that were rewound and must not be printed again
+    replay_output: usize,
+}
+
+impl<I: IO> Debugger<I> {
+    pub fn new(vm: Vm<I>) -> Self {
+        Self {
+            vm,
+            log: VecDeque::new(),
+            history_limit: DEFAULT_HISTORY_LIMIT,
+            forgotten: 0,
+            breakpoints: BTreeSet::new(),
+            replay_input: vec![],
+            replay_output: 0,
+        }
+    }
+
+    pub fn vm(&self) -> &Vm<I> {
+        &self.vm
+    }
+
+    /// Number of steps that can currently be undone
+    pub fn steps(&self) -> usize {
+        self.log.len()
+    }
+
+    /// Keep at most limit steps in the undo log, dropping the oldest ones
+    pub fn set_history_limit(&mut self, limit: usize) {
+        self.history_limit = limit;
+        self.trim_log();
+    }
+
+    fn record(&mut self, entry: UndoEntry) {
+        self.log.push_back(entry);
+        self.trim_log();
+    }
+
+    fn trim_log(&mut self) {
+        while self.log.len() > self.history_limit {
+            self.log.pop_front();
+            self.forgotten += 1;
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, pc: usize) {
+        self.breakpoints.insert(pc);
+    }
+
+    pub fn remove_breakpoint(&mut self, pc: usize) -> bool {
+        self.breakpoints.remove(&pc)
+    }
+
+    /// Execute one instruction, returns false if the program has already finished
+    pub fn step(&mut self) -> bool {
+        if self.vm.is_finished() {
+            return false;
+        }
+
+        let pc = self.vm.pc;
+        let tp = self.vm.tp;
+        let change = match self.vm.program[pc] {
+            Op::Inc(_) | Op::Dec(_) => Change::Cell { tp, old: self.vm.tape[tp] },
+            Op::MovR(_) | Op::MovL(_) | Op::ScanR(_) | Op::ScanL(_) => Change::Pointer { old: tp },
+            Op::Read => {
+                let old = self.vm.tape[tp];
+                let byte = match self.replay_input.pop() {
+                    Some(byte) => byte,
+                    None => self.vm.io.read_byte(),
+                };
+                self.vm.tape[tp] = byte;
+                self.vm.pc += 1;
+                self.record(UndoEntry { pc, change: Change::Input { tp, old, byte } });
+                return true;
+            }
+            Op::Print => {
+                if self.replay_output > 0 {
+                    self.replay_output -= 1;
+                    self.vm.pc += 1;
+                    self.record(UndoEntry { pc, change: Change::Output });
+                    return true;
+                }
+                Change::Output
+            }
+            Op::JmpIfZ(_) | Op::JmpIfNZ(_) | Op::Nop => Change::None,
+        };
+
+        self.vm.step();
+        self.record(UndoEntry { pc, change });
+        true
+    }
+
+    /// Undo the last executed instruction, returns false if there is nothing to undo
+    pub fn reverse_step(&mut self) -> bool {
+        let Some(entry) = self.log.pop_back() else {
+            return false;
+        };
+
+        match entry.change {
+            Change::None => {}
+            Change::Cell { tp, old } => self.vm.tape[tp] = old,
+            Change::Pointer { old } => self.vm.tp = old,
+            Change::Input { tp, old, byte } => {
+                self.vm.tape[tp] = old;
+                self.replay_input.push(byte);
+            }
+            // Output cannot be taken back, but it is not printed again when replaying
+            Change::Output => self.replay_output += 1,
+        }
+        self.vm.pc = entry.pc;
+        true
+    }
+
+    /// Run forward until a breakpoint is reached or the program finishes
+    pub fn continue_forward(&mut self) -> StopReason {
+        // Always make progress, even when sitting on a breakpoint
+        if !self.step() {
+            return StopReason::End;
+        }
+        loop {
+            if self.breakpoints.contains(&self.vm.pc) {
+                return StopReason::Breakpoint(self.vm.pc);
+            }
+            if !self.step() {
+                return StopReason::End;
+            }
+        }
+    }
+
+    /// Run backwards until a breakpoint is reached or the history is exhausted
+    pub fn continue_reverse(&mut self) -> StopReason {
+        if !self.reverse_step() {
+            return StopReason::End;
+        }
+        loop {
+            if self.breakpoints.contains(&self.vm.pc) {
+                return StopReason::Breakpoint(self.vm.pc);
+            }
+            if !self.reverse_step() {
+                return StopReason::End;
+            }
+        }
+    }
+
+    /// Find the most recent write to the given cell, if it is still in the history
+    pub fn last_write(&self, cell: usize) -> Option<CellWrite> {
+        self.log.iter().enumerate().rev().find_map(|(index, entry)| match entry.change {
+            Change::Cell { tp, old } | Change::Input { tp, old, .. } if tp == cell => {
+                Some(CellWrite { step: self.forgotten + index, pc: entry.pc, old })
+            }
+            _ => None,
+        })
+    }
+
+    pub fn into_vm(self) -> Vm<I> {
+        self.vm
+    }
+}
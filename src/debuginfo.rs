@@ -0,0 +1,252 @@
+// Making generated code visible to profilers and debuggers
+//
+// perf picks up symbols for JIT code from /tmp/perf-<pid>.map, a line per
+// symbol with its address and size in hex. gdb instead reads an in-memory
+// ELF file per piece of code from a linked list it finds through the
+// __jit_debug_descriptor symbol, and sets a breakpoint in
+// __jit_debug_register_code to hear about changes to the list.
+
+use crate::jit::Symbol;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::ptr;
+use std::sync::Mutex;
+
+/// Append the symbols of code at the given address to the perf map of this process
+pub fn write_perf_map(addr: usize, symbols: &[Symbol]) -> io::Result<()> {
+    let path = format!("/tmp/perf-{}.map", std::process::id());
+    let mut lines = String::new();
+    for symbol in symbols {
+        lines.push_str(&format!("{:x} {:x} {}\n", addr + symbol.offset, symbol.len, symbol.name));
+    }
+    // One write, so runs on other threads don't interleave their lines with ours
+    OpenOptions::new().create(true).append(true).open(path)?.write_all(lines.as_bytes())
+}
+
+pub const EM_X86_64: u16 = 62;
+pub const EM_AARCH64: u16 = 183;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_NOBITS: u32 = 8;
+const SHF_ALLOC: u64 = 0x2;
+const SHF_EXECINSTR: u64 = 0x4;
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_FUNC: u8 = 2;
+const TEXT_SECTION: u16 = 1;
+
+/// An ELF object describing len bytes of code already loaded at addr, with
+/// the symbols as functions in its .text. The section has no contents, the
+/// same way LuaJIT describes its code to gdb.
+pub fn symbol_file(machine: u16, addr: u64, len: u64, symbols: &[Symbol]) -> Vec<u8> {
+    relocatable(machine, Text::Loaded { addr, len }, &[], symbols)
+}
+
+/// What the .text of an object file holds
+pub(crate) enum Text<'a> {
+    /// Nothing, describes code loaded elsewhere
+    Loaded { addr: u64, len: u64 },
+    /// The code itself, to be linked
+    Code(&'a [u8]),
+}
+
+/// An ELF relocatable object with a single .text section and the symbols as
+/// functions in it, the local ones only visible to debuggers and profilers.
+/// Objects with code also get an empty .note.GNU-stack, so linking them
+/// doesn't make the stack executable.
+pub(crate) fn relocatable(machine: u16, text: Text, locals: &[Symbol], globals: &[Symbol]) -> Vec<u8> {
+    let mut strtab = vec![0];
+    let mut symtab = vec![0; 24];
+    let binding = locals.iter().map(|symbol| (symbol, STB_LOCAL)).chain(globals.iter().map(|symbol| (symbol, STB_GLOBAL)));
+    for (symbol, bind) in binding {
+        let name = strtab.len() as u32;
+        strtab.extend(symbol.name.as_bytes());
+        strtab.push(0);
+        symtab.extend(name.to_le_bytes());
+        symtab.push(bind << 4 | STT_FUNC);
+        symtab.push(0);
+        symtab.extend(TEXT_SECTION.to_le_bytes());
+        // Values are from the start of the section in a relocatable file
+        symtab.extend((symbol.offset as u64).to_le_bytes());
+        symtab.extend((symbol.len as u64).to_le_bytes());
+    }
+    let code: &[u8] = match text {
+        Text::Loaded { .. } => &[],
+        Text::Code(code) => code,
+    };
+    let mut shstrtab = vec![0];
+    let mut section_name = |name: &str| {
+        let offset = shstrtab.len() as u32;
+        shstrtab.extend(name.as_bytes());
+        shstrtab.push(0);
+        offset
+    };
+    let names = [
+        section_name(".text"),
+        section_name(".symtab"),
+        section_name(".strtab"),
+        section_name(".shstrtab"),
+        section_name(".note.GNU-stack"),
+    ];
+    let sections = match text {
+        Text::Loaded { .. } => 5u16,
+        Text::Code(_) => 6,
+    };
+
+    // Header, then the code, then the tables, then the section headers
+    let code_offset = 64;
+    let symtab_offset = (code_offset + code.len()).next_multiple_of(8);
+    let strtab_offset = symtab_offset + symtab.len();
+    let shstrtab_offset = strtab_offset + strtab.len();
+    let headers_offset = (shstrtab_offset + shstrtab.len()).next_multiple_of(8);
+
+    let mut elf = Vec::with_capacity(headers_offset + sections as usize * 64);
+    elf.extend(b"\x7fELF");
+    // 64-bit, little-endian, version 1, System V ABI
+    elf.extend([2, 1, 1, 0]);
+    elf.extend([0; 8]);
+    elf.extend(1u16.to_le_bytes()); // ET_REL
+    elf.extend(machine.to_le_bytes());
+    elf.extend(1u32.to_le_bytes()); // version
+    elf.extend(0u64.to_le_bytes()); // entry
+    elf.extend(0u64.to_le_bytes()); // program headers
+    elf.extend((headers_offset as u64).to_le_bytes());
+    elf.extend(0u32.to_le_bytes()); // flags
+    elf.extend(64u16.to_le_bytes()); // header size
+    elf.extend(0u16.to_le_bytes()); // program header size
+    elf.extend(0u16.to_le_bytes()); // program header count
+    elf.extend(64u16.to_le_bytes()); // section header size
+    elf.extend(sections.to_le_bytes()); // section header count
+    elf.extend(4u16.to_le_bytes()); // index of .shstrtab
+    elf.extend(code);
+    elf.resize(symtab_offset, 0);
+    elf.extend(&symtab);
+    elf.extend(&strtab);
+    elf.extend(&shstrtab);
+    elf.resize(headers_offset, 0);
+
+    let mut section = |name: u32, kind: u32, flags: u64, addr: u64, offset: usize, size: u64, link: u32, info: u32, align: u64, entsize: u64| {
+        elf.extend(name.to_le_bytes());
+        elf.extend(kind.to_le_bytes());
+        elf.extend(flags.to_le_bytes());
+        elf.extend(addr.to_le_bytes());
+        elf.extend((offset as u64).to_le_bytes());
+        elf.extend(size.to_le_bytes());
+        elf.extend(link.to_le_bytes());
+        elf.extend(info.to_le_bytes());
+        elf.extend(align.to_le_bytes());
+        elf.extend(entsize.to_le_bytes());
+    };
+    section(0, 0, 0, 0, 0, 0, 0, 0, 0, 0);
+    match text {
+        Text::Loaded { addr, len } => section(names[0], SHT_NOBITS, SHF_ALLOC | SHF_EXECINSTR, addr, 0, len, 0, 0, 4, 0),
+        Text::Code(code) => {
+            section(names[0], SHT_PROGBITS, SHF_ALLOC | SHF_EXECINSTR, 0, code_offset, code.len() as u64, 0, 0, 4, 0)
+        }
+    }
+    // Linked to .strtab, the info is the index of the first global symbol
+    let first_global = 1 + locals.len() as u32;
+    section(names[1], SHT_SYMTAB, 0, 0, symtab_offset, symtab.len() as u64, 3, first_global, 8, 24);
+    section(names[2], SHT_STRTAB, 0, 0, strtab_offset, strtab.len() as u64, 0, 0, 1, 0);
+    section(names[3], SHT_STRTAB, 0, 0, shstrtab_offset, shstrtab.len() as u64, 0, 0, 1, 0);
+    if let Text::Code(_) = text {
+        section(names[4], SHT_PROGBITS, 0, 0, headers_offset, 0, 0, 0, 1, 0);
+    }
+    elf
+}
+
+// The GDB JIT interface, see "JIT Compilation Interface" in the gdb manual
+
+#[repr(C)]
+struct JitCodeEntry {
+    next_entry: *mut JitCodeEntry,
+    prev_entry: *mut JitCodeEntry,
+    symfile_addr: *const u8,
+    symfile_size: u64,
+}
+
+#[repr(C)]
+pub struct JitDescriptor {
+    version: u32,
+    action_flag: u32,
+    relevant_entry: *mut JitCodeEntry,
+    first_entry: *mut JitCodeEntry,
+}
+
+const JIT_REGISTER_FN: u32 = 1;
+const JIT_UNREGISTER_FN: u32 = 2;
+
+#[unsafe(no_mangle)]
+pub static mut __jit_debug_descriptor: JitDescriptor = JitDescriptor {
+    version: 1,
+    action_flag: 0,
+    relevant_entry: ptr::null_mut(),
+    first_entry: ptr::null_mut(),
+};
+
+/// gdb puts a breakpoint here, so it must not be inlined or optimized away
+#[unsafe(no_mangle)]
+#[inline(never)]
+pub extern "C" fn __jit_debug_register_code() {
+    std::hint::black_box(());
+}
+
+/// The descriptor is shared by everything in the process that generates code
+static DESCRIPTOR_LOCK: Mutex<()> = Mutex::new(());
+
+/// Code registered with gdb, unregistered when dropped
+pub struct GdbRegistration {
+    entry: Box<JitCodeEntry>,
+    // Pointed to by the entry
+    _symfile: Vec<u8>,
+}
+
+impl GdbRegistration {
+    /// Tell gdb about the symbols of code loaded at addr
+    pub fn new(addr: usize, len: usize, symbols: &[Symbol]) -> Self {
+        let machine = if cfg!(target_arch = "x86_64") { EM_X86_64 } else { EM_AARCH64 };
+        let symfile = symbol_file(machine, addr as u64, len as u64, symbols);
+        let mut entry = Box::new(JitCodeEntry {
+            next_entry: ptr::null_mut(),
+            prev_entry: ptr::null_mut(),
+            symfile_addr: symfile.as_ptr(),
+            symfile_size: symfile.len() as u64,
+        });
+        let _lock = DESCRIPTOR_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        unsafe {
+            let descriptor = &raw mut __jit_debug_descriptor;
+            entry.next_entry = (*descriptor).first_entry;
+            if !entry.next_entry.is_null() {
+                (*entry.next_entry).prev_entry = &mut *entry;
+            }
+            (*descriptor).first_entry = &mut *entry;
+            (*descriptor).relevant_entry = &mut *entry;
+            (*descriptor).action_flag = JIT_REGISTER_FN;
+        }
+        __jit_debug_register_code();
+        Self { entry, _symfile: symfile }
+    }
+}
+
+impl Drop for GdbRegistration {
+    fn drop(&mut self) {
+        let _lock = DESCRIPTOR_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        let entry: *mut JitCodeEntry = &mut *self.entry;
+        unsafe {
+            let descriptor = &raw mut __jit_debug_descriptor;
+            if (*entry).prev_entry.is_null() {
+                (*descriptor).first_entry = (*entry).next_entry;
+            } else {
+                (*(*entry).prev_entry).next_entry = (*entry).next_entry;
+            }
+            if !(*entry).next_entry.is_null() {
+                (*(*entry).next_entry).prev_entry = (*entry).prev_entry;
+            }
+            (*descriptor).relevant_entry = entry;
+            (*descriptor).action_flag = JIT_UNREGISTER_FN;
+        }
+        __jit_debug_register_code();
+    }
+}
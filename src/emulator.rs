@@ -0,0 +1,625 @@
+// AArch64 emulator for the code generated by the JIT
+//
+// Runs the output of JIT::compile on any host, for exactly the instructions
+// the JIT emits. The IO functions are fake addresses that call straight into
+// an IO, and the output buffer is kept small so the flush path runs often.
+// To catch code that only works by accident, caller-saved registers are
+// clobbered on every call, the upper bits of a returned byte are garbage and
+// callee-saved registers are checked on return.
+
+use crate::io::IO;
+use crate::jit::{self, JIT, header, status};
+use crate::runtime::{self, RunError, RunSummary};
+use crate::tiered::LoopRunner;
+
+const CODE_BASE: u64 = 0x1000_0000;
+const TAPE_BASE: u64 = 0x2000_0000;
+const STACK_BASE: u64 = 0x3000_0000;
+const STACK_SIZE: usize = 64 * 1024;
+const RT_ADDR: u64 = 0x4000_0000;
+const HEADER_SIZE: usize = 80;
+const OUTPUT_BASE: u64 = RT_ADDR + HEADER_SIZE as u64;
+const OUTPUT_SIZE: usize = 64;
+const WRITE_FN: u64 = 0x5000_0000;
+const READ_FN: u64 = 0x5000_0010;
+const RETURN_ADDR: u64 = 0x5000_0020;
+const COMPILE_FN: u64 = 0x5000_0030;
+
+const TAPE_SIZE: usize = 30000;
+const POISON: u64 = 0xDEAD_BEEF_DEAD_BEEF;
+
+/// Register 31 means sp in address and add/sub immediate operands, zr elsewhere
+#[derive(Clone, Copy)]
+enum Reg31 {
+    Sp,
+    Zr,
+}
+
+pub struct Emulator<I: IO> {
+    code: Vec<u8>,
+    tape: Vec<u8>,
+    stack: Vec<u8>,
+    /// The header generated code accesses through the runtime pointer, followed by the output buffer
+    rt: Vec<u8>,
+    error: Option<String>,
+    regs: [u64; 31],
+    /// NEON registers, byte 0 of the vector is the low byte
+    vregs: [u128; 32],
+    sp: u64,
+    /// NZCV condition flags
+    flags: u8,
+    pc: u64,
+    steps: u64,
+    /// Cell the tape pointer starts at
+    start: usize,
+    io: I,
+    /// Compiles the loops of lazy code, which are appended to the code
+    jit: Option<JIT>,
+    loops: usize,
+}
+
+impl<I: IO> Emulator<I> {
+    pub fn new(io: I, code: Vec<u8>) -> Self {
+        Self::with_tape(io, code, vec![0; TAPE_SIZE], 0)
+    }
+
+    /// Run on the given tape with the tape pointer starting at the cell tp
+    pub fn with_tape(io: I, code: Vec<u8>, tape: Vec<u8>, tp: usize) -> Self {
+        assert!(tp < tape.len(), "Tape pointer {tp} is outside the tape");
+        let tape_end = TAPE_BASE + tape.len() as u64;
+        let mut emulator = Self {
+            code,
+            tape,
+            stack: vec![0; STACK_SIZE],
+            rt: vec![0; HEADER_SIZE + OUTPUT_SIZE],
+            error: None,
+            regs: [0; 31],
+            vregs: [0; 32],
+            sp: STACK_BASE + STACK_SIZE as u64,
+            flags: 0,
+            pc: CODE_BASE,
+            steps: 0,
+            start: tp,
+            io,
+            jit: None,
+            loops: 0,
+        };
+        emulator.set_header(header::OUT_START, OUTPUT_BASE);
+        emulator.set_header(header::OUT_END, OUTPUT_BASE + OUTPUT_SIZE as u64);
+        emulator.set_header(header::TAPE_START, TAPE_BASE);
+        emulator.set_header(header::TAPE_END, tape_end);
+        emulator.set_header(header::FUEL, i64::MAX as u64);
+        emulator.set_header(header::STATUS, status::OK);
+        emulator.set_header(header::STEPS, runtime::NOT_METERED);
+        emulator.set_header(header::COMPILE_LOOP, COMPILE_FN);
+        emulator.set_header(header::FAULT_OP, runtime::NO_FAULT_OP);
+        emulator
+    }
+
+    /// Run code from the JIT with loops compiled on first entry, like Runtime::lazy
+    pub fn lazy(io: I, jit: JIT) -> Result<Self, String> {
+        let mut emulator = Self::new(io, jit.compile_lazy()?);
+        emulator.jit = Some(jit);
+        Ok(emulator)
+    }
+
+    /// Limit the number of steps code from JIT::metered may run, like Runtime::set_fuel
+    pub fn set_fuel(&mut self, fuel: u64) {
+        self.set_header(header::FUEL, fuel.min(i64::MAX as u64));
+    }
+
+    /// What the generated code reported, once run has returned
+    pub fn result(&self) -> Result<RunSummary, RunError> {
+        runtime::run_result(
+            self.regs[0],
+            self.get_header(header::TAPE_OFFSET) as i64,
+            self.get_header(header::STEPS),
+            self.get_header(header::FAULT_OP),
+            self.error.clone(),
+        )
+    }
+
+    fn get_header(&self, offset: u32) -> u64 {
+        let offset = offset as usize;
+        u64::from_le_bytes(self.rt[offset..offset + 8].try_into().unwrap())
+    }
+
+    fn set_header(&mut self, offset: u32, value: u64) {
+        let offset = offset as usize;
+        self.rt[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// Call the generated function the same way Runtime does. An error means the
+    /// generated code did something it shouldn't, see result for how the program ended.
+    pub fn run(&mut self) -> Result<(), String> {
+        // Give every register a recognizable value so callee-saved ones can be checked
+        for (i, reg) in self.regs.iter_mut().enumerate() {
+            *reg = 0x5EED_0000_0000_0000 | i as u64;
+        }
+        self.regs[0] = TAPE_BASE + self.start as u64;
+        self.regs[1] = RT_ADDR;
+        self.regs[2] = WRITE_FN;
+        self.regs[3] = READ_FN;
+        self.regs[30] = RETURN_ADDR;
+        let saved: Vec<u64> = self.regs[19..=29].to_vec();
+        let saved_sp = self.sp;
+
+        while self.pc != RETURN_ADDR {
+            self.step()?;
+        }
+
+        for (i, &value) in saved.iter().enumerate() {
+            if self.regs[19 + i] != value {
+                return Err(format!("callee-saved register x{} was not restored", 19 + i));
+            }
+        }
+        if self.sp != saved_sp {
+            return Err("sp was not restored".to_string());
+        }
+        Ok(())
+    }
+
+    /// Number of instructions executed so far
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Number of loops lazy code has compiled so far
+    pub fn compiled_loops(&self) -> usize {
+        self.loops
+    }
+
+    pub fn tape(&self) -> &[u8] {
+        &self.tape
+    }
+
+    pub fn into_io(self) -> I {
+        self.io
+    }
+
+    pub fn into_tape(self) -> Vec<u8> {
+        self.tape
+    }
+
+    fn reg(&self, n: u32, r31: Reg31) -> u64 {
+        match (n, r31) {
+            (31, Reg31::Sp) => self.sp,
+            (31, Reg31::Zr) => 0,
+            _ => self.regs[n as usize],
+        }
+    }
+
+    fn set_reg(&mut self, n: u32, value: u64, r31: Reg31) {
+        match (n, r31) {
+            (31, Reg31::Sp) => self.sp = value,
+            (31, Reg31::Zr) => {}
+            _ => self.regs[n as usize] = value,
+        }
+    }
+
+    fn memory(&mut self, addr: u64, len: usize) -> Result<&mut [u8], String> {
+        let (base, region) = if addr >= STACK_BASE && addr < STACK_BASE + STACK_SIZE as u64 {
+            (STACK_BASE, &mut self.stack)
+        } else if addr >= TAPE_BASE && addr < TAPE_BASE + self.tape.len() as u64 {
+            (TAPE_BASE, &mut self.tape)
+        } else if addr >= RT_ADDR && addr < OUTPUT_BASE + OUTPUT_SIZE as u64 {
+            (RT_ADDR, &mut self.rt)
+        } else {
+            return Err(format!("access to unmapped address {addr:#x} at pc {:#x}", self.pc - CODE_BASE));
+        };
+        let start = (addr - base) as usize;
+        region
+            .get_mut(start..start + len)
+            .ok_or_else(|| format!("access past the end of a region at {addr:#x}"))
+    }
+
+    fn load_u64(&mut self, addr: u64) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.memory(addr, 8)?.try_into().unwrap()))
+    }
+
+    fn store_u64(&mut self, addr: u64, value: u64) -> Result<(), String> {
+        self.memory(addr, 8)?.copy_from_slice(&value.to_le_bytes());
+        Ok(())
+    }
+
+    /// Jump to a register, calling into the IO for the fake function addresses
+    fn call(&mut self, target: u64) -> Result<(), String> {
+        match target {
+            WRITE_FN => {
+                let cursor = self.regs[1];
+                if !(OUTPUT_BASE..=OUTPUT_BASE + OUTPUT_SIZE as u64).contains(&cursor) {
+                    return Err(format!("output cursor {cursor:#x} is outside the buffer"));
+                }
+                let start = HEADER_SIZE;
+                let bytes = &self.rt[start..start + (cursor - OUTPUT_BASE) as usize];
+                let io = &mut self.io;
+                let result = runtime::catch_io(|| {
+                    for &c in bytes {
+                        io.write_byte(c);
+                    }
+                });
+                self.io_failed(result);
+                self.clobber_caller_saved();
+                self.regs[0] = OUTPUT_BASE;
+            }
+            READ_FN => {
+                let result = runtime::read_input(&mut self.io);
+                let byte = self.io_failed(result).unwrap_or(0);
+                self.clobber_caller_saved();
+                // Only the low byte of a u8 return value is defined
+                self.regs[0] = (POISON & !0xFF) | byte as u64;
+            }
+            COMPILE_FN => {
+                let (start, stub) = (self.regs[1], self.regs[2]);
+                let result = match &self.jit {
+                    Some(jit) => jit.compile_loop(start as usize),
+                    None => return Err("call to the loop compiler without a program".to_string()),
+                };
+                let entry = match result {
+                    Ok(code) => self.load_loop(code, stub)?,
+                    Err(message) => {
+                        self.set_header(header::STATUS, status::COMPILE_ERROR);
+                        self.error = Some(message);
+                        0
+                    }
+                };
+                self.clobber_caller_saved();
+                self.regs[0] = entry;
+            }
+            _ => return Err(format!("call to unknown address {target:#x}")),
+        }
+        Ok(())
+    }
+
+    /// Append a compiled loop to the code and patch the stub at the given address to call it
+    fn load_loop(&mut self, code: Vec<u8>, stub: u64) -> Result<u64, String> {
+        if !self.in_code(stub) || !self.in_code(stub + jit::STUB_SIZE as u64 - 4) {
+            return Err(format!("loop stub {stub:#x} is outside the code"));
+        }
+        // Leave a gap, so loop code that runs off its end hits an invalid instruction
+        self.code.resize(self.code.len().next_multiple_of(0x1000) + 0x1000, 0);
+        let entry = CODE_BASE + self.code.len() as u64;
+        self.code.extend(code);
+        let at = (stub - CODE_BASE) as usize;
+        self.code[at..at + jit::STUB_SIZE].copy_from_slice(&jit::patched_stub(entry));
+        self.loops += 1;
+        Ok(entry)
+    }
+
+    fn in_code(&self, addr: u64) -> bool {
+        addr >= CODE_BASE && addr < CODE_BASE + self.code.len() as u64
+    }
+
+    /// Record a failed IO call the way the Runtime trampolines do
+    fn io_failed<T>(&mut self, result: Result<T, (u64, String)>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err((code, message)) => {
+                self.set_header(header::STATUS, code);
+                self.error = Some(message);
+                None
+            }
+        }
+    }
+
+    fn clobber_caller_saved(&mut self) {
+        for reg in &mut self.regs[0..=17] {
+            *reg = POISON;
+        }
+    }
+
+    fn step(&mut self) -> Result<(), String> {
+        let offset = (self.pc - CODE_BASE) as usize;
+        let Some(bytes) = self.code.get(offset..offset + 4) else {
+            return Err(format!("pc {:#x} is outside the code", offset));
+        };
+        let word = u32::from_le_bytes(bytes.try_into().unwrap());
+        self.steps += 1;
+
+        let rd = word & 0x1F;
+        let rn = (word >> 5) & 0x1F;
+        let sf = word >> 31 == 1;
+        let mut next = self.pc + 4;
+
+        if word & 0xFFFFFC00 == 0x4C407000 {
+            // ld1 {vT.16b}, [xN]
+            let addr = self.reg(rn, Reg31::Sp);
+            let bytes: [u8; 16] = self.memory(addr, 16)?.try_into().unwrap();
+            self.vregs[rd as usize] = u128::from_le_bytes(bytes);
+        } else if word & 0xFFFFFC00 == 0x4E209800 {
+            // cmeq vD.16b, vN.16b, #0
+            let bytes = self.vregs[rn as usize].to_le_bytes().map(|b| if b == 0 { 0xFF } else { 0 });
+            self.vregs[rd as usize] = u128::from_le_bytes(bytes);
+        } else if word & 0xFFE0FC00 == 0x4E201C00 {
+            // and vD.16b, vN.16b, vM.16b
+            let rm = (word >> 16) & 0x1F;
+            self.vregs[rd as usize] = self.vregs[rn as usize] & self.vregs[rm as usize];
+        } else if word & 0x9FF80C00 == 0x0F000400 {
+            // movi vD.<T>, #imm
+            let imm = expand_simd_imm(word)?;
+            let quad = word & 0x40000000 != 0;
+            self.vregs[rd as usize] = if quad { (imm as u128) << 64 | imm as u128 } else { imm as u128 };
+        } else if word & 0xFFFFFC00 == 0x0F0C8400 {
+            // shrn vD.8b, vN.8h, #4, the upper half of vD is cleared
+            let halves = self.vregs[rn as usize].to_le_bytes();
+            let mut narrowed = [0u8; 16];
+            for i in 0..8 {
+                narrowed[i] = (u16::from_le_bytes([halves[2 * i], halves[2 * i + 1]]) >> 4) as u8;
+            }
+            self.vregs[rd as usize] = u128::from_le_bytes(narrowed);
+        } else if word & 0xFFFFFC00 == 0x9E660000 {
+            // fmov xD, dN
+            self.set_reg(rd, self.vregs[rn as usize] as u64, Reg31::Zr);
+        } else if word & 0xFFFFFC00 == 0xDAC00000 {
+            // rbit xD, xN
+            self.set_reg(rd, self.reg(rn, Reg31::Zr).reverse_bits(), Reg31::Zr);
+        } else if word & 0xFFFFFC00 == 0xDAC01000 {
+            // clz xD, xN
+            self.set_reg(rd, self.reg(rn, Reg31::Zr).leading_zeros() as u64, Reg31::Zr);
+        } else if word & 0xFF800000 == 0x39000000 || word & 0xFFC00000 == 0x39400000 {
+            // ldrb/strb wT, [xN, #imm]
+            let imm = ((word >> 10) & 0xFFF) as u64;
+            let addr = self.reg(rn, Reg31::Sp).wrapping_add(imm);
+            if word & 0x00400000 != 0 {
+                let value = self.memory(addr, 1)?[0];
+                self.set_reg(rd, value as u64, Reg31::Zr);
+            } else {
+                let value = self.reg(rd, Reg31::Zr) as u8;
+                self.memory(addr, 1)?[0] = value;
+            }
+        } else if word & 0xFFE00C00 == 0x38000400 {
+            // strb wT, [xN], #imm
+            let addr = self.reg(rn, Reg31::Sp);
+            let value = self.reg(rd, Reg31::Zr) as u8;
+            self.memory(addr, 1)?[0] = value;
+            let imm = sign_extend(((word >> 12) & 0x1FF) as u64, 9);
+            self.set_reg(rn, addr.wrapping_add(imm), Reg31::Sp);
+        } else if word & 0xFFC00000 == 0xF9400000 {
+            // ldr xT, [xN, #imm]
+            let imm = (((word >> 10) & 0xFFF) as u64) << 3;
+            let addr = self.reg(rn, Reg31::Sp).wrapping_add(imm);
+            let value = self.load_u64(addr)?;
+            self.set_reg(rd, value, Reg31::Zr);
+        } else if word & 0xFFC00000 == 0xF9000000 {
+            // str xT, [xN, #imm]
+            let imm = (((word >> 10) & 0xFFF) as u64) << 3;
+            let addr = self.reg(rn, Reg31::Sp).wrapping_add(imm);
+            let value = self.reg(rd, Reg31::Zr);
+            self.store_u64(addr, value)?;
+        } else if word & 0x1F800000 == 0x11000000 {
+            // add/sub{s} xD|wD, xN|wN, #imm{, lsl #12}
+            let mut imm = ((word >> 10) & 0xFFF) as u64;
+            if word & 0x00400000 != 0 {
+                imm <<= 12;
+            }
+            let a = self.reg(rn, Reg31::Sp);
+            let is_sub = word & 0x40000000 != 0;
+            let value = if is_sub { a.wrapping_sub(imm) } else { a.wrapping_add(imm) };
+            if word & 0x20000000 != 0 {
+                // The flag setting forms write zr instead of sp
+                self.flags = add_sub_flags(a, imm, is_sub, sf);
+                self.set_reg(rd, truncate(value, sf), Reg31::Zr);
+            } else {
+                self.set_reg(rd, truncate(value, sf), Reg31::Sp);
+            }
+        } else if word & 0x1F200000 == 0x0B000000 {
+            // add/sub{s} xD|wD, xN|wN, xM|wM, lsl|lsr|asr #amount
+            let rm = (word >> 16) & 0x1F;
+            let amount = (word >> 10) & 0x3F;
+            let m = truncate(self.reg(rm, Reg31::Zr), sf);
+            let b = match (word >> 22) & 0x3 {
+                0b00 => m << amount,
+                0b01 => m >> amount,
+                0b10 if sf => ((m as i64) >> amount) as u64,
+                0b10 => ((m as i32) >> amount) as u32 as u64,
+                _ => return Err(format!("unsupported shift in {word:#010x}")),
+            };
+            let a = self.reg(rn, Reg31::Zr);
+            let is_sub = word & 0x40000000 != 0;
+            let value = if is_sub { a.wrapping_sub(b) } else { a.wrapping_add(b) };
+            if word & 0x20000000 != 0 {
+                self.flags = add_sub_flags(a, b, is_sub, sf);
+            }
+            self.set_reg(rd, truncate(value, sf), Reg31::Zr);
+        } else if word & 0x7F800000 == 0x12000000 {
+            // and xD|wD, xN|wN, #mask
+            let mask = decode_bit_mask(word, sf)?;
+            let value = self.reg(rn, Reg31::Zr) & mask;
+            self.set_reg(rd, truncate(value, sf), Reg31::Sp);
+        } else if word & 0x1F800000 == 0x12800000 {
+            // movz/movk xD|wD, #imm, lsl #(16 * hw)
+            let shift = ((word >> 21) & 0x3) * 16;
+            let imm = ((word >> 5) & 0xFFFF) as u64;
+            let value = match (word >> 29) & 0x3 {
+                0b10 => imm << shift,
+                0b11 => (self.reg(rd, Reg31::Zr) & !(0xFFFF << shift)) | (imm << shift),
+                _ => return Err(format!("unsupported move wide {word:#010x}")),
+            };
+            self.set_reg(rd, truncate(value, sf), Reg31::Zr);
+        } else if word & 0x7E000000 == 0x34000000 {
+            // cbz/cbnz xT|wT, <offset>
+            let value = truncate(self.reg(rd, Reg31::Zr), sf);
+            let is_nz = word & 0x01000000 != 0;
+            if (value != 0) == is_nz {
+                next = self.pc.wrapping_add(sign_extend(((word >> 5) & 0x7FFFF) as u64, 19) << 2);
+            }
+        } else if word & 0xFF000010 == 0x54000000 {
+            // b.cond <offset>
+            if self.condition_holds(word & 0xF) {
+                next = self.pc.wrapping_add(sign_extend(((word >> 5) & 0x7FFFF) as u64, 19) << 2);
+            }
+        } else if word & 0xFC000000 == 0x14000000 {
+            // b <offset>
+            next = self.pc.wrapping_add(sign_extend((word & 0x3FFFFFF) as u64, 26) << 2);
+        } else if word & 0xFC000000 == 0x94000000 {
+            // bl <offset>
+            self.regs[30] = next;
+            next = self.pc.wrapping_add(sign_extend((word & 0x3FFFFFF) as u64, 26) << 2);
+        } else if word & 0x9F000000 == 0x10000000 {
+            // adr xD, <offset>
+            let imm = (((word >> 5) & 0x7FFFF) << 2 | ((word >> 29) & 0x3)) as u64;
+            self.set_reg(rd, self.pc.wrapping_add(sign_extend(imm, 21)), Reg31::Zr);
+        } else if word & 0xFFFFFC1F == 0xD61F0000 {
+            // br xN
+            next = self.reg(rn, Reg31::Zr);
+        } else if word & 0xFFFFFC1F == 0xD63F0000 {
+            // blr xN
+            let target = self.reg(rn, Reg31::Zr);
+            self.regs[30] = next;
+            if self.in_code(target) {
+                next = target;
+            } else {
+                self.call(target)?;
+            }
+        } else if word & 0xFFFFFC1F == 0xD65F0000 {
+            // ret xN
+            next = self.reg(rn, Reg31::Zr);
+        } else if word & 0xFE400000 == 0xA8000000 || word & 0xFE400000 == 0xA8400000 {
+            // stp/ldp xA, xB, [sp|xN] with post-index, signed offset or pre-index
+            let rt2 = (word >> 10) & 0x1F;
+            let offset = sign_extend(((word >> 15) & 0x7F) as u64, 7) << 3;
+            let base = self.reg(rn, Reg31::Sp);
+            let (addr, writeback) = match (word >> 23) & 0x3 {
+                0b01 => (base, Some(base.wrapping_add(offset))),
+                0b10 => (base.wrapping_add(offset), None),
+                0b11 => (base.wrapping_add(offset), Some(base.wrapping_add(offset))),
+                _ => return Err(format!("unsupported load/store pair {word:#010x}")),
+            };
+            if word & 0x00400000 != 0 {
+                let a = self.load_u64(addr)?;
+                let b = self.load_u64(addr + 8)?;
+                self.set_reg(rd, a, Reg31::Zr);
+                self.set_reg(rt2, b, Reg31::Zr);
+            } else {
+                let a = self.reg(rd, Reg31::Zr);
+                let b = self.reg(rt2, Reg31::Zr);
+                self.store_u64(addr, a)?;
+                self.store_u64(addr + 8, b)?;
+            }
+            if let Some(base) = writeback {
+                self.set_reg(rn, base, Reg31::Sp);
+            }
+        } else {
+            return Err(format!("unsupported instruction {word:#010x} at {offset:#x}"));
+        }
+
+        self.pc = next;
+        Ok(())
+    }
+
+    /// ConditionHolds in the ARM ARM
+    fn condition_holds(&self, cond: u32) -> bool {
+        let [n, z, c, v] = [8, 4, 2, 1].map(|bit| self.flags & bit != 0);
+        let result = match cond >> 1 {
+            0b000 => z,
+            0b001 => c,
+            0b010 => n,
+            0b011 => v,
+            0b100 => c && !z,
+            0b101 => n == v,
+            0b110 => n == v && !z,
+            _ => true,
+        };
+        if cond & 1 == 1 && cond != 0xF { !result } else { result }
+    }
+}
+
+/// NZCV after an adds/subs of a and b
+fn add_sub_flags(a: u64, b: u64, is_sub: bool, sf: bool) -> u8 {
+    let bits = if sf { 64 } else { 32 };
+    let (a, b) = (truncate(a, sf), truncate(b, sf));
+    // A subtraction is an addition of the complement with a carry in
+    let (b, carry_in) = if is_sub { (truncate(!b, sf), 1) } else { (b, 0) };
+    let wide = a as u128 + b as u128 + carry_in;
+    let result = truncate(wide as u64, sf);
+    let sign = |x: u64| (x >> (bits - 1)) & 1 == 1;
+
+    let n = sign(result);
+    let z = result == 0;
+    let c = wide >> bits != 0;
+    let v = sign(a) == sign(b) && sign(result) != sign(a);
+    (n as u8) << 3 | (z as u8) << 2 | (c as u8) << 1 | v as u8
+}
+
+/// The 64-bit pattern of a movi, AdvSIMDExpandImm in the ARM ARM for the forms the JIT uses
+fn expand_simd_imm(word: u32) -> Result<u64, String> {
+    let op = (word >> 29) & 1;
+    let cmode = (word >> 12) & 0xF;
+    let imm8 = ((word >> 11) & 0xE0 | (word >> 5) & 0x1F) as u64;
+    let replicate = |value: u64, bits: u32| {
+        let mut result = 0;
+        let mut i = 0;
+        while i < 64 {
+            result |= value << i;
+            i += bits;
+        }
+        result
+    };
+    match (op, cmode) {
+        (0, 0b0000 | 0b0010 | 0b0100 | 0b0110) => Ok(replicate(imm8 << (8 * (cmode >> 1)), 32)),
+        (0, 0b1000 | 0b1010) => Ok(replicate(imm8 << (8 * ((cmode >> 1) & 1)), 16)),
+        (1, 0b1110) => Ok((0..8).filter(|bit| imm8 & (1 << bit) != 0).map(|bit| 0xFF << (8 * bit)).sum()),
+        _ => Err(format!("unsupported movi {word:#010x}")),
+    }
+}
+
+fn truncate(value: u64, sf: bool) -> u64 {
+    if sf { value } else { value & 0xFFFF_FFFF }
+}
+
+fn sign_extend(value: u64, bits: u32) -> u64 {
+    let shift = 64 - bits;
+    (((value << shift) as i64) >> shift) as u64
+}
+
+/// Decode the immediate of a logical instruction, DecodeBitMasks in the ARM ARM
+fn decode_bit_mask(word: u32, sf: bool) -> Result<u64, String> {
+    let n = (word >> 22) & 1;
+    let immr = (word >> 16) & 0x3F;
+    let imms = (word >> 10) & 0x3F;
+
+    let combined = (n << 6) | (!imms & 0x3F);
+    if combined == 0 || (!sf && n == 1) {
+        return Err(format!("invalid logical immediate in {word:#010x}"));
+    }
+    let len = 31 - combined.leading_zeros();
+    let size = 1u32 << len;
+    let levels = size - 1;
+    let s = imms & levels;
+    let r = immr & levels;
+    if s == levels {
+        return Err(format!("invalid logical immediate in {word:#010x}"));
+    }
+
+    let ones = if s + 1 == 64 { u64::MAX } else { (1u64 << (s + 1)) - 1 };
+    let element_mask = if size == 64 { u64::MAX } else { (1u64 << size) - 1 };
+    let element = if r == 0 { ones } else { ((ones >> r) | (ones << (size - r))) & element_mask };
+
+    let mut mask = 0u64;
+    let mut i = 0;
+    while i < 64 {
+        mask |= element << i;
+        i += size;
+    }
+    Ok(truncate(mask, sf))
+}
+
+/// Runs the hot loops of a Tiered engine in the emulator, so tiering can be
+/// tested on any host
+pub struct EmulatedLoops;
+
+impl<I: IO> LoopRunner<I> for EmulatedLoops {
+    type Loaded = Vec<u8>;
+
+    fn load(&mut self, code: Vec<u8>) -> Vec<u8> {
+        code
+    }
+
+    fn run(&mut self, code: &Vec<u8>, io: &mut I, tape: &mut Vec<u8>, tp: usize) -> Result<RunSummary, RunError> {
+        let mut emulator = Emulator::with_tape(&mut *io, code.clone(), std::mem::take(tape), tp);
+        emulator.run().unwrap_or_else(|err| panic!("Emulator failed: {err}"));
+        let result = emulator.result();
+        *tape = emulator.into_tape();
+        result
+    }
+}
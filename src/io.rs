@@ -1,12 +1,63 @@
 use std::io::Read;
-use std::io::{self, Write};
+use std::io::{self, BufReader, BufWriter, Stdin, StdoutLock, Write};
 
-pub trait IO<'a> {
+pub trait IO {
     fn write_byte(&mut self, c: u8);
 
-    fn read_byte(&mut self) -> u8;
+    /// The next input byte, None at the end of the input
+    fn try_read_byte(&mut self) -> Option<u8>;
+
+    /// The next input byte, panics at the end of the input
+    fn read_byte(&mut self) -> u8 {
+        match self.try_read_byte() {
+            Some(c) => c,
+            None => panic!("read_byte(): no more input available (EOF)"),
+        }
+    }
 
     fn flush(&mut self);
+
+    /// Backends that count steps pass the number of steps run so far before
+    /// every read and write, e.g. for RecordingIO to log when they happened
+    fn set_step(&mut self, _step: u64) {}
+}
+
+/// Allows choosing the IO at runtime, at the cost of a virtual call per byte
+impl<I: IO + ?Sized> IO for Box<I> {
+    fn write_byte(&mut self, c: u8) {
+        (**self).write_byte(c);
+    }
+
+    fn try_read_byte(&mut self) -> Option<u8> {
+        (**self).try_read_byte()
+    }
+
+    fn flush(&mut self) {
+        (**self).flush();
+    }
+
+    fn set_step(&mut self, step: u64) {
+        (**self).set_step(step);
+    }
+}
+
+/// Lets code that takes an IO by value borrow one instead
+impl<I: IO + ?Sized> IO for &mut I {
+    fn write_byte(&mut self, c: u8) {
+        (**self).write_byte(c);
+    }
+
+    fn try_read_byte(&mut self) -> Option<u8> {
+        (**self).try_read_byte()
+    }
+
+    fn flush(&mut self) {
+        (**self).flush();
+    }
+
+    fn set_step(&mut self, step: u64) {
+        (**self).set_step(step);
+    }
 }
 
 pub struct SimpleIO {}
@@ -17,20 +68,20 @@ impl SimpleIO {
     }
 }
 
-impl<'a> IO<'a> for SimpleIO {
+impl IO for SimpleIO {
     fn write_byte(&mut self, c: u8) {
-        print!("{}", c as char);
-        io::stdout().flush().unwrap();
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(&[c]).unwrap();
+        stdout.flush().unwrap();
     }
 
-    fn read_byte(&mut self) -> u8 {
+    fn try_read_byte(&mut self) -> Option<u8> {
         io::stdout().flush().unwrap();
         let mut buf = [0u8; 1];
-        loop {
-            std::io::stdin().read_exact(&mut buf).unwrap();
-            if buf[0] != b'\r' {
-                return buf[0];
-            }
+        match std::io::stdin().read_exact(&mut buf) {
+            Ok(()) => Some(buf[0]),
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => None,
+            Err(err) => panic!("SimpleIO::read_byte(): {err}"),
         }
     }
 
@@ -39,81 +90,418 @@ impl<'a> IO<'a> for SimpleIO {
     }
 }
 
-pub struct BatchedIO {
+/// When BatchedIO writes its buffer to stdout
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlushPolicy {
+    /// Only when the buffer is full or the IO is dropped, best for bulk output
+    Full,
+    /// After every newline and before every read
+    Line,
+    /// After every byte
+    Unbuffered,
+    /// When the buffer is full and before every read, so prompts show up in time
+    BeforeRead,
+}
+
+/// Buffers output and reads input in blocks, on stdin and stdout unless given other streams
+pub struct BatchedIO<R: Read = Stdin, W: Write = StdoutLock<'static>> {
     buffer: Vec<u8>,
     pos: usize,
+    policy: FlushPolicy,
+    reader: R,
+    writer: W,
+    input: Vec<u8>,
+    input_pos: usize,
+    input_len: usize,
 }
 
 impl BatchedIO {
     pub fn new(buffer_size: usize) -> Self {
-        Self { buffer: vec![0; buffer_size], pos: 0}
+        Self::with_policy(buffer_size, FlushPolicy::BeforeRead)
+    }
+
+    pub fn with_policy(buffer_size: usize, policy: FlushPolicy) -> Self {
+        Self::with_streams(io::stdin(), io::stdout().lock(), buffer_size, policy)
     }
 }
 
-impl<'a> IO<'a> for BatchedIO {
+impl<R: Read, W: Write> BatchedIO<R, W> {
+    pub fn with_streams(reader: R, writer: W, buffer_size: usize, policy: FlushPolicy) -> Self {
+        Self {
+            buffer: vec![0; buffer_size.max(1)],
+            pos: 0,
+            policy,
+            reader,
+            writer,
+            input: vec![0; 4096],
+            input_pos: 0,
+            input_len: 0,
+        }
+    }
+
+    fn next_input_byte(&mut self) -> Option<u8> {
+        if self.input_pos == self.input_len {
+            self.input_len = loop {
+                match self.reader.read(&mut self.input) {
+                    Ok(len) => break len,
+                    // A signal arrived before any input, e.g. the terminal was resized
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(err) => panic!("BatchedIO::read_byte(): {err}"),
+                }
+            };
+            self.input_pos = 0;
+            if self.input_len == 0 {
+                return None;
+            }
+        }
+        let b = self.input[self.input_pos];
+        self.input_pos += 1;
+        Some(b)
+    }
+}
+
+impl<R: Read, W: Write> IO for BatchedIO<R, W> {
     fn write_byte(&mut self, c: u8) {
         if self.pos == self.buffer.len() {
             self.flush();
         }
         self.buffer[self.pos] = c;
         self.pos += 1;
+
+        match self.policy {
+            FlushPolicy::Unbuffered => self.flush(),
+            FlushPolicy::Line if c == b'\n' => self.flush(),
+            _ => {}
+        }
     }
 
-    fn read_byte(&mut self) -> u8 {
-        self.flush();
-        let mut buf = [0u8; 1];
-        loop {
-            std::io::stdin().read_exact(&mut buf).unwrap();
-            if buf[0] != b'\r' {
-                return buf[0];
-            }
+    fn try_read_byte(&mut self) -> Option<u8> {
+        if self.policy != FlushPolicy::Full {
+            self.flush();
         }
+        self.next_input_byte()
     }
 
     fn flush(&mut self) {
         if self.pos > 0 {
-            print!("{}", String::from_utf8_lossy(&self.buffer[..self.pos]));
+            self.writer.write_all(&self.buffer[..self.pos]).unwrap();
             self.pos = 0;
-            io::stdout().flush().unwrap();
         }
+        self.writer.flush().unwrap();
+    }
+}
+
+impl<R: Read, W: Write> Drop for BatchedIO<R, W> {
+    fn drop(&mut self) {
+        // Don't panic while dropping, the output might have been closed already
+        let _ = self.writer.write_all(&self.buffer[..self.pos]);
+        let _ = self.writer.flush();
     }
 }
 
-pub struct MemoryIO<'a> {
-    output: &'a mut Vec<u8>,
+pub struct MemoryIO {
+    output: Vec<u8>,
     input: Vec<u8>,
     input_pos: usize,
 }
 
-impl<'a> MemoryIO<'a> {
-    pub fn new(output_buffer: &'a mut Vec<u8>, input: Vec<u8>) -> Self {
+impl MemoryIO {
+    pub fn new(input: Vec<u8>) -> Self {
         Self {
-            output: output_buffer,
+            output: Vec::with_capacity(1024),
             input,
             input_pos: 0,
         }
     }
+
+    /// Everything written so far
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    /// Consume the IO and return everything written to it
+    pub fn into_output(self) -> Vec<u8> {
+        self.output
+    }
 }
 
-impl<'a> IO<'a> for MemoryIO<'a> {
+impl IO for MemoryIO {
     fn write_byte(&mut self, c: u8) {
         self.output.push(c);
     }
 
-    fn read_byte(&mut self) -> u8 {
-        // Read until a non-carriage-return or non-newline (skip CR and LF)
-        while self.input_pos < self.input.len() {
-            let b = self.input[self.input_pos];
-            self.input_pos += 1;
-            if b != b'\r' {
-                return b;
+    fn try_read_byte(&mut self) -> Option<u8> {
+        let b = *self.input.get(self.input_pos)?;
+        self.input_pos += 1;
+        Some(b)
+    }
+
+    fn flush(&mut self) {
+        // No-op for memory IO as everything is already in memory
+    }
+}
+
+/// What a read returns once the input is exhausted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EofPolicy {
+    /// Report the end of the input, read_byte panics
+    Panic,
+    /// Read a 0
+    Zero,
+    /// Read a 255, i.e. -1 for programs that treat cells as signed
+    MinusOne,
+}
+
+/// IO on top of any reader and writer, e.g. files, sockets, pipes to a child process or Cursors
+pub struct ReadWriteIO<R: Read, W: Write> {
+    reader: BufReader<R>,
+    writer: BufWriter<W>,
+    eof: EofPolicy,
+}
+
+impl<R: Read, W: Write> ReadWriteIO<R, W> {
+    /// Buffered on both ends, panics at the end of the input
+    pub fn new(reader: R, writer: W) -> Self {
+        Self::with_options(reader, writer, 8 * 1024, EofPolicy::Panic)
+    }
+
+    /// A buffer size of 0 passes every byte straight through to the reader and writer
+    pub fn with_options(reader: R, writer: W, buffer_size: usize, eof: EofPolicy) -> Self {
+        Self {
+            reader: BufReader::with_capacity(buffer_size, reader),
+            writer: BufWriter::with_capacity(buffer_size, writer),
+            eof,
+        }
+    }
+
+    /// Flush the output and give back the reader and writer
+    pub fn into_inner(self) -> (R, W) {
+        let writer = self.writer.into_inner().map_err(|err| err.into_error()).unwrap();
+        (self.reader.into_inner(), writer)
+    }
+}
+
+impl<R: Read, W: Write> IO for ReadWriteIO<R, W> {
+    fn write_byte(&mut self, c: u8) {
+        self.writer.write_all(&[c]).unwrap();
+    }
+
+    fn try_read_byte(&mut self) -> Option<u8> {
+        // The other end might wait for our output before sending more input
+        if self.reader.buffer().is_empty() {
+            self.writer.flush().unwrap();
+        }
+
+        let mut buf = [0u8; 1];
+        if self.reader.read(&mut buf).unwrap() == 0 {
+            return match self.eof {
+                EofPolicy::Panic => None,
+                EofPolicy::Zero => Some(0),
+                EofPolicy::MinusOne => Some(255),
+            };
+        }
+        Some(buf[0])
+    }
+
+    fn flush(&mut self) {
+        self.writer.flush().unwrap();
+    }
+}
+
+/// How line endings are translated between the program and the outside world
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Newline {
+    /// Pass every byte through unchanged
+    Raw,
+    /// Drop every '\r' on input
+    StripCr,
+    /// Turn "\r\n" on input into a single '\n'
+    CrLfToLf,
+    /// Turn '\n' on output into "\r\n"
+    LfToCrLf,
+    /// Terminals send '\r' for Enter, hand it to the program as '\n'
+    EnterSends10,
+}
+
+/// Applies a newline translation on top of another IO
+pub struct NewlineIO<I: IO> {
+    inner: I,
+    mode: Newline,
+    // Byte read ahead while looking for "\r\n"
+    pending: Option<u8>,
+    // The last byte handed out was a translated '\r'
+    after_cr: bool,
+}
+
+impl<I: IO> NewlineIO<I> {
+    pub fn new(inner: I, mode: Newline) -> Self {
+        Self { inner, mode, pending: None, after_cr: false }
+    }
+
+    pub fn into_inner(self) -> I {
+        self.inner
+    }
+
+    fn next_byte(&mut self) -> Option<u8> {
+        match self.pending.take() {
+            Some(b) => Some(b),
+            None => self.inner.try_read_byte(),
+        }
+    }
+}
+
+impl<I: IO> IO for NewlineIO<I> {
+    fn write_byte(&mut self, c: u8) {
+        if self.mode == Newline::LfToCrLf && c == b'\n' {
+            self.inner.write_byte(b'\r');
+        }
+        self.inner.write_byte(c);
+    }
+
+    fn try_read_byte(&mut self) -> Option<u8> {
+        match self.mode {
+            Newline::Raw | Newline::LfToCrLf => self.next_byte(),
+            Newline::StripCr => loop {
+                let b = self.next_byte()?;
+                if b != b'\r' {
+                    return Some(b);
+                }
+            },
+            Newline::CrLfToLf => {
+                let b = self.next_byte()?;
+                if b != b'\r' {
+                    return Some(b);
+                }
+                // A '\r' at the end of the input is still handed out
+                match self.next_byte() {
+                    Some(b'\n') => Some(b'\n'),
+                    Some(next) => {
+                        self.pending = Some(next);
+                        Some(b'\r')
+                    }
+                    None => Some(b'\r'),
+                }
+            }
+            // No read ahead here, an interactive program must not block on the byte after Enter
+            Newline::EnterSends10 => {
+                let mut b = self.next_byte()?;
+                if self.after_cr && b == b'\n' {
+                    b = self.next_byte()?;
+                }
+                self.after_cr = b == b'\r';
+                Some(if self.after_cr { b'\n' } else { b })
             }
         }
-        // No more input: panic with a clear error message
-        panic!("MemoryIO::read_byte(): no more input available (EOF)");
     }
 
     fn flush(&mut self) {
-        // No-op for memory IO as everything is already in memory
+        self.inner.flush();
+    }
+
+    fn set_step(&mut self, step: u64) {
+        self.inner.set_step(step);
+    }
+}
+
+/// How NumericIO formats and parses cell values
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NumberFormat {
+    Decimal,
+    Hex,
+}
+
+/// Writes cells as numbers and reads whitespace separated numbers into cells
+///
+/// Cells are 8 bits wide, so numbers read have to be within -255..=255. Negative
+/// ones are stored as their two's complement, -1 reads as 255. A number is a '-'
+/// or a letter or digit followed by letters and digits, so anything else ends it
+/// and is read next. Bytes that can't start a number are read as they are,
+/// e.g. "12," reads as 12 and then 44. The end of the input ends a number.
+/// Reading at the end of the input passes on what the inner IO does there, also
+/// when it returns a byte that can't start a number, like the 0 of EofPolicy::Zero.
+pub struct NumericIO<I: IO> {
+    inner: I,
+    format: NumberFormat,
+    separator: Vec<u8>,
+    // The byte that ended the last number, read next
+    pending: Option<u8>,
+}
+
+impl<I: IO> NumericIO<I> {
+    pub fn new(inner: I, format: NumberFormat, separator: &[u8]) -> Self {
+        Self { inner, format, separator: separator.to_vec(), pending: None }
+    }
+
+    pub fn into_inner(self) -> I {
+        self.inner
+    }
+
+    fn next_byte(&mut self) -> Option<u8> {
+        match self.pending.take() {
+            Some(b) => Some(b),
+            None => self.inner.try_read_byte(),
+        }
+    }
+}
+
+impl<I: IO> IO for NumericIO<I> {
+    fn write_byte(&mut self, c: u8) {
+        let number = match self.format {
+            NumberFormat::Decimal => c.to_string(),
+            NumberFormat::Hex => format!("{c:02x}"),
+        };
+        for b in number.bytes().chain(self.separator.iter().copied()) {
+            self.inner.write_byte(b);
+        }
+    }
+
+    fn try_read_byte(&mut self) -> Option<u8> {
+        let mut b = self.next_byte()?;
+        while b.is_ascii_whitespace() {
+            b = self.next_byte()?;
+        }
+        if b != b'-' && !b.is_ascii_alphanumeric() {
+            return Some(b);
+        }
+
+        let mut text = String::from(b as char);
+        while let Some(next) = self.inner.try_read_byte() {
+            if !next.is_ascii_alphanumeric() {
+                // Whitespace only separates numbers, anything else is read next
+                if !next.is_ascii_whitespace() {
+                    self.pending = Some(next);
+                }
+                break;
+            }
+            text.push(next as char);
+        }
+
+        let (negative, digits) = match text.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, text.as_str()),
+        };
+        let value = match self.format {
+            NumberFormat::Decimal => digits.parse::<u64>(),
+            NumberFormat::Hex => {
+                let digits = digits.strip_prefix("0x").unwrap_or(digits);
+                u64::from_str_radix(digits, 16)
+            }
+        };
+        let Ok(value) = value else {
+            panic!("NumericIO::read_byte(): expected a number but got {text:?}");
+        };
+        let Ok(value) = u8::try_from(value) else {
+            panic!("NumericIO::read_byte(): {text} does not fit in a cell");
+        };
+
+        Some(if negative { value.wrapping_neg() } else { value })
     }
-}
\ No newline at end of file
+
+    fn flush(&mut self) {
+        self.inner.flush();
+    }
+
+    fn set_step(&mut self, step: u64) {
+        self.inner.set_step(step);
+    }
+}
@@ -1,8 +1,15 @@
+use crate::aarch64::{self, reg};
 use crate::vm;
+use std::ops::Range;
 
 
 pub struct JIT {
     code: Vec<vm::Op>,
+    metered: bool,
+    /// Tape faults report the op that moved off the tape
+    fault_ops: bool,
+    /// Where each op starts in the source, for naming symbols
+    source_offsets: Option<Vec<usize>>,
 }
 
 impl JIT {
@@ -11,138 +18,824 @@ impl JIT {
 
         Self { 
             code: code,
+            metered: false,
+            fault_ops: false,
+            source_offsets: None,
         }
     }
+
+    /// Like new, but the generated code counts the steps it runs and stops once it
+    /// runs out of fuel. A step is one Op, the same as a Vm step.
+    pub fn metered(code: Vec<vm::Op>) -> Self {
+        Self { code, metered: true, fault_ops: false, source_offsets: None }
+    }
+
+    /// Make tape faults report the index of the op that moved off the tape, see
+    /// RunSummary::fault_op. Only the paths to the fault get longer.
+    pub fn set_fault_ops(&mut self, report: bool) {
+        self.fault_ops = report;
+    }
+
+    /// Name symbols after the source offsets from Compiler::compile_with_offsets
+    /// instead of op indices
+    pub fn set_source_offsets(&mut self, offsets: Vec<usize>) {
+        assert_eq!(offsets.len(), self.code.len(), "Need one source offset per op");
+        self.source_offsets = Some(offsets);
+    }
+}
+
+/// A named range of generated code, so profilers and debuggers can tell what is running
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    /// Offset in the generated code
+    pub offset: usize,
+    pub len: usize,
+}
+
+/// Layout of the header rt_ptr points at, Runtime mirrors it in a #[repr(C)] struct
+pub(crate) mod header {
+    /// Output buffer start, the cursor loaded on entry
+    pub const OUT_START: u32 = 0;
+    pub const OUT_END: u32 = 8;
+    /// Tape bounds, the tape pointer argument may point anywhere in between
+    pub const TAPE_START: u32 = 16;
+    pub const TAPE_END: u32 = 24;
+    /// Fuel limit in, read by metered code only
+    pub const FUEL: u32 = 32;
+    /// Set by the IO functions when they fail, and by the generated code on exit
+    pub const STATUS: u32 = 40;
+    /// Written on exit, from the tape start
+    pub const TAPE_OFFSET: u32 = 48;
+    /// Written on exit by metered code only
+    pub const STEPS: u32 = 56;
+    /// Called by lazy code to compile a loop on first entry, see JIT::compile_lazy
+    pub const COMPILE_LOOP: u32 = 64;
+    /// Written on a tape fault by code from a JIT with set_fault_ops only
+    pub const FAULT_OP: u32 = 72;
 }
 
+/// Status codes returned by generated code
+pub(crate) mod status {
+    pub const OK: u64 = 0;
+    pub const IO_ERROR: u64 = 1;
+    pub const EOF: u64 = 2;
+    pub const OUT_OF_FUEL: u64 = 3;
+    pub const TAPE_FAULT: u64 = 4;
+    pub const COMPILE_ERROR: u64 = 5;
+}
+
+/// Version of the generated code and of the header and status codes it relies on.
+/// Bump it with any change to either, the code cache only reuses code of the same version
+pub const ABI_VERSION: u32 = 1;
+
 impl JIT {
 
     // The final function will be called with the following signature:
-    // fn(tape_ptr: *mut u8,
+    // fn(tape_ptr: *mut u8, the current cell,
     //    rt_ptr: *mut u8,
-    //    write_char: extern "C" fn(rt_ptr: *mut u8, u8),
+    //    flush_output: extern "C" fn(rt_ptr: *mut u8, cursor: *mut u8) -> *mut u8,
     //    read_char: extern "C" fn(rt_ptr: *mut u8) -> u8)
-    //    -> u8
-    // For now the tape is not growable, so we can just pass a pointer to the tape
+    //    -> u64
+    // For now the tape is not growable, so we can just pass a pointer to the tape.
+    // rt_ptr points at the header described in the header module. Output bytes are
+    // stored in the buffer directly and flush_output hands them to the IO, returning
+    // the buffer start as the new cursor. An IO function that fails sets the status
+    // in the header. The return value is the status, the final tape offset and step
+    // count are written to the header.
 
-    // ARM64 only, no support for x86
+    // Generates ARM64 code, which can be done on any host but only runs on ARM64
     pub fn compile(&self) -> Result<Vec<u8>, String> {
-        // Ensure we're on ARM64
-        assert_eq!(std::env::consts::ARCH, "aarch64");
+        Ok(self.compile_part(Part::Program)?.0)
+    }
 
-        // Calling convention:
-        //   x0: tape_ptr, x1: rt_ptr, x2: write_fn, x3: read_fn
-        // We'll save them in callee-saved registers:
-        //   x19 = tape_ptr, x20 = rt_ptr, x21 = write_fn, x22 = read_fn
-        let mut code: Vec<u8> = Vec::new();
-        // PROLOGUE: push frame pointer & link register
-        code.extend(&[0xFD, 0x7B, 0xBF, 0xA9]); // stp x29, x30, [sp, #-16]!
-        code.extend(&[0xFD, 0x03, 0x00, 0x91]); // mov x29, sp
-        // Save arguments into callee-saved regs via ADD #0 (mov xN, xM)
-        code.extend(&[0x13, 0x00, 0x00, 0x91]); // add x19, x0, #0
-        code.extend(&[0x34, 0x00, 0x00, 0x91]); // add x20, x1, #0
-        code.extend(&[0x55, 0x00, 0x00, 0x91]); // add x21, x2, #0
-        code.extend(&[0x76, 0x00, 0x00, 0x91]); // add x22, x3, #0
-
-        // First pass: compute byte offsets for each instruction label
-        let mut label_offsets = vec![0usize; self.code.len()];
-        {
-            let mut offset = code.len();
-            for (i, op) in self.code.iter().enumerate() {
-                label_offsets[i] = offset;
-                offset += match op {
-                    vm::Op::Inc(_) | vm::Op::Dec(_) => 12,
-                    vm::Op::MovR(_) | vm::Op::MovL(_) => 4,
-                    vm::Op::Print | vm::Op::Read => 16,
-                    vm::Op::JmpIfZ(_) | vm::Op::JmpIfNZ(_) => 8,
-                    vm::Op::Nop => 0,
-                };
-            }
-        }
-
-        // Second pass: emit each opcode sequence
-        for (i, op) in self.code.iter().enumerate() {
+    /// Like compile, but also returns a symbol for every top-level loop, named
+    /// bf_loop_<from>_<to> after the offsets of its brackets, and bf_program
+    /// symbols for the code in between
+    pub fn compile_with_symbols(&self) -> Result<(Vec<u8>, Vec<Symbol>), String> {
+        let (code, loops) = self.compile_part(Part::Program)?;
+        let symbols = self.symbols(code.len(), &loops);
+        Ok((code, symbols))
+    }
+
+    /// Like compile, but every top-level loop is left as a stub that compiles the
+    /// loop on first entry, for huge programs where most loops never run. The stub
+    /// calls the function in the header with the loop's JmpIfZ index, which compiles
+    /// the loop with compile_loop and patches the stub to call it directly:
+    ///
+    /// ```text
+    /// movz x16, #index; movk x16, ... (x3); bl <compile loop>
+    /// movz x16, #loop;  movk x16, ... (x3); blr x16
+    /// ```
+    pub(crate) fn compile_lazy(&self) -> Result<Vec<u8>, String> {
+        Ok(self.compile_part(Part::LazyProgram)?.0)
+    }
+
+    /// Compile the top-level loop starting at the JmpIfZ at index start for code
+    /// from compile_lazy. The result is position independent and entered with
+    /// the cell in w23 synced and non-zero, it returns once the cell is zero.
+    pub(crate) fn compile_loop(&self, start: usize) -> Result<Vec<u8>, String> {
+        match self.code.get(start) {
+            Some(vm::Op::JmpIfZ(_)) => Ok(self.compile_part(Part::Loop(start))?.0),
+            _ => Err(format!("No loop starts at {start}")),
+        }
+    }
+
+    /// Name of the top-level loop starting at the JmpIfZ at index start
+    pub(crate) fn loop_name(&self, start: usize) -> String {
+        let end = self.loop_end(start).unwrap_or_else(|| panic!("No loop starts at {start}"));
+        match &self.source_offsets {
+            Some(offsets) => format!("bf_loop_{}_{}", offsets[start], offsets[end]),
+            None => format!("bf_loop_{}_{}", start, end),
+        }
+    }
+
+    /// Index of the JmpIfNZ closing the loop that starts at the JmpIfZ at index
+    /// start, found by matching brackets. The jump targets in the Ops are only 16
+    /// bits, so they can't be trusted in programs longer than that.
+    fn loop_end(&self, start: usize) -> Option<usize> {
+        let vm::Op::JmpIfZ(_) = self.code.get(start)? else { return None };
+        let mut depth = 0;
+        for (i, op) in self.code.iter().enumerate().skip(start) {
+            match op {
+                vm::Op::JmpIfZ(_) => depth += 1,
+                vm::Op::JmpIfNZ(_) => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Some(i);
+                    }
+                }
+                _ => {}
+            }
+        }
+        None
+    }
+
+    /// Symbols for a program with the given top-level loops, and bf_program for the gaps
+    pub(crate) fn symbols(&self, len: usize, loops: &[(usize, Range<usize>)]) -> Vec<Symbol> {
+        let mut symbols = vec![];
+        let mut offset = 0;
+        for (start, range) in loops {
+            if range.start > offset {
+                symbols.push(Symbol { name: "bf_program".to_string(), offset, len: range.start - offset });
+            }
+            symbols.push(Symbol { name: self.loop_name(*start), offset: range.start, len: range.len() });
+            offset = range.end;
+        }
+        symbols.push(Symbol { name: "bf_program".to_string(), offset, len: len - offset });
+        symbols
+    }
+
+    /// Compile a part of the program, also returning the code ranges of its
+    /// top-level loops by their JmpIfZ index
+    fn compile_part(&self, part: Part) -> Result<(Vec<u8>, LoopRanges), String> {
+        // Start with every loop using short branches and widen the ones that
+        // don't reach. Widening only ever grows the code, so this terminates.
+        let mut forms = vec![BranchForm::Short; self.code.len()];
+        loop {
+            let (code, too_far, loops) = self.emit(&forms, part)?;
+            if too_far.is_empty() {
+                return Ok((code, loops));
+            }
+            for i in too_far {
+                forms[i] = forms[i].wider();
+            }
+        }
+    }
+
+    /// Emit the part with the given branch form for each loop, indexed by its JmpIfZ.
+    /// Also returns the loops whose branches are out of range for their form, and
+    /// the code ranges of the top-level loops compiled inline.
+    fn emit(&self, forms: &[BranchForm], part: Part) -> Result<(Vec<u8>, Vec<usize>, LoopRanges), String> {
+        let mut asm = Emitter::new();
+        // Open loops as (JmpIfZ index, branch to backpatch, offset of the loop body)
+        let mut loops: Vec<(usize, Option<usize>, usize)> = vec![];
+        let ops = match part {
+            Part::Program | Part::LazyProgram => {
+                self.prologue(&mut asm);
+                0..self.code.len()
+            }
+            Part::Loop(start) => {
+                let end = self.loop_end(start).ok_or("Unmatched '['")?;
+                // Called from a stub, so the return address has to survive the IO calls
+                // stp x29, x30, [sp, #-16]!
+                asm.emit(aarch64::stp_x(29, 30, -LOOP_FRAME_SIZE, true));
+                // The stub is only called with the cell synced and non-zero
+                asm.cell = CellCache { loaded: true, dirty: false, wide: false };
+                // The JmpIfZ has been run and charged for by the stub's caller
+                loops.push((start, None, asm.offset()));
+                start + 1..end + 1
+            }
+        };
+        // Loop stubs as (offset of the bl, JmpIfZ index)
+        let mut stubs = vec![];
+        // Top-level loops compiled inline as (JmpIfZ index, code range)
+        let mut top_level = vec![];
+        let mut top_level_start = 0;
+        let mut too_far = vec![];
+        // Steps since fuel was last charged
+        let mut steps = 0;
+
+        let mut i = ops.start;
+        while i < ops.end {
+            let op = &self.code[i];
+            steps += 1;
             match op {
                 vm::Op::Nop => {}
                 vm::Op::Inc(n) => {
-                    // ldrb w4, [x19]
-                    code.extend(&0x39401664u32.to_le_bytes());
-                    // add w4, w4, #n
-                    let instr = 0x11000000u32 | ((*n as u32) << 10) | (4 << 5) | 4;
-                    code.extend(&instr.to_le_bytes());
-                    // strb w4, [x19]
-                    code.extend(&0x39001664u32.to_le_bytes());
+                    asm.load_cell();
+                    // add w23, w23, #n
+                    asm.emit(aarch64::add_w(reg::CELL, reg::CELL, *n as u32));
+                    asm.cell.dirty = true;
+                    asm.cell.wide = true;
                 }
                 vm::Op::Dec(n) => {
-                    code.extend(&0x39401664u32.to_le_bytes());
-                    let instr = 0x51000000u32 | ((*n as u32) << 10) | (4 << 5) | 4;
-                    code.extend(&instr.to_le_bytes());
-                    code.extend(&0x39001664u32.to_le_bytes());
+                    asm.load_cell();
+                    // sub w23, w23, #n
+                    asm.emit(aarch64::sub_w(reg::CELL, reg::CELL, *n as u32));
+                    asm.cell.dirty = true;
+                    asm.cell.wide = true;
                 }
                 vm::Op::MovR(n) => {
+                    asm.spill_cell();
                     // add x19, x19, #n
-                    let instr = 0x91000000u32 | ((*n as u32) << 10) | (19 << 5) | 19;
-                    code.extend(&instr.to_le_bytes());
+                    asm.emit(aarch64::add_x(reg::TAPE, reg::TAPE, *n as u32));
+                    asm.cell.loaded = false;
+                    // cmp x19, x28; b.lo <ok>; b <tape fault>
+                    asm.emit(aarch64::cmp_x(reg::TAPE, reg::TAPE_END));
+                    asm.tape_fault_unless(aarch64::b_lo, self.fault_ops.then_some(i));
                 }
                 vm::Op::MovL(n) => {
-                    let instr = 0xD1000000u32 | ((*n as u32) << 10) | (19 << 5) | 19;
-                    code.extend(&instr.to_le_bytes());
+                    asm.spill_cell();
+                    // sub x19, x19, #n
+                    asm.emit(aarch64::sub_x(reg::TAPE, reg::TAPE, *n as u32));
+                    asm.cell.loaded = false;
+                    // cmp x19, x26; b.hs <ok>; b <tape fault>
+                    asm.emit(aarch64::cmp_x(reg::TAPE, reg::TAPE_START));
+                    asm.tape_fault_unless(aarch64::b_hs, self.fault_ops.then_some(i));
+                }
+                vm::Op::ScanR(n) | vm::Op::ScanL(n) => {
+                    // The scan reads the tape, so it has to be up to date
+                    asm.spill_cell();
+                    asm.scan(*n, matches!(op, vm::Op::ScanR(_)), self.fault_ops.then_some(i));
+                    asm.cell.loaded = false;
                 }
                 vm::Op::Print => {
-                    // ldrb w4, [x19]
-                    code.extend(&0x39401664u32.to_le_bytes());
-                    // mov x0, x20 via ADD #0
-                    code.extend(&0x91000280u32.to_le_bytes());
-                    // mov w1, w4 via ADD #0 (32-bit)
-                    code.extend(&0x11000081u32.to_le_bytes());
-                    // blr x21 (branch with link to reg X21)
-                    code.extend(&[0xA0, 0x02, 0x3F, 0xD6]);
+                    // strb only stores the low byte, so w23 doesn't have to be narrowed
+                    asm.load_cell();
+                    // cmp x24, x25
+                    asm.emit(aarch64::cmp_x(reg::OUT, reg::OUT_END));
+                    // b.lo <store>, over the flush when there is room
+                    let skip = asm.offset();
+                    asm.emit(aarch64::b_lo(0));
+                    asm.flush_output();
+                    asm.exit_on_io_error();
+                    let store = asm.offset();
+                    asm.patch(skip, aarch64::b_lo((store - skip) as i64));
+                    // strb w23, [x24], #1
+                    asm.emit(aarch64::strb_post(reg::CELL, reg::OUT, 1));
                 }
                 vm::Op::Read => {
+                    // Output has to be visible before the program waits for input
+                    asm.flush_output();
+                    asm.exit_on_io_error();
+                    // The cell is overwritten, so a pending store would be dead
                     // mov x0, x20 via ADD #0
-                    code.extend(&0x91000280u32.to_le_bytes());
+                    asm.emit(aarch64::add_x(0, reg::RT, 0));
                     // blr x22 (branch with link to reg X22)
-                    code.extend(&[0xC0, 0x02, 0x3F, 0xD6]);
-                    // strb w0, [x19]
-                    code.extend(&0x390006C4u32.to_le_bytes());
+                    asm.emit(aarch64::blr(reg::READ_FN));
+                    // A failed read leaves the cell as it was
+                    asm.exit_on_io_error();
+                    // mov w23, w0 via ADD #0, the upper bits of a u8 return value are undefined
+                    asm.emit(aarch64::add_w(reg::CELL, 0, 0));
+                    asm.cell = CellCache { loaded: true, dirty: true, wide: true };
+                }
+                vm::Op::JmpIfZ(_) if matches!(part, Part::LazyProgram) && loops.is_empty() => {
+                    // The same state at the loop boundary as below, which the loop code relies on
+                    asm.sync_cell();
+                    if self.metered {
+                        asm.charge_fuel(steps);
+                        steps = 0;
+                    }
+                    // cbz w23, <loop end>, over the stub
+                    asm.emit(aarch64::cbz_w(reg::CELL, 4 + STUB_SIZE as i64));
+                    // mov x16, #index
+                    for instr in aarch64::mov_x_imm64(reg::IP0, i as u64) {
+                        asm.emit(instr);
+                    }
+                    // bl <compile loop>
+                    stubs.push((asm.offset(), i));
+                    asm.emit(aarch64::bl(0));
+                    // The loop code leaves the cell synced, and its JmpIfNZ charged the fuel
+                    i = self.loop_end(i).ok_or("Unmatched '['")?;
                 }
-                vm::Op::JmpIfZ(target) => {
-                    code.extend(&0x39401664u32.to_le_bytes());
-                    // cbz w4, <label>
-                    let to = label_offsets[*target as usize] as i64 - (code.len() as i64 + 4);
-                    let imm19 = ((to / 4) as i32) & 0x7FFFF;
-                    let instr = 0x34000000u32 | ((imm19 as u32) << 5) | 4;
-                    code.extend(&instr.to_le_bytes());
+                vm::Op::JmpIfZ(_) => {
+                    if loops.is_empty() {
+                        top_level_start = asm.offset();
+                    }
+                    // Both edges into the loop body and past the loop leave the cell
+                    // loaded and stored, so neither side has to reload it
+                    asm.sync_cell();
+                    if self.metered {
+                        asm.charge_fuel(steps);
+                        steps = 0;
+                    }
+                    let branch = asm.offset();
+                    // cbz w23, <loop end>, patched once the end is known
+                    asm.branch(forms[i], true, branch);
+                    loops.push((i, Some(branch), asm.offset()));
                 }
-                vm::Op::JmpIfNZ(target) => {
-                    code.extend(&0x39401664u32.to_le_bytes());
-                    let to = label_offsets[*target as usize] as i64 - (code.len() as i64 + 4);
-                    let imm19 = ((to / 4) as i32) & 0x7FFFF;
-                    let instr = 0x35000000u32 | ((imm19 as u32) << 5) | 4;
-                    code.extend(&instr.to_le_bytes());
+                vm::Op::JmpIfNZ(_) => {
+                    asm.sync_cell();
+                    if self.metered {
+                        // Every loop iteration passes here, so this is where fuel runs out
+                        asm.charge_fuel(steps);
+                        steps = 0;
+                        // b.pl <continue>; b <out of fuel>
+                        asm.emit(aarch64::b_pl(8));
+                        asm.exit(Exit::OutOfFuel);
+                    }
+                    let (start, branch, body) = loops.pop().ok_or("Unmatched ']'")?;
+                    // cbnz w23, <loop body>
+                    let back_in_range = asm.branch(forms[start], false, body);
+                    let end = asm.offset();
+                    let forward_in_range = match branch {
+                        Some(branch) => asm.patch_branch(forms[start], true, branch, end),
+                        None => true,
+                    };
+                    if !back_in_range || !forward_in_range {
+                        too_far.push(start);
+                    }
+                    if loops.is_empty() && branch.is_some() {
+                        top_level.push((start, top_level_start..asm.offset()));
+                    }
                 }
             }
+            i += 1;
+        }
+        if !loops.is_empty() {
+            return Err("Unmatched '['".to_string());
         }
 
-        // EPILOGUE
-        // mov w0, #0
-        code.extend(&0x52800000u32.to_le_bytes());
-        // ldp x29, x30, [sp], #16
-        code.extend(&[0xFD, 0x7B, 0xC1, 0xA8]);
+        if let Part::Loop(_) = part {
+            // ldp x29, x30, [sp], #16; ret
+            asm.emit(aarch64::ldp_x(29, 30, LOOP_FRAME_SIZE, true));
+            asm.emit(aarch64::ret());
+        } else {
+            if self.metered && steps > 0 {
+                asm.charge_fuel(steps);
+            }
+            // EPILOGUE
+            asm.spill_cell();
+            asm.flush_output();
+            // The status is still OK unless the final flush failed
+            asm.exit(Exit::End);
+        }
+
+        // Exit stubs that record why execution stopped, hand over the output
+        // written so far like the end of the program does and fall through to the
+        // exit. A failing flush replaces the status with its own.
+        let out_of_fuel = asm.offset();
+        // mov x16, #OUT_OF_FUEL; b <set status>
+        asm.emit(aarch64::movz_x(reg::IP0, status::OUT_OF_FUEL as u16, 0));
+        asm.emit(aarch64::b(if self.fault_ops { 12 } else { 8 }));
+        let tape_fault = asm.offset();
+        if self.fault_ops {
+            // str x17, [x20, #FAULT_OP], before the flush clobbers it
+            asm.emit(aarch64::str_x(reg::IP1, reg::RT, header::FAULT_OP));
+        }
+        // mov x16, #TAPE_FAULT
+        asm.emit(aarch64::movz_x(reg::IP0, status::TAPE_FAULT as u16, 0));
+        // str x16, [x20, #STATUS]
+        asm.emit(aarch64::str_x(reg::IP0, reg::RT, header::STATUS));
+        asm.flush_output();
+
+        let exit = asm.offset();
+        if let Part::Loop(_) = part {
+            // Loop code has its own exit, so it doesn't have to know where the program
+            // is. It leaves the program straight away, dropping the stub's frame.
+            // add sp, sp, #16
+            asm.emit(aarch64::add_x(aarch64::SP, aarch64::SP, LOOP_FRAME_SIZE as u32));
+        }
+        // sub x16, x19, x26; str x16, [x20, #TAPE_OFFSET]
+        asm.emit(aarch64::sub_x_reg(reg::IP0, reg::TAPE, reg::TAPE_START));
+        asm.emit(aarch64::str_x(reg::IP0, reg::RT, header::TAPE_OFFSET));
+        if self.metered {
+            // ldr x16, [x20, #FUEL]; sub x16, x16, x27; str x16, [x20, #STEPS]
+            asm.emit(aarch64::ldr_x(reg::IP0, reg::RT, header::FUEL));
+            asm.emit(aarch64::sub_x_reg(reg::IP0, reg::IP0, reg::FUEL));
+            asm.emit(aarch64::str_x(reg::IP0, reg::RT, header::STEPS));
+        }
+        // ldr x0, [x20, #STATUS]
+        asm.emit(aarch64::ldr_x(0, reg::RT, header::STATUS));
+        // restore the callee-saved registers
+        asm.emit(aarch64::ldp_x(27, 28, 80, false)); // ldp x27, x28, [sp, #80]
+        asm.emit(aarch64::ldp_x(25, 26, 64, false)); // ldp x25, x26, [sp, #64]
+        asm.emit(aarch64::ldp_x(23, 24, 48, false)); // ldp x23, x24, [sp, #48]
+        asm.emit(aarch64::ldp_x(21, 22, 32, false)); // ldp x21, x22, [sp, #32]
+        asm.emit(aarch64::ldp_x(19, 20, 16, false)); // ldp x19, x20, [sp, #16]
+        // ldp x29, x30, [sp], #96
+        asm.emit(aarch64::ldp_x(29, 30, FRAME_SIZE, true));
         // ret
-        code.extend(&[0xC0, 0x03, 0x5F, 0xD6]);
+        asm.emit(aarch64::ret());
+
+        if !stubs.is_empty() {
+            // Shared by the loop stubs, x16 holds the loop and x30 the end of the stub
+            let compile_loop = asm.offset();
+            // stp x29, x30, [sp, #-16]!
+            asm.emit(aarch64::stp_x(29, 30, -LOOP_FRAME_SIZE, true));
+            // compile_loop(rt_ptr, index, stub) via the header
+            asm.emit(aarch64::add_x(0, reg::RT, 0)); // mov x0, x20
+            asm.emit(aarch64::add_x(1, reg::IP0, 0)); // mov x1, x16
+            asm.emit(aarch64::sub_x(2, 30, STUB_SIZE as u32)); // sub x2, x30, #20
+            asm.emit(aarch64::ldr_x(reg::IP0, reg::RT, header::COMPILE_LOOP)); // ldr x16, [x20, #COMPILE_LOOP]
+            asm.emit(aarch64::blr(reg::IP0)); // blr x16
+            // ldp x29, x30, [sp], #16
+            asm.emit(aarch64::ldp_x(29, 30, LOOP_FRAME_SIZE, true));
+            // cbnz x0, <enter>; b <exit>, the status is set when compiling failed
+            asm.emit(aarch64::cbnz_x(0, 8));
+            asm.exit(Exit::End);
+            // br x0, the loop returns to the end of the stub
+            asm.emit(aarch64::br(0));
+            for (at, _) in &stubs {
+                let offset = compile_loop as i64 - *at as i64;
+                if !aarch64::branch_in_range(offset, 26) {
+                    return Err("Program too large to reach the loop compiler".to_string());
+                }
+                asm.patch(*at, aarch64::bl(offset));
+            }
+        }
 
-        /*// DEBUG: map each high-level Op to its code offset
-        #[cfg(debug_assertions)]
-        {
-            println!("JIT instruction map:");
-            for (i, op) in self.code.iter().enumerate() {
-                let off = label_offsets[i];
-                println!("  0x{:04x}: {:?}", off, op);
+        for (at, target) in std::mem::take(&mut asm.exits) {
+            let target = match target {
+                Exit::End => exit,
+                Exit::OutOfFuel => out_of_fuel,
+                Exit::TapeFault => tape_fault,
+            };
+            let offset = target as i64 - at as i64;
+            if !aarch64::branch_in_range(offset, 26) {
+                return Err("Program too large to reach the exit".to_string());
             }
-        }*/
-        return Ok(code);
+            asm.patch(at, aarch64::b(offset));
+        }
+
+        Ok((asm.code, too_far, top_level))
+    }
+
+    /// Set up the frame and registers for a call from the runtime
+    fn prologue(&self, asm: &mut Emitter) {
+        // Calling convention:
+        //   x0: tape_ptr, x1: rt_ptr, x2: write_fn, x3: read_fn
+        // We'll save them in callee-saved registers:
+        //   x19 = tape_ptr, x20 = rt_ptr, x21 = write_fn, x22 = read_fn
+        // keep the current cell in w23, which survives the IO calls,
+        // the output cursor and buffer end in x24 and x25,
+        // the tape start and end in x26 and x28 and the fuel in x27
+        // PROLOGUE: push frame pointer & link register, save the callee-saved registers we use
+        asm.emit(aarch64::stp_x(29, 30, -FRAME_SIZE, true)); // stp x29, x30, [sp, #-96]!
+        asm.emit(aarch64::add_x(29, aarch64::SP, 0)); // mov x29, sp
+        asm.emit(aarch64::stp_x(19, 20, 16, false)); // stp x19, x20, [sp, #16]
+        asm.emit(aarch64::stp_x(21, 22, 32, false)); // stp x21, x22, [sp, #32]
+        asm.emit(aarch64::stp_x(23, 24, 48, false)); // stp x23, x24, [sp, #48]
+        asm.emit(aarch64::stp_x(25, 26, 64, false)); // stp x25, x26, [sp, #64]
+        asm.emit(aarch64::stp_x(27, 28, 80, false)); // stp x27, x28, [sp, #80]
+        // Save arguments into callee-saved regs via ADD #0 (mov xN, xM)
+        asm.emit(aarch64::add_x(reg::TAPE, 0, 0)); // add x19, x0, #0
+        asm.emit(aarch64::add_x(reg::RT, 1, 0)); // add x20, x1, #0
+        asm.emit(aarch64::add_x(reg::WRITE_FN, 2, 0)); // add x21, x2, #0
+        asm.emit(aarch64::add_x(reg::READ_FN, 3, 0)); // add x22, x3, #0
+        // Load the output buffer, tape bounds and fuel from the runtime
+        asm.emit(aarch64::ldr_x(reg::OUT, reg::RT, header::OUT_START)); // ldr x24, [x20]
+        asm.emit(aarch64::ldr_x(reg::OUT_END, reg::RT, header::OUT_END)); // ldr x25, [x20, #8]
+        asm.emit(aarch64::ldr_x(reg::TAPE_START, reg::RT, header::TAPE_START)); // ldr x26, [x20, #16]
+        asm.emit(aarch64::ldr_x(reg::TAPE_END, reg::RT, header::TAPE_END)); // ldr x28, [x20, #24]
+        if self.metered {
+            asm.emit(aarch64::ldr_x(reg::FUEL, reg::RT, header::FUEL)); // ldr x27, [x20, #32]
+        }
     }
+}
 
+/// Code ranges of top-level loops by the index of their JmpIfZ
+type LoopRanges = Vec<(usize, Range<usize>)>;
+
+/// Which code emit generates
+#[derive(Debug, Clone, Copy)]
+enum Part {
+    /// The whole program
+    Program,
+    /// The program with stubs for the top-level loops
+    LazyProgram,
+    /// The top-level loop starting at the given JmpIfZ
+    Loop(usize),
 }
 
+/// Bytes of a loop stub, which is patched in place once the loop is compiled
+pub(crate) const STUB_SIZE: usize = 20;
+
+/// What a loop stub is patched with once the loop is compiled to the given address
+pub(crate) fn patched_stub(addr: u64) -> Vec<u8> {
+    // mov x16, #addr; blr x16
+    let mut instrs = aarch64::mov_x_imm64(reg::IP0, addr).to_vec();
+    instrs.push(aarch64::blr(reg::IP0));
+    instrs.into_iter().flat_map(u32::to_le_bytes).collect()
+}
+
+/// Stack space for the return address while in loop code
+const LOOP_FRAME_SIZE: i32 = 16;
+
+// Frame record plus x19-x28
+const FRAME_SIZE: i32 = 96;
+
+/// Where a branch out of the program body goes
+#[derive(Debug, Clone, Copy)]
+enum Exit {
+    /// Straight to the exit, the status is already set
+    End,
+    OutOfFuel,
+    TapeFault,
+}
+
+/// How a loop branch is encoded, from shortest to longest reach
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BranchForm {
+    /// cbz/cbnz, +-1 MiB
+    Short,
+    /// Inverted cbz/cbnz over a b, +-128 MiB
+    Far,
+    /// Inverted cbz/cbnz over an adr/mov/add/br sequence, anywhere
+    Indirect,
+}
+
+impl BranchForm {
+    fn wider(self) -> Self {
+        match self {
+            BranchForm::Short => BranchForm::Far,
+            BranchForm::Far | BranchForm::Indirect => BranchForm::Indirect,
+        }
+    }
+}
+
+/// What the cell register w23 currently holds
+#[derive(Debug, Clone, Copy)]
+struct CellCache {
+    /// w23 holds the current cell
+    loaded: bool,
+    /// w23 has changed since the cell was last stored
+    dirty: bool,
+    /// w23 may have bits set above the low byte after an add/sub
+    wide: bool,
+}
+
+/// Machine code buffer that keeps the current cell in a register where possible
+struct Emitter {
+    code: Vec<u8>,
+    cell: CellCache,
+    /// Branches to the exit stubs, patched once the stubs are emitted
+    exits: Vec<(usize, Exit)>,
+}
+
+impl Emitter {
+    fn new() -> Self {
+        Self {
+            code: Vec::new(),
+            cell: CellCache { loaded: false, dirty: false, wide: false },
+            exits: Vec::new(),
+        }
+    }
+
+    fn emit(&mut self, instr: u32) {
+        self.code.extend(&instr.to_le_bytes());
+    }
+
+    fn offset(&self) -> usize {
+        self.code.len()
+    }
+
+    fn patch(&mut self, at: usize, instr: u32) {
+        self.code[at..at + 4].copy_from_slice(&instr.to_le_bytes());
+    }
+
+    /// Emit a b to one of the exits, patched at the end
+    fn exit(&mut self, exit: Exit) {
+        self.exits.push((self.offset(), exit));
+        self.emit(aarch64::b(0));
+    }
+
+    /// Leave with the status set by an IO function if it failed
+    fn exit_on_io_error(&mut self) {
+        // ldr x16, [x20, #STATUS]
+        self.emit(aarch64::ldr_x(reg::IP0, reg::RT, header::STATUS));
+        // cbz x16, <continue>, over the store of a pending cell and the b
+        let skip = if self.cell.dirty { 12 } else { 8 };
+        self.emit(aarch64::cbz_x(reg::IP0, skip));
+        if self.cell.dirty {
+            // strb w23, [x19], without clearing dirty as this is only one path
+            self.emit(aarch64::strb(reg::CELL, reg::TAPE, 0));
+        }
+        self.exit(Exit::End);
+    }
+
+    /// Branch over a tape fault exit with the given condition branch. With an op,
+    /// x17 tells the exit stub which op faulted.
+    fn tape_fault_unless(&mut self, skip: fn(i64) -> u32, op: Option<usize>) {
+        match op {
+            Some(op) => {
+                // b.cond <ok>; mov x17, #op; b <tape fault>
+                self.emit(skip(4 * 6));
+                for instr in aarch64::mov_x_imm64(reg::IP1, op as u64) {
+                    self.emit(instr);
+                }
+            }
+            // b.cond <ok>; b <tape fault>
+            None => self.emit(skip(8)),
+        }
+        self.exit(Exit::TapeFault);
+    }
+
+    /// Move x19 by the stride until it points at a zero cell, or leave with a tape fault
+    /// once it runs off the tape. Strides up to 8 that divide 16 check 16 cells at a
+    /// time with NEON while there are 16 cells left, cell by cell otherwise.
+    fn scan(&mut self, stride: u8, right: bool, op: Option<usize>) {
+        let mut done = vec![];
+        if matches!(stride, 1 | 2 | 4 | 8) {
+            if stride > 1 {
+                // v1 selects the cells the scan visits, counting from the end it starts at
+                self.emit(aarch64::movi_lane_mask(1, stride, !right));
+            }
+            let top = self.offset();
+            // x16 = start of the 16 cells, b.hi/b.lo <scalar> if they don't fit on the tape
+            if right {
+                self.emit(aarch64::add_x(reg::IP0, reg::TAPE, 16)); // add x16, x19, #16
+                self.emit(aarch64::cmp_x(reg::IP0, reg::TAPE_END)); // cmp x16, x28
+            } else {
+                self.emit(aarch64::sub_x(reg::IP0, reg::TAPE, 15)); // sub x16, x19, #15
+                self.emit(aarch64::cmp_x(reg::IP0, reg::TAPE_START)); // cmp x16, x26
+            }
+            let to_scalar = self.offset();
+            self.emit(0);
+            let start = if right { reg::TAPE } else { reg::IP0 };
+            self.emit(aarch64::ld1_16b(0, start)); // ld1 {v0.16b}, [x19|x16]
+            self.emit(aarch64::cmeq_zero_16b(0, 0)); // cmeq v0.16b, v0.16b, #0
+            if stride > 1 {
+                self.emit(aarch64::and_16b(0, 0, 1)); // and v0.16b, v0.16b, v1.16b
+            }
+            // 4 bits per cell in x17
+            self.emit(aarch64::shrn_8b_4(0, 0)); // shrn v0.8b, v0.8h, #4
+            self.emit(aarch64::fmov_x_d(reg::IP1, 0)); // fmov x17, d0
+            // cbnz x17, <found>, over the move and the b
+            self.emit(aarch64::cbnz_x(reg::IP1, 12));
+            if right {
+                self.emit(aarch64::add_x(reg::TAPE, reg::TAPE, 16)); // add x19, x19, #16
+            } else {
+                self.emit(aarch64::sub_x(reg::TAPE, reg::TAPE, 16)); // sub x19, x19, #16
+            }
+            let back = top as i64 - self.offset() as i64;
+            self.emit(aarch64::b(back));
+            // found: move to the first zero cell in scan order
+            if right {
+                self.emit(aarch64::rbit_x(reg::IP1, reg::IP1)); // rbit x17, x17
+                self.emit(aarch64::clz_x(reg::IP1, reg::IP1)); // clz x17, x17
+                self.emit(aarch64::add_x_lsr(reg::TAPE, reg::TAPE, reg::IP1, 2)); // add x19, x19, x17, lsr #2
+            } else {
+                self.emit(aarch64::clz_x(reg::IP1, reg::IP1)); // clz x17, x17
+                self.emit(aarch64::sub_x_lsr(reg::TAPE, reg::TAPE, reg::IP1, 2)); // sub x19, x19, x17, lsr #2
+            }
+            done.push(self.offset());
+            self.emit(0);
+            let offset = (self.offset() - to_scalar) as i64;
+            self.patch(to_scalar, if right { aarch64::b_hi(offset) } else { aarch64::b_lo(offset) });
+        }
+
+        // scalar: cmp x19, x28|x26; b.lo|b.hs <ok>; b <tape fault>
+        let top = self.offset();
+        if right {
+            self.emit(aarch64::cmp_x(reg::TAPE, reg::TAPE_END));
+            self.tape_fault_unless(aarch64::b_lo, op);
+        } else {
+            self.emit(aarch64::cmp_x(reg::TAPE, reg::TAPE_START));
+            self.tape_fault_unless(aarch64::b_hs, op);
+        }
+        self.emit(aarch64::ldrb(reg::IP0, reg::TAPE, 0)); // ldrb w16, [x19]
+        // cbz w16, <done>, over the move and the b
+        self.emit(aarch64::cbz_w(reg::IP0, 12));
+        if right {
+            self.emit(aarch64::add_x(reg::TAPE, reg::TAPE, stride as u32)); // add x19, x19, #stride
+        } else {
+            self.emit(aarch64::sub_x(reg::TAPE, reg::TAPE, stride as u32)); // sub x19, x19, #stride
+        }
+        let back = top as i64 - self.offset() as i64;
+        self.emit(aarch64::b(back));
+
+        let end = self.offset();
+        for at in done {
+            self.patch(at, aarch64::b((end - at) as i64));
+        }
+    }
+
+    /// Take the given number of steps from the fuel in x27, setting the flags
+    fn charge_fuel(&mut self, mut steps: u32) {
+        while steps > 0xFFF {
+            // sub x27, x27, #0xfff
+            self.emit(aarch64::sub_x(reg::FUEL, reg::FUEL, 0xFFF));
+            steps -= 0xFFF;
+        }
+        // subs x27, x27, #steps
+        self.emit(aarch64::subs_x(reg::FUEL, reg::FUEL, steps));
+    }
+
+    /// Emit a branch on w23 being zero (or non-zero) to target, which is patched later
+    /// for forward branches. Returns false if the target is out of range for the form.
+    fn branch(&mut self, form: BranchForm, if_zero: bool, target: usize) -> bool {
+        let at = self.offset();
+        let (instrs, in_range) = branch_instrs(form, if_zero, at, target);
+        for instr in instrs {
+            self.emit(instr);
+        }
+        in_range
+    }
+
+    /// Point the branch emitted at the given offset to target
+    fn patch_branch(&mut self, form: BranchForm, if_zero: bool, at: usize, target: usize) -> bool {
+        let (instrs, in_range) = branch_instrs(form, if_zero, at, target);
+        for (i, instr) in instrs.into_iter().enumerate() {
+            let pos = at + i * 4;
+            self.code[pos..pos + 4].copy_from_slice(&instr.to_le_bytes());
+        }
+        in_range
+    }
+
+    /// Make sure w23 holds the current cell
+    fn load_cell(&mut self) {
+        if !self.cell.loaded {
+            // ldrb w23, [x19]
+            self.emit(aarch64::ldrb(reg::CELL, reg::TAPE, 0));
+            self.cell = CellCache { loaded: true, dirty: false, wide: false };
+        }
+    }
+
+    /// Write w23 back to the tape if it has changed
+    fn spill_cell(&mut self) {
+        if self.cell.dirty {
+            // strb w23, [x19]
+            self.emit(aarch64::strb(reg::CELL, reg::TAPE, 0));
+            self.cell.dirty = false;
+        }
+    }
+
+    /// Hand the buffered output to the runtime, which resets the cursor
+    fn flush_output(&mut self) {
+        // mov x0, x20 via ADD #0
+        self.emit(aarch64::add_x(0, reg::RT, 0));
+        // mov x1, x24 via ADD #0
+        self.emit(aarch64::add_x(1, reg::OUT, 0));
+        // blr x21
+        self.emit(aarch64::blr(reg::WRITE_FN));
+        // mov x24, x0 via ADD #0
+        self.emit(aarch64::add_x(reg::OUT, 0, 0));
+    }
+
+    /// Bring w23 and the tape in sync with w23 holding exactly the cell value,
+    /// this is the state at every loop boundary and call
+    fn sync_cell(&mut self) {
+        self.load_cell();
+        if self.cell.wide {
+            // and w23, w23, #0xff
+            self.emit(aarch64::uxtb_w(reg::CELL, reg::CELL));
+            self.cell.wide = false;
+        }
+        self.spill_cell();
+    }
+}
+
+/// Instructions for a branch at the given offset on w23 being zero (or non-zero),
+/// and whether the target is in range for the form
+fn branch_instrs(form: BranchForm, if_zero: bool, at: usize, target: usize) -> (Vec<u32>, bool) {
+    let cond = if if_zero { aarch64::cbz_w } else { aarch64::cbnz_w };
+    let inverted = if if_zero { aarch64::cbnz_w } else { aarch64::cbz_w };
+    match form {
+        BranchForm::Short => {
+            let offset = target as i64 - at as i64;
+            (vec![cond(reg::CELL, offset)], aarch64::branch_in_range(offset, 19))
+        }
+        BranchForm::Far => {
+            // cbnz w23, #8; b <target>
+            let offset = target as i64 - (at as i64 + 4);
+            (vec![inverted(reg::CELL, 8), aarch64::b(offset)], aarch64::branch_in_range(offset, 26))
+        }
+        BranchForm::Indirect => {
+            // cbnz w23, #32; adr x16, #0; mov x17, #offset; add x16, x16, x17; br x16
+            let offset = (target as i64 - (at as i64 + 4)) as u64;
+            let instrs = vec![
+                inverted(reg::CELL, 32),
+                aarch64::adr_here(reg::IP0),
+                aarch64::movz_x(reg::IP1, offset as u16, 0),
+                aarch64::movk_x(reg::IP1, (offset >> 16) as u16, 1),
+                aarch64::movk_x(reg::IP1, (offset >> 32) as u16, 2),
+                aarch64::movk_x(reg::IP1, (offset >> 48) as u16, 3),
+                aarch64::add_x_reg(reg::IP0, reg::IP0, reg::IP1),
+                aarch64::br(reg::IP0),
+            ];
+            (instrs, true)
+        }
+    }
+}
@@ -1,12 +1,29 @@
 // Re-export modules for use in benchmarks and tests
+pub(crate) mod aarch64;
+pub mod asm;
+pub mod bytecode;
+pub mod cache;
 pub mod compiler;
+#[cfg(feature = "cranelift")]
+pub mod cranelift;
+pub mod debugger;
+pub mod debuginfo;
+pub mod emulator;
 pub mod io;
 pub mod vm;
 pub mod jit;
+pub mod object;
 pub mod runtime;
+pub mod repl;
+pub mod replay;
+pub mod scan;
+pub mod threaded;
+pub mod tiered;
+pub mod terminal;
 
 // Re-export main components if needed
 pub use crate::compiler::*;
+pub use crate::debugger::*;
 pub use crate::vm::*;
 pub use crate::io::*;
 pub use crate::jit::*;
\ No newline at end of file
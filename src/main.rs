@@ -1,8 +1,24 @@
-use std::{fs, path::Path};
+use std::fs::File;
+use std::io::{self, BufReader, Cursor, Read, Write};
+use std::process;
+use std::fs;
+use std::path::{Path, PathBuf};
 
+use brainv::asm::{self, Target};
+use brainv::bytecode::Bytecode;
+use brainv::cache::{CacheKey, CodeCache};
+#[cfg(feature = "cranelift")]
+use brainv::cranelift::CraneliftJIT;
 use brainv::jit::JIT;
-use brainv::runtime::Runtime;
-use clap::Parser;
+use brainv::object::{self, Object};
+use brainv::replay::{RecordingIO, ReplayIO};
+use brainv::runtime::{self, Registration, Runtime};
+use brainv::repl::Repl;
+use brainv::terminal::RawTerminal;
+use brainv::threaded::Threaded;
+use brainv::tiered::Tiered;
+use brainv::vm::{Op, Vm};
+use clap::{Args, Parser, Subcommand};
 use clap::{ValueEnum, command};
 
 use brainv::compiler::*;
@@ -10,11 +26,190 @@ use brainv::io::*;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true)]
 struct Cli {
-    filename: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    run: RunArgs,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run a brainf**k program, the same as leaving out the subcommand
+    Run(RunArgs),
+    /// Compile a program to bytecode that runs without parsing the source again
+    Compile {
+        filename: String,
+
+        /// Where to write the bytecode, the source file name with a .bfc extension by default
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// Build a program into a file for the system toolchain
+    Build {
+        filename: String,
+
+        /// What to build
+        #[arg(long, value_enum, default_value_t = Emit::Obj)]
+        emit: Emit,
+
+        /// Where to write it, the source file name with the extension for --emit by default.
+        /// Objects get a C header next to them, with a .h extension.
+        #[arg(short, long)]
+        output: Option<String>,
+
+        /// Name of the function running the program
+        #[arg(long, default_value = object::DEFAULT_SYMBOL)]
+        symbol: String,
+    },
+    /// Write a program as GNU assembler source for Linux, with a main that runs it
+    EmitAsm {
+        filename: String,
+
+        /// Instruction set to write, the host's by default
+        #[arg(long, value_enum)]
+        target: Option<AsmTarget>,
+
+        /// Where to write the assembly, stdout by default
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// Manage the cache of JIT code written by --cache
+    Cache {
+        #[command(subcommand)]
+        action: CacheAction,
+
+        /// The cache directory, $BRAINV_CACHE_DIR or brainv in the user's cache directory by default
+        #[arg(long)]
+        dir: Option<String>,
+    },
+    /// Run brainf**k line by line against a persistent tape
+    Repl {
+        /// How line endings are translated on input and output
+        #[arg(long, value_enum, default_value_t = NewlineMode::StripCr)]
+        newline: NewlineMode,
+    },
+}
+
+#[derive(Subcommand)]
+enum CacheAction {
+    /// Delete every cached program
+    Clear,
+    /// Show how many programs are cached and how much space they take
+    Stats,
+}
+
+#[derive(Args)]
+struct RunArgs {
+    #[arg(required = true)]
+    filename: Option<String>,
+
+    #[arg(short, long, value_enum, default_value_t = Backend::Jit)]
+    backend: Backend,
 
     #[arg(short, long, value_enum, default_value_t = IOMode::Batched)]
     io: IOMode,
+
+    /// When batched output is written to stdout or the --output file
+    #[arg(long, value_enum, default_value_t = FlushMode::BeforeRead)]
+    flush: FlushMode,
+
+    /// How line endings are translated on input and output
+    #[arg(long, value_enum, default_value_t = NewlineMode::StripCr)]
+    newline: NewlineMode,
+
+    /// Read the program input from a file instead of stdin
+    #[arg(long, group = "input_source")]
+    input: Option<String>,
+
+    /// Use the given text as program input instead of stdin
+    #[arg(long, group = "input_source")]
+    input_string: Option<String>,
+
+    /// Treat everything after the first '!' in the source as program input
+    #[arg(long, group = "input_source")]
+    bang_input: bool,
+
+    /// Write the program output to a file instead of stdout
+    #[arg(short, long)]
+    output: Option<String>,
+
+    /// Hand every key press to the program immediately, without echo
+    #[arg(long)]
+    raw_tty: bool,
+
+    /// Print cells as numbers and read whitespace separated numbers into cells
+    #[arg(long, value_enum)]
+    numbers: Option<NumberMode>,
+
+    /// Written after every number printed with --numbers
+    #[arg(long, default_value = "\n", requires = "numbers")]
+    separator: String,
+
+    /// Log every byte read and written to a file
+    #[arg(long, conflicts_with = "replay")]
+    record: Option<String>,
+
+    /// Take the input from a log written by --record and check that the output matches it
+    #[arg(long, conflicts_with = "input_source")]
+    replay: Option<String>,
+
+    /// Write symbols for the JIT code to /tmp/perf-<pid>.map for perf, named after source offsets
+    #[arg(long)]
+    perf_map: bool,
+
+    /// Register the JIT code with gdb through its JIT interface
+    #[arg(long)]
+    gdb_jit: bool,
+
+    /// Reuse the code the jit backend generated for this program on an earlier run
+    #[arg(long)]
+    cache: bool,
+
+    /// The cache directory for --cache, $BRAINV_CACHE_DIR or brainv in the user's cache directory by default
+    #[arg(long, requires = "cache")]
+    cache_dir: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum Backend {
+    /// AArch64 machine code
+    Jit,
+    /// AArch64 machine code, compiling each top-level loop when it is first entered
+    LazyJit,
+    /// Portable interpreter
+    Vm,
+    /// Portable interpreter dispatching through pre-decoded handlers, for hosts without executable memory
+    Threaded,
+    /// Interpreter that compiles hot loops to AArch64 machine code, only interpreting on other hosts
+    Tiered,
+    /// Machine code for the host, generated through Cranelift
+    #[cfg(feature = "cranelift")]
+    Cranelift,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum Emit {
+    /// ELF relocatable object with AArch64 code, called like JIT code
+    Obj,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum AsmTarget {
+    #[value(name = "x86_64")]
+    X86_64,
+    Aarch64,
+}
+
+impl From<AsmTarget> for Target {
+    fn from(target: AsmTarget) -> Self {
+        match target {
+            AsmTarget::X86_64 => Target::X86_64,
+            AsmTarget::Aarch64 => Target::Aarch64,
+        }
+    }
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
@@ -25,33 +220,335 @@ enum IOMode {
     OnePrint,
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum NumberMode {
+    Decimal,
+    Hex,
+}
+
+impl From<NumberMode> for NumberFormat {
+    fn from(mode: NumberMode) -> Self {
+        match mode {
+            NumberMode::Decimal => NumberFormat::Decimal,
+            NumberMode::Hex => NumberFormat::Hex,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum FlushMode {
+    Full,
+    Line,
+    Unbuffered,
+    BeforeRead,
+}
+
+impl From<FlushMode> for FlushPolicy {
+    fn from(mode: FlushMode) -> Self {
+        match mode {
+            FlushMode::Full => FlushPolicy::Full,
+            FlushMode::Line => FlushPolicy::Line,
+            FlushMode::Unbuffered => FlushPolicy::Unbuffered,
+            FlushMode::BeforeRead => FlushPolicy::BeforeRead,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum NewlineMode {
+    Raw,
+    StripCr,
+    #[value(name = "crlf-to-lf")]
+    CrLfToLf,
+    #[value(name = "lf-to-crlf")]
+    LfToCrLf,
+    #[value(name = "enter-sends-10")]
+    EnterSends10,
+}
+
+impl From<NewlineMode> for Newline {
+    fn from(mode: NewlineMode) -> Self {
+        match mode {
+            NewlineMode::Raw => Newline::Raw,
+            NewlineMode::StripCr => Newline::StripCr,
+            NewlineMode::CrLfToLf => Newline::CrLfToLf,
+            NewlineMode::LfToCrLf => Newline::LfToCrLf,
+            NewlineMode::EnterSends10 => Newline::EnterSends10,
+        }
+    }
+}
+
 fn main() {
     let cli = Cli::parse();
 
-    let program_path = Path::new(&cli.filename);
+    match cli.command {
+        Some(Command::Run(args)) => exit_on_error(run(args)),
+        Some(Command::Compile { filename, output }) => compile(&filename, output),
+        Some(Command::Build { filename, emit, output, symbol }) => build(&filename, emit, output, &symbol),
+        Some(Command::EmitAsm { filename, target, output }) => emit_asm(&filename, target, output),
+        Some(Command::Cache { action, dir }) => manage_cache(action, dir),
+        Some(Command::Repl { newline }) => {
+            // Output has to show up before the tape is printed, so don't batch it
+            let mut repl = Repl::new(NewlineIO::new(SimpleIO::new(), newline.into()));
+            repl.run();
+        }
+        None => exit_on_error(run(cli.run)),
+    }
+}
+
+/// Report an error from running a program, once run() has cleaned up after it
+fn exit_on_error(result: Result<(), String>) {
+    if let Err(err) = result {
+        eprintln!("{err}");
+        process::exit(1);
+    }
+}
+
+fn run(args: RunArgs) -> Result<(), String> {
+    let filename = args.filename.as_ref().expect("clap requires a filename");
+    if matches!(args.backend, Backend::Jit | Backend::LazyJit) && !runtime::JIT_SUPPORTED {
+        let name = args.backend.to_possible_value().expect("no backend is skipped").get_name().to_string();
+        return Err(format!("the {name} backend requires aarch64, use --backend vm or --backend threaded"));
+    }
+    if args.cache && args.backend != Backend::Jit {
+        return Err("--cache only applies to the jit backend".to_string());
+    }
+    let program_path = Path::new(filename);
+
+    let bytes = fs::read(program_path).expect("Failed to read the file");
+    let (mut program_text, bytecode) = if Bytecode::is_bytecode(&bytes) {
+        let bytecode = Bytecode::from_bytes(&bytes).map_err(|err| format!("{filename}: {err}"))?;
+        if args.bang_input {
+            return Err("--bang-input needs the source, bytecode files don't keep the input after the '!'".to_string());
+        }
+        (String::new(), Some((bytecode, bytes)))
+    } else {
+        (String::from_utf8(bytes).expect("The file is not valid UTF-8"), None)
+    };
+
+    let input: Option<Box<dyn Read>> = if let Some(path) = &args.input {
+        Some(Box::new(File::open(path).expect("Failed to open the input file")))
+    } else if let Some(text) = &args.input_string {
+        Some(Box::new(Cursor::new(text.clone().into_bytes())))
+    } else if args.bang_input {
+        let input = match program_text.find('!') {
+            Some(index) => program_text.split_off(index)[1..].to_string(),
+            None => String::new(),
+        };
+        Some(Box::new(Cursor::new(input.into_bytes())))
+    } else {
+        None
+    };
+    let output: Option<Box<dyn Write>> = args.output.as_ref().map(|path| {
+        Box::new(File::create(path).expect("Failed to create the output file")) as Box<dyn Write>
+    });
+
+    let program = match bytecode {
+        Some((Bytecode { code, offsets, .. }, bytes)) => Program { code, offsets, origin: bytes },
+        None => {
+            let (code, offsets) = Compiler::new(&program_text).compile_with_offsets();
+            Program { code, offsets, origin: program_text.into_bytes() }
+        }
+    };
+
+    let flush = args.flush.into();
+    let newline = args.newline.into();
 
-    let io: Box<dyn IO> = match cli.io {
-        IOMode::Simple => Box::new(SimpleIO::new()),
-        IOMode::Batched => Box::new(BatchedIO::new(200)),
-        IOMode::OnePrint => Box::new(BatchedIO::new(100000)),
+    // Restores the terminal when dropped at the end of this function
+    let _raw_terminal = if args.raw_tty {
+        RawTerminal::enable().inspect_err(|err| eprintln!("{err}, ignoring --raw-tty")).ok()
+    } else {
+        None
     };
 
-    let program_text = fs::read_to_string(program_path).expect("Failed to read the file");
+    if input.is_some() || output.is_some() {
+        // Both ends are buffered, so boxing them costs a virtual call per chunk, not per byte
+        let input = input.unwrap_or_else(|| Box::new(io::stdin()));
+        let output = output.unwrap_or_else(|| Box::new(io::stdout()));
+        return match args.io {
+            IOMode::Simple => {
+                let io = ReadWriteIO::with_options(input, output, 0, EofPolicy::Panic);
+                run_numeric(NewlineIO::new(io, newline), &args, program)
+            }
+            IOMode::Batched => run_numeric(NewlineIO::new(BatchedIO::with_streams(input, output, 200, flush), newline), &args, program),
+            IOMode::OnePrint => run_numeric(NewlineIO::new(BatchedIO::with_streams(input, output, 100000, flush), newline), &args, program),
+        };
+    }
+
+    // Dispatch once here so the backends are monomorphized for each IO
+    match args.io {
+        IOMode::Simple => run_numeric(NewlineIO::new(SimpleIO::new(), newline), &args, program),
+        IOMode::Batched => run_numeric(NewlineIO::new(BatchedIO::with_policy(200, flush), newline), &args, program),
+        IOMode::OnePrint => run_numeric(NewlineIO::new(BatchedIO::with_policy(100000, flush), newline), &args, program),
+    }
+}
+
+fn compile(filename: &str, output: Option<String>) {
+    let program_text = fs::read_to_string(filename).expect("Failed to read the file");
+    let (code, offsets) = Compiler::new(&program_text).compile_with_offsets();
+    let output = output.unwrap_or_else(|| Path::new(filename).with_extension("bfc").to_string_lossy().into_owned());
+    let bytecode = Bytecode::new(code, offsets).unwrap_or_else(|err| {
+        eprintln!("{filename}: {err}");
+        process::exit(1);
+    });
+    fs::write(&output, bytecode.to_bytes()).expect("Failed to write the bytecode file");
+}
+
+fn build(filename: &str, emit: Emit, output: Option<String>, symbol: &str) {
+    let program_text = fs::read_to_string(filename).expect("Failed to read the file");
+    let (code, offsets) = Compiler::new(&program_text).compile_with_offsets();
+    match emit {
+        Emit::Obj => {
+            let mut jit = JIT::new(code);
+            jit.set_source_offsets(offsets);
+            let built = Object::new(&jit, symbol).unwrap_or_else(|err| {
+                eprintln!("{filename}: {err}");
+                process::exit(1);
+            });
+            let output = output.map(PathBuf::from).unwrap_or_else(|| Path::new(filename).with_extension("o"));
+            fs::write(&output, built.object).expect("Failed to write the object file");
+            fs::write(output.with_extension("h"), built.header).expect("Failed to write the header");
+        }
+    }
+}
+
+fn emit_asm(filename: &str, target: Option<AsmTarget>, output: Option<String>) {
+    let target = target.map(Target::from).or_else(Target::host).unwrap_or_else(|| {
+        eprintln!("No assembly for {}, pass a --target", std::env::consts::ARCH);
+        process::exit(1);
+    });
+    let program_text = fs::read_to_string(filename).expect("Failed to read the file");
+    let (code, offsets) = Compiler::new(&program_text).compile_with_offsets();
+    let assembly = asm::emit(&program_text, &code, &offsets, target);
+    match output {
+        Some(path) => fs::write(path, assembly).expect("Failed to write the assembly"),
+        None => io::stdout().write_all(assembly.as_bytes()).expect("Failed to write the assembly"),
+    }
+}
+
+/// The cache in the given directory or the default one
+fn code_cache(dir: Option<&String>) -> CodeCache {
+    let dir = dir.map(PathBuf::from).or_else(CodeCache::default_dir).unwrap_or_else(|| {
+        eprintln!("No cache directory, set $BRAINV_CACHE_DIR or pass one");
+        process::exit(1);
+    });
+    CodeCache::new(dir)
+}
+
+fn manage_cache(action: CacheAction, dir: Option<String>) {
+    let cache = code_cache(dir.as_ref());
+    match action {
+        CacheAction::Clear => {
+            let removed = cache.clear().expect("Failed to clear the cache");
+            println!("Removed {removed} cached programs from {}", cache.dir().display());
+        }
+        CacheAction::Stats => {
+            let stats = cache.stats().expect("Failed to read the cache");
+            println!("Directory: {}", cache.dir().display());
+            println!("Programs:  {}", stats.entries);
+            println!("Size:      {} bytes", stats.bytes);
+            println!("Stale:     {}", stats.stale);
+        }
+    }
+}
 
-    let compiler = Compiler::new(&program_text);
-    let code = compiler.compile();
-    let mut jit = JIT::new(code);
-    let compiled_code = jit.compile();
+/// The compiled program, with where each op starts in the source for naming JIT code
+struct Program {
+    code: Vec<Op>,
+    offsets: Vec<usize>,
+    /// What the program was compiled from, source or bytecode, to key the code cache
+    origin: Vec<u8>,
+}
 
-    if let Ok(code_vec) = compiled_code {
-        let mut runtime = Runtime::new(io, code_vec);
-        runtime.run();
-        //let tape = runtime.tape();
-        //println!("Tape: {:?}", tape);
+/// Wrap the IO for --numbers and run the program
+fn run_numeric<I: IO>(io: I, args: &RunArgs, program: Program) -> Result<(), String> {
+    match args.numbers {
+        Some(mode) => run_logged(NumericIO::new(io, mode.into(), args.separator.as_bytes()), args, program),
+        None => run_logged(io, args, program),
     }
+}
 
-    
+/// Wrap the IO for --record or --replay and run the program
+fn run_logged<I: IO>(io: I, args: &RunArgs, program: Program) -> Result<(), String> {
+    if let Some(path) = &args.record {
+        let log = File::create(path).expect("Failed to create the record file");
+        execute(RecordingIO::new(io, log), args, program)?;
+    } else if let Some(path) = &args.replay {
+        let log = File::open(path).expect("Failed to open the replay file");
+        let replay = ReplayIO::new(io, BufReader::new(log)).unwrap_or_else(|err| panic!("Failed to read the replay file: {err}"));
+        let mut replay = execute(replay, args, program)?;
+        replay.flush();
+        replay.finish()?;
+    } else {
+        execute(io, args, program)?;
+    }
+    Ok(())
+}
 
-    //vm.run();
-    //vm.flush_io();
+/// Run the program, errors are returned after the output so far was flushed
+fn execute<I: IO>(io: I, args: &RunArgs, program: Program) -> Result<I, String> {
+    let Program { code, offsets, origin } = program;
+    match args.backend {
+        Backend::Jit | Backend::LazyJit => {
+            let mut jit = JIT::new(code);
+            jit.set_source_offsets(offsets);
+            let mut runtime = if args.backend == Backend::LazyJit {
+                Runtime::lazy(io, jit).expect("Failed to compile the program")
+            } else {
+                if args.cache {
+                    let cache = code_cache(args.cache_dir.as_ref());
+                    let key = CacheKey::new(&origin, "jit", "");
+                    Runtime::cached(io, &jit, &cache, &key).expect("Failed to compile the program")
+                } else {
+                    let (machine_code, symbols) = jit.compile_with_symbols().expect("Failed to compile the program");
+                    let mut runtime = Runtime::new(io, machine_code);
+                    runtime.set_symbols(symbols);
+                    runtime
+                }
+            };
+            runtime.set_registration(Registration { perf_map: args.perf_map, gdb: args.gdb_jit });
+            let result = runtime.run();
+            //let tape = runtime.tape();
+            //println!("Tape: {:?}", tape);
+            let mut io = runtime.into_io();
+            if let Err(err) = result {
+                io.flush();
+                return Err(err.to_string());
+            }
+            Ok(io)
+        }
+        Backend::Vm => {
+            let mut vm = Vm::new(io, code);
+            if args.record.is_some() || args.replay.is_some() {
+                vm.run_timed();
+            } else {
+                vm.run();
+            }
+            vm.flush_io();
+            Ok(vm.into_io())
+        }
+        Backend::Threaded => {
+            let mut threaded = Threaded::new(io, code);
+            threaded.run();
+            threaded.flush_io();
+            Ok(threaded.into_io())
+        }
+        Backend::Tiered => {
+            let mut tiered = Tiered::new(io, code);
+            let result = tiered.run();
+            tiered.flush_io();
+            result.map_err(|err| err.to_string())?;
+            Ok(tiered.into_io())
+        }
+        #[cfg(feature = "cranelift")]
+        Backend::Cranelift => {
+            let code = CraneliftJIT::new(code).compile().expect("Failed to compile the program");
+            let mut io = io;
+            let mut tape = vec![0; 30000];
+            let result = code.call(&mut io, &mut tape, 0);
+            io.flush();
+            result.map_err(|err| err.to_string())?;
+            Ok(io)
+        }
+    }
 }
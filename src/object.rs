@@ -0,0 +1,128 @@
+// Object files for linking programs into C
+//
+// The JIT code for a whole program is position independent, so it can go into
+// the .text of an ELF relocatable object as it is. The entry point is a global
+// function named bf_main by default, called with exactly the arguments the
+// runtime passes to JIT code, and the generated C header describes them.
+// Symbols for the loops are kept as local ones, for debuggers and profilers,
+// so objects for several programs link together as long as their entry points
+// have different names.
+
+use crate::debuginfo::{self, EM_AARCH64, Text};
+use crate::jit::{JIT, Symbol};
+
+pub const DEFAULT_SYMBOL: &str = "bf_main";
+
+/// A program compiled for linking
+#[derive(Debug, Clone)]
+pub struct Object {
+    /// The ELF relocatable object, AArch64 code
+    pub object: Vec<u8>,
+    /// C declarations for the entry point
+    pub header: String,
+}
+
+impl Object {
+    /// Compile the program to an object with its entry point named symbol
+    pub fn new(jit: &JIT, symbol: &str) -> Result<Self, String> {
+        if !is_identifier(symbol) {
+            return Err(format!("{symbol:?} is not a valid C identifier"));
+        }
+        let (code, loops) = jit.compile_with_symbols()?;
+        let entry = Symbol { name: symbol.to_string(), offset: 0, len: code.len() };
+        let object = debuginfo::relocatable(EM_AARCH64, Text::Code(&code), &loops, &[entry]);
+        Ok(Self { object, header: c_header(symbol) })
+    }
+}
+
+/// Whether name can be declared in C, and so linked against
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Declarations shared by the headers of every program, guarded on their own
+/// so headers for several programs can be included together
+const COMMON_DECLARATIONS: &str = r#"#ifndef BRAINV_ABI_H
+#define BRAINV_ABI_H
+
+#include <stdint.h>
+
+/* Status codes returned by the programs */
+#define BF_OK 0
+#define BF_IO_ERROR 1
+#define BF_EOF 2
+#define BF_OUT_OF_FUEL 3
+#define BF_TAPE_FAULT 4
+#define BF_COMPILE_ERROR 5
+
+/*
+ * What ctx points at. It may be the start of a larger struct, so the IO
+ * functions can find their own state through the pointer they are passed.
+ *
+ * Before a call, set the output buffer, which must have room for at least one
+ * byte, and the bounds of the tape, which the tape pointer has to be within.
+ * Set the status to BF_OK. The program writes the final tape pointer, from the
+ * start of the tape, to tape_offset when it returns. The other fields are not
+ * used by programs built with brainv build.
+ */
+typedef struct bf_context {
+    uint8_t *out_start;
+    uint8_t *out_end;
+    uint8_t *tape_start;
+    uint8_t *tape_end;
+    uint64_t fuel;
+    uint64_t status;
+    int64_t tape_offset;
+    uint64_t steps;
+    void *compile_loop;
+} bf_context;
+
+/*
+ * Called with the bytes from out_start up to cursor when the output buffer is
+ * full, before every read and before the program returns, also when it returns
+ * with BF_TAPE_FAULT. Returns the cursor to carry on from, normally out_start.
+ * To stop the program, set the status in the context to BF_IO_ERROR.
+ */
+typedef uint8_t *(*bf_write_fn)(void *ctx, uint8_t *cursor);
+
+/*
+ * Called for every ',' to get the next input byte. To stop the program, set the
+ * status in the context to BF_EOF or BF_IO_ERROR, the cell is left as it was.
+ */
+typedef uint8_t (*bf_read_fn)(void *ctx);
+
+#endif
+"#;
+
+/// The C header declaring the entry point of a program
+pub fn c_header(symbol: &str) -> String {
+    let guard = format!("BRAINV_{}_H", symbol.to_ascii_uppercase());
+    format!(
+        "/* Generated by brainv {version}, do not edit */\n\
+         \n\
+         #ifndef {guard}\n\
+         #define {guard}\n\
+         \n\
+         {COMMON_DECLARATIONS}\
+         \n\
+         #ifdef __cplusplus\n\
+         extern \"C\" {{\n\
+         #endif\n\
+         \n\
+         /*\n \
+          * Run the program with the tape pointer at tape. Returns BF_OK, or the status\n \
+          * an IO function set or BF_TAPE_FAULT when the tape pointer left the tape.\n \
+          * AArch64 only.\n \
+          */\n\
+         uint64_t {symbol}(uint8_t *tape, void *ctx, bf_write_fn write_fn, bf_read_fn read_fn);\n\
+         \n\
+         #ifdef __cplusplus\n\
+         }}\n\
+         #endif\n\
+         \n\
+         #endif\n",
+        version = env!("CARGO_PKG_VERSION"),
+    )
+}
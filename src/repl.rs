@@ -0,0 +1,212 @@
+// Interactive read-eval-print loop
+//
+// Every line is compiled on its own and executed against the same Vm, so the
+// tape and the tape pointer persist between lines.
+
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::panic::{self, AssertUnwindSafe};
+
+use crate::compiler::Compiler;
+use crate::io::IO;
+use crate::vm::Vm;
+
+// Number of cells shown on each side of the tape pointer
+const WINDOW: usize = 8;
+
+const HELP: &str = "\
+:reset          clear the tape and move the pointer to cell 0
+:tape A..B      show cells A to B (exclusive), or a single cell with :tape A
+:load FILE      run a brainf**k file against the current tape
+:undo           revert the tape and pointer to before the last line
+:help           show this message
+:quit           leave the repl";
+
+struct Snapshot {
+    tape: Vec<u8>,
+    tp: usize,
+}
+
+pub struct Repl<I: IO> {
+    vm: Vm<I>,
+    history: Vec<Snapshot>,
+    // Lines of a loop that isn't closed yet
+    source: String,
+}
+
+impl<I: IO> Repl<I> {
+    pub fn new(io: I) -> Self {
+        Self {
+            vm: Vm::new(io, vec![]),
+            history: vec![],
+            source: String::new(),
+        }
+    }
+
+    pub fn run(&mut self) {
+        println!("brainv repl, type :help for a list of commands");
+
+        let stdin = io::stdin();
+        let mut stdout = io::stdout();
+        loop {
+            print!("{}", if self.source.is_empty() { "bf> " } else { "... " });
+            stdout.flush().unwrap();
+
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line).unwrap() == 0 {
+                break;
+            }
+            if !self.eval(&line, &mut stdout).unwrap() {
+                break;
+            }
+        }
+    }
+
+    /// Handle a line of input, writing what the repl has to say to out. Returns
+    /// false if the repl should exit.
+    pub fn eval(&mut self, line: &str, out: &mut impl Write) -> io::Result<bool> {
+        let line = line.trim_end();
+        if self.source.is_empty() && line.starts_with(':') {
+            return self.command(line, out);
+        }
+
+        self.source.push_str(line);
+        self.source.push('\n');
+        match bracket_depth(&self.source) {
+            // Keep reading lines until every loop is closed
+            Some(depth) if depth > 0 => return Ok(true),
+            Some(_) => {
+                let source = std::mem::take(&mut self.source);
+                self.execute(&source);
+                self.print_window(out)?;
+            }
+            None => writeln!(out, "error: unmatched ']'")?,
+        }
+        self.source.clear();
+        Ok(true)
+    }
+
+    /// Current tape pointer
+    pub fn tp(&self) -> usize {
+        self.vm.tp()
+    }
+
+    pub fn tape(&self) -> &[u8] {
+        self.vm.tape()
+    }
+
+    /// Handle a meta command, returns false if the repl should exit
+    fn command(&mut self, line: &str, out: &mut impl Write) -> io::Result<bool> {
+        let (name, arg) = match line.split_once(char::is_whitespace) {
+            Some((name, arg)) => (name, arg.trim()),
+            None => (line, ""),
+        };
+
+        match name {
+            ":reset" => {
+                self.vm.reset();
+                self.history.clear();
+                self.print_window(out)?;
+            }
+            ":tape" => match parse_range(arg) {
+                Some((start, end)) if start >= end => writeln!(out, "error: the range {arg} is empty")?,
+                Some((start, end)) => self.print_cells(start, end, out)?,
+                None => writeln!(out, "error: expected a range like 0..32")?,
+            },
+            ":load" => match fs::read_to_string(arg) {
+                Ok(program) => match bracket_depth(&program) {
+                    Some(0) => {
+                        self.execute(&program);
+                        self.print_window(out)?;
+                    }
+                    _ => writeln!(out, "error: unbalanced brackets in {arg}")?,
+                },
+                Err(err) => writeln!(out, "error: failed to read {arg}: {err}")?,
+            },
+            ":undo" => match self.history.pop() {
+                Some(snapshot) => {
+                    self.restore(snapshot);
+                    self.print_window(out)?;
+                }
+                None => writeln!(out, "nothing to undo")?,
+            },
+            ":help" => writeln!(out, "{HELP}")?,
+            ":quit" | ":q" => return Ok(false),
+            _ => writeln!(out, "unknown command {name}, type :help for a list of commands")?,
+        }
+        Ok(true)
+    }
+
+    fn execute(&mut self, source: &str) {
+        let snapshot = Snapshot {
+            tape: self.vm.tape.clone(),
+            tp: self.vm.tp,
+        };
+
+        self.vm.load(Compiler::new(source).compile());
+        let result = panic::catch_unwind(AssertUnwindSafe(|| self.vm.run()));
+        self.vm.flush_io();
+
+        // The panic message has already been printed, roll back the partial line
+        if result.is_err() {
+            self.restore(snapshot);
+            return;
+        }
+        self.history.push(snapshot);
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) {
+        self.vm.tape = snapshot.tape;
+        self.vm.tp = snapshot.tp;
+    }
+
+    fn print_window(&self, out: &mut impl Write) -> io::Result<()> {
+        let tp = self.vm.tp();
+        let start = tp.saturating_sub(WINDOW);
+        self.print_cells(start, tp + WINDOW + 1, out)
+    }
+
+    fn print_cells(&self, start: usize, end: usize, out: &mut impl Write) -> io::Result<()> {
+        let tape = self.vm.tape();
+        let end = end.min(tape.len());
+        if start >= end {
+            return writeln!(out, "error: tape has {} cells", tape.len());
+        }
+
+        let mut indices = String::new();
+        let mut values = String::new();
+        let mut pointer = String::new();
+        for (i, value) in tape.iter().enumerate().take(end).skip(start) {
+            indices.push_str(&format!("{i:>5}"));
+            values.push_str(&format!("{value:>5}"));
+            pointer.push_str(if i == self.vm.tp() { "    ^" } else { "     " });
+        }
+        writeln!(out)?;
+        writeln!(out, "{indices}")?;
+        writeln!(out, "{values}")?;
+        writeln!(out, "{}", pointer.trim_end())
+    }
+}
+
+/// Loop nesting depth at the end of the source, or None if a ']' has no matching '['
+fn bracket_depth(source: &str) -> Option<usize> {
+    let mut depth = 0usize;
+    for c in source.bytes() {
+        match c {
+            b'[' => depth += 1,
+            b']' => depth = depth.checked_sub(1)?,
+            _ => {}
+        }
+    }
+    Some(depth)
+}
+
+fn parse_range(arg: &str) -> Option<(usize, usize)> {
+    match arg.split_once("..") {
+        Some((start, end)) => Some((start.trim().parse().ok()?, end.trim().parse().ok()?)),
+        None => {
+            let cell: usize = arg.parse().ok()?;
+            Some((cell, cell + 1))
+        }
+    }
+}
@@ -0,0 +1,219 @@
+// Record and replay program IO
+//
+// RecordingIO writes every byte read and written to a log, ReplayIO feeds the
+// logged input back and checks that the program writes the same output. Reading
+// at the end of the input is logged as well, and replayed as the end of the
+// input at the same point. Events
+// are numbered in the order they happen. Backends that count steps, i.e. the Vm
+// through Vm::run_timed, also stamp every event with the number of steps run
+// before it, and replaying on such a backend checks the steps as well. Other
+// backends leave the steps out or ignore them, so a log recorded with one
+// backend replays on any other.
+//
+// Every event is flushed to the log right away, so the log is complete up to
+// the last byte even when the program is aborted.
+//
+// Log format, one event per line, v1 logs are the same without eof events:
+//   # brainv io log v2
+//   <event> r <byte> [<step>]
+//   <event> w <byte> [<step>]
+//   <event> eof [<step>]
+
+use std::io::{BufRead, BufWriter, Write};
+
+use crate::io::IO;
+
+const HEADER: &str = "# brainv io log v2";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    Read,
+    Write,
+    /// A read at the end of the input
+    Eof,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Event {
+    /// Position in the log, from 0
+    pub index: u64,
+    pub kind: EventKind,
+    /// Zero for Eof
+    pub byte: u8,
+    /// Steps run before the event, if the backend counted them
+    pub step: Option<u64>,
+}
+
+/// Passes everything through to another IO and logs it
+pub struct RecordingIO<I: IO, W: Write> {
+    inner: I,
+    // Buffered so every event goes out in a single write
+    log: BufWriter<W>,
+    events: u64,
+    step: Option<u64>,
+}
+
+impl<I: IO, W: Write> RecordingIO<I, W> {
+    pub fn new(inner: I, log: W) -> Self {
+        let mut log = BufWriter::new(log);
+        writeln!(log, "{HEADER}").unwrap();
+        log.flush().unwrap();
+        Self { inner, log, events: 0, step: None }
+    }
+
+    /// Log an event, given as its kind and byte, e.g. "r 97"
+    fn record(&mut self, event: &str) {
+        match self.step.take() {
+            Some(step) => writeln!(self.log, "{} {event} {step}", self.events),
+            None => writeln!(self.log, "{} {event}", self.events),
+        }
+        .unwrap();
+        self.log.flush().unwrap();
+        self.events += 1;
+    }
+}
+
+impl<I: IO, W: Write> IO for RecordingIO<I, W> {
+    fn write_byte(&mut self, c: u8) {
+        self.record(&format!("w {c}"));
+        self.inner.write_byte(c);
+    }
+
+    fn try_read_byte(&mut self) -> Option<u8> {
+        let c = self.inner.try_read_byte();
+        match c {
+            Some(c) => self.record(&format!("r {c}")),
+            None => self.record("eof"),
+        }
+        c
+    }
+
+    fn flush(&mut self) {
+        self.inner.flush();
+        self.log.flush().unwrap();
+    }
+
+    fn set_step(&mut self, step: u64) {
+        self.step = Some(step);
+        self.inner.set_step(step);
+    }
+}
+
+/// Feeds input from a log and panics as soon as the output differs from it
+///
+/// Output is still passed on to the inner IO, input is never read from it.
+pub struct ReplayIO<I: IO> {
+    inner: I,
+    events: Vec<Event>,
+    pos: usize,
+    step: Option<u64>,
+}
+
+impl<I: IO> ReplayIO<I> {
+    pub fn new(inner: I, log: impl BufRead) -> Result<Self, String> {
+        Ok(Self { inner, events: parse_log(log)?, pos: 0, step: None })
+    }
+
+    pub fn into_inner(self) -> I {
+        self.inner
+    }
+
+    /// Check that the program performed every logged event
+    pub fn finish(&self) -> Result<(), String> {
+        match self.events.get(self.pos) {
+            Some(event) => Err(format!(
+                "replay ended early, {} events left starting at event {}",
+                self.events.len() - self.pos,
+                event.index
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// The next event, which has to be of the given kind. A read also matches
+    /// an Eof, which the caller then has to return.
+    fn next_event(&mut self, kind: EventKind, byte: Option<u8>) -> Event {
+        let step = self.step.take();
+        let Some(&event) = self.events.get(self.pos) else {
+            panic!("replay diverged at event {}: the program did a {:?} after the log ended", self.pos, kind);
+        };
+        if event.kind == EventKind::Eof && kind != EventKind::Read {
+            panic!("replay diverged at event {}: the log has the end of the input but the program did a {:?}", event.index, kind);
+        }
+        if event.kind != kind && event.kind != EventKind::Eof {
+            panic!(
+                "replay diverged at event {}: the log has a {:?} of {} but the program did a {:?}",
+                event.index, event.kind, event.byte, kind
+            );
+        }
+        if let Some(byte) = byte
+            && byte != event.byte
+        {
+            panic!("replay diverged at event {}: the log has a write of {} but the program wrote {}", event.index, event.byte, byte);
+        }
+        if let (Some(logged), Some(step)) = (event.step, step)
+            && logged != step
+        {
+            panic!("replay diverged at event {}: the log has the {:?} at step {logged} but the program did it at step {step}", event.index, kind);
+        }
+        self.pos += 1;
+        event
+    }
+}
+
+impl<I: IO> IO for ReplayIO<I> {
+    fn write_byte(&mut self, c: u8) {
+        self.next_event(EventKind::Write, Some(c));
+        self.inner.write_byte(c);
+    }
+
+    fn try_read_byte(&mut self) -> Option<u8> {
+        self.inner.flush();
+        let event = self.next_event(EventKind::Read, None);
+        (event.kind == EventKind::Read).then_some(event.byte)
+    }
+
+    fn flush(&mut self) {
+        self.inner.flush();
+    }
+
+    fn set_step(&mut self, step: u64) {
+        self.step = Some(step);
+        self.inner.set_step(step);
+    }
+}
+
+pub fn parse_log(log: impl BufRead) -> Result<Vec<Event>, String> {
+    let mut events = vec![];
+    for (line_number, line) in log.lines().enumerate() {
+        let line = line.map_err(|err| err.to_string())?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let invalid = || format!("invalid event on line {}: {line}", line_number + 1);
+        let mut parts = line.split_whitespace();
+        let index: u64 = parts.next().and_then(|s| s.parse().ok()).ok_or_else(invalid)?;
+        let kind = match parts.next() {
+            Some("r") => EventKind::Read,
+            Some("w") => EventKind::Write,
+            Some("eof") => EventKind::Eof,
+            _ => return Err(invalid()),
+        };
+        let byte: u8 = match kind {
+            EventKind::Eof => 0,
+            _ => parts.next().and_then(|s| s.parse().ok()).ok_or_else(invalid)?,
+        };
+        let step = match parts.next() {
+            Some(s) => Some(s.parse::<u64>().map_err(|_| invalid())?),
+            None => None,
+        };
+        if parts.next().is_some() || index != events.len() as u64 {
+            return Err(invalid());
+        }
+
+        events.push(Event { index, kind, byte, step });
+    }
+    Ok(events)
+}
@@ -1,47 +1,423 @@
+use crate::cache::{CacheKey, CodeCache};
+use crate::debuginfo::{self, GdbRegistration};
 use crate::io::IO;
-use std::mem;
+use crate::jit::{self, JIT, Symbol, header, status};
+use std::fmt;
+use std::mem::{self, MaybeUninit};
+use std::panic::{self, AssertUnwindSafe};
 use std::ptr;
 
 #[cfg(windows)]
-use winapi::um::memoryapi::VirtualAlloc;
+use winapi::um::memoryapi::{VirtualAlloc, VirtualFree};
 #[cfg(windows)]
-use winapi::um::winnt::{MEM_COMMIT, MEM_RESERVE, PAGE_EXECUTE_READWRITE};
+use winapi::um::winnt::{MEM_COMMIT, MEM_RELEASE, MEM_RESERVE, PAGE_EXECUTE_READWRITE};
 #[cfg(windows)]
 use winapi::shared::basetsd::SIZE_T;
 #[cfg(windows)]
 use winapi::um::processthreadsapi::{GetCurrentProcess, FlushInstructionCache};
 
-/// Trampoline to write a byte via the runtime pointer
-extern "C" fn write_trampoline(rt_ptr: *mut u8, c: u8) {
-    // DEBUG: show the runtime pointer and byte
-    //eprintln!("[JIT DEBUG] write_trampoline rt_ptr={:p}, byte=0x{:02x}", rt_ptr, c);
-    let rt = rt_ptr as *mut Runtime;
-    unsafe { (*rt).io.write_byte(c); }
+/// Trampoline to hand the buffered output to the IO, returns the reset cursor
+extern "C" fn flush_trampoline<I: IO>(rt_ptr: *mut u8, cursor: *mut u8) -> *mut u8 {
+    let rt = unsafe { &mut *(rt_ptr as *mut Context<I>) };
+    let start = rt.header.out_start;
+    let bytes = unsafe { std::slice::from_raw_parts(start, cursor.offset_from(start) as usize) };
+    let result = catch_io(|| {
+        for &c in bytes {
+            rt.io.write_byte(c);
+        }
+    });
+    if let Err((status, message)) = result {
+        rt.header.status = status;
+        rt.error = Some(message);
+    }
+    start
 }
 
 /// Trampoline to read a byte via the runtime pointer
-extern "C" fn read_trampoline(rt_ptr: *mut u8) -> u8 {
+extern "C" fn read_trampoline<I: IO>(rt_ptr: *mut u8) -> u8 {
     // DEBUG: show the runtime pointer for read
     //eprintln!("[JIT DEBUG] read_trampoline rt_ptr={:p}", rt_ptr);
-    let rt = rt_ptr as *mut Runtime;
-    unsafe { (*rt).io.read_byte() }
+    let rt = unsafe { &mut *(rt_ptr as *mut Context<I>) };
+    match read_input(&mut *rt.io) {
+        Ok(c) => c,
+        Err((status, message)) => {
+            rt.header.status = status;
+            rt.error = Some(message);
+            0
+        }
+    }
+}
+
+/// Compile a loop for lazy code on first entry and patch its stub to call it from now on.
+/// Returns the loop code, or null with the status set if it failed to compile.
+extern "C" fn compile_trampoline<I: IO>(rt_ptr: *mut u8, start: u64, stub: *mut u8) -> *const u8 {
+    let rt = unsafe { &mut *(rt_ptr as *mut Context<I>) };
+    let result = match &mut rt.loops {
+        Some(loops) => loops.jit.compile_loop(start as usize),
+        None => Err("Called the loop compiler without a program".to_string()),
+    };
+    match result {
+        Ok(code) => {
+            let loops = rt.loops.as_mut().unwrap();
+            let mut loop_code = Executable::new(&code);
+            let symbol = Symbol { name: loops.jit.loop_name(start as usize), offset: 0, len: code.len() };
+            loop_code.register(&[symbol], loops.registration);
+            unsafe { patch_executable(stub, &jit::patched_stub(loop_code.ptr as u64)) };
+            let ptr = loop_code.ptr as *const u8;
+            // Kept for as long as the program, which calls it from the patched stub
+            loops.compiled.push(loop_code);
+            ptr
+        }
+        Err(message) => {
+            rt.header.status = status::COMPILE_ERROR;
+            rt.error = Some(message);
+            ptr::null()
+        }
+    }
+}
+
+/// Run an IO call, turning a panic into an IO error status and message.
+/// Unwinding through generated code would abort the process.
+pub(crate) fn catch_io<T>(f: impl FnOnce() -> T) -> Result<T, (u64, String)> {
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "IO failed".to_string());
+        (status::IO_ERROR, message)
+    })
+}
+
+/// Read a byte for generated code, the end of the input is an EOF status
+pub(crate) fn read_input<I: IO + ?Sized>(io: &mut I) -> Result<u8, (u64, String)> {
+    catch_io(|| io.try_read_byte())?.ok_or_else(|| (status::EOF, "no more input available (EOF)".to_string()))
+}
+
+/// Header the generated code reads and writes through the runtime pointer, see jit::header
+#[repr(C)]
+struct Header {
+    out_start: *mut u8,
+    out_end: *mut u8,
+    tape_start: *mut u8,
+    tape_end: *mut u8,
+    fuel: u64,
+    status: u64,
+    tape_offset: i64,
+    steps: u64,
+    compile_loop: extern "C" fn(*mut u8, u64, *mut u8) -> *const u8,
+    fault_op: u64,
+}
+
+const _: () = {
+    assert!(mem::offset_of!(Header, out_start) == header::OUT_START as usize);
+    assert!(mem::offset_of!(Header, out_end) == header::OUT_END as usize);
+    assert!(mem::offset_of!(Header, tape_start) == header::TAPE_START as usize);
+    assert!(mem::offset_of!(Header, tape_end) == header::TAPE_END as usize);
+    assert!(mem::offset_of!(Header, fuel) == header::FUEL as usize);
+    assert!(mem::offset_of!(Header, status) == header::STATUS as usize);
+    assert!(mem::offset_of!(Header, tape_offset) == header::TAPE_OFFSET as usize);
+    assert!(mem::offset_of!(Header, steps) == header::STEPS as usize);
+    assert!(mem::offset_of!(Header, compile_loop) == header::COMPILE_LOOP as usize);
+    assert!(mem::offset_of!(Header, fault_op) == header::FAULT_OP as usize);
+};
+
+/// What the runtime pointer points at during a call, the header must stay the first field
+#[repr(C)]
+struct Context<'a, I: IO> {
+    header: Header,
+    io: &'a mut I,
+    error: Option<String>,
+    loops: Option<LazyLoops<'a>>,
+}
+
+/// Loops of code from JIT::compile_lazy that have been compiled so far
+struct LazyLoops<'a> {
+    jit: &'a JIT,
+    compiled: Vec<Executable>,
+    registration: Registration,
+}
+
+/// Where the runtime announces generated code, so profilers and debuggers can
+/// attribute it to the program, none by default
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Registration {
+    /// Append the symbols to /tmp/perf-<pid>.map for perf
+    pub perf_map: bool,
+    /// Register the code through the GDB JIT interface
+    pub gdb: bool,
+}
+
+/// Whether this host runs the code generated by the JIT, which is AArch64 only
+pub const JIT_SUPPORTED: bool = cfg!(target_arch = "aarch64");
+
+/// Written to the steps in the header before a run, metered code overwrites it
+pub(crate) const NOT_METERED: u64 = u64::MAX;
+
+/// Written to the fault op in the header before a run, see JIT::set_fault_ops
+pub(crate) const NO_FAULT_OP: u64 = u64::MAX;
+
+const OUTPUT_BUFFER_SIZE: usize = 4096;
+
+/// Why generated code stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+    IoError,
+    Eof,
+    OutOfFuel,
+    TapeFault,
+    CompileError,
+}
+
+/// Where and after how long generated code stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RunSummary {
+    pub status: Status,
+    /// Final tape pointer from the start of the tape, outside of it after a tape fault
+    pub tape_offset: isize,
+    /// Number of steps run, only counted by code from JIT::metered
+    pub steps: Option<u64>,
+    /// Index of the op that moved off the tape on a tape fault, only reported by
+    /// code from a JIT with set_fault_ops. Everything before it has run.
+    pub fault_op: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunError {
+    pub summary: RunSummary,
+    /// What the IO panicked with for IO errors and EOF, or why a loop failed to compile
+    pub message: Option<String>,
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.summary.status {
+            Status::Ok => write!(f, "no error"),
+            Status::IoError => write!(f, "IO error: {}", self.message.as_deref().unwrap_or("unknown")),
+            Status::Eof => write!(f, "read past the end of the input"),
+            Status::OutOfFuel => write!(f, "out of fuel after {} steps", self.summary.steps.unwrap_or(0)),
+            Status::TapeFault => write!(f, "tape pointer moved off the tape to {}", self.summary.tape_offset),
+            Status::CompileError => write!(f, "failed to compile a loop: {}", self.message.as_deref().unwrap_or("unknown")),
+        }
+    }
+}
+
+impl std::error::Error for RunError {}
+
+/// Turn the values generated code left in the header into the result of a run
+pub(crate) fn run_result(code: u64, tape_offset: i64, steps: u64, fault_op: u64, message: Option<String>) -> Result<RunSummary, RunError> {
+    let status = match code {
+        status::OK => Status::Ok,
+        status::IO_ERROR => Status::IoError,
+        status::EOF => Status::Eof,
+        status::OUT_OF_FUEL => Status::OutOfFuel,
+        status::TAPE_FAULT => Status::TapeFault,
+        status::COMPILE_ERROR => Status::CompileError,
+        _ => panic!("Generated code returned an unknown status {code}"),
+    };
+    let summary = RunSummary {
+        status,
+        tape_offset: tape_offset as isize,
+        steps: if steps == NOT_METERED { None } else { Some(steps) },
+        fault_op: if fault_op == NO_FAULT_OP { None } else { Some(fault_op as usize) },
+    };
+    match status {
+        Status::Ok => Ok(summary),
+        _ => Err(RunError { summary, message }),
+    }
+}
+
+/// Generated code copied into executable memory, ready to be called any number of times
+pub struct Executable {
+    ptr: *mut u8,
+    len: usize,
+    gdb: Option<GdbRegistration>,
+}
+
+impl Executable {
+    pub fn new(code: &[u8]) -> Self {
+        Self { ptr: alloc_executable(code), len: code.len(), gdb: None }
+    }
+
+    /// Announce the symbols of the code, with offsets from its start
+    pub fn register(&mut self, symbols: &[Symbol], registration: Registration) {
+        if registration.perf_map {
+            // Only a help for profiling, so not being able to write it doesn't stop the program
+            let _ = debuginfo::write_perf_map(self.ptr as usize, symbols);
+        }
+        if registration.gdb {
+            self.gdb = Some(GdbRegistration::new(self.ptr as usize, self.len, symbols));
+        }
+    }
+
+    /// Call the code with the tape pointer at the cell tp. The tape offset in the
+    /// result is from the start of the tape as well.
+    pub fn call<I: IO>(&self, io: &mut I, tape: &mut [u8], tp: usize, fuel: u64) -> Result<RunSummary, RunError> {
+        self.call_with(io, tape, tp, fuel, None)
+    }
+
+    /// Call code from JIT::compile_lazy, compiling its loops with the same JIT. The
+    /// compiled loops only live for the call, so the code can't be called again.
+    fn call_with<I: IO>(
+        &self,
+        io: &mut I,
+        tape: &mut [u8],
+        tp: usize,
+        fuel: u64,
+        lazy: Option<(&JIT, Registration)>,
+    ) -> Result<RunSummary, RunError> {
+        assert_eq!(std::env::consts::ARCH, "aarch64", "JIT code only runs on aarch64");
+        unsafe { call_code(self.ptr, io, tape, tp, fuel, lazy) }
+    }
+}
+
+/// Call a function generated for the runtime ABI, see JIT::compile, with the tape
+/// pointer at the cell tp. The tape offset in the result is from the start of the
+/// tape as well. The code must be for this host.
+pub(crate) unsafe fn call_code<I: IO>(
+    ptr: *const u8,
+    io: &mut I,
+    tape: &mut [u8],
+    tp: usize,
+    fuel: u64,
+    lazy: Option<(&JIT, Registration)>,
+) -> Result<RunSummary, RunError> {
+    assert!(tp < tape.len(), "Tape pointer {tp} is outside the tape");
+
+    // Cast the code pointer to the BF JIT function signature:
+    // fn(*mut u8, *mut u8, extern "C" fn(*mut u8, *mut u8) -> *mut u8, extern "C" fn(*mut u8) -> u8) -> u64
+    let bf_fn = unsafe {
+        mem::transmute::<
+            *const u8,
+            extern "C" fn(
+                *mut u8,
+                *mut u8,
+                extern "C" fn(*mut u8, *mut u8) -> *mut u8,
+                extern "C" fn(*mut u8) -> u8,
+            ) -> u64,
+        >(ptr)
+    };
+    // Only written by the generated code before it is read, so it doesn't need clearing
+    let mut buffer = [MaybeUninit::<u8>::uninit(); OUTPUT_BUFFER_SIZE];
+    let buffer = buffer.as_mut_ptr_range();
+    let tape = tape.as_mut_ptr_range();
+    let mut context = Context {
+        header: Header {
+            out_start: buffer.start as *mut u8,
+            out_end: buffer.end as *mut u8,
+            tape_start: tape.start,
+            tape_end: tape.end,
+            fuel: fuel.min(i64::MAX as u64),
+            status: status::OK,
+            tape_offset: 0,
+            steps: NOT_METERED,
+            compile_loop: compile_trampoline::<I>,
+            fault_op: NO_FAULT_OP,
+        },
+        io,
+        error: None,
+        loops: lazy.map(|(jit, registration)| LazyLoops { jit, compiled: vec![], registration }),
+    };
+    let tape_ptr = unsafe { tape.start.add(tp) };
+    let rt_ptr = &mut context as *mut Context<I> as *mut u8;
+    // DEBUG: show pointers before JIT call
+    /*println!("[JIT RUN] tape_ptr={:p}, rt_ptr={:p}, write_fn={:p}, read_fn={:p}",
+        tape_ptr, rt_ptr,
+        flush_trampoline::<I> as *const u8,
+        read_trampoline::<I> as *const u8
+    );*/
+    // Call the BF function
+    let code = bf_fn(tape_ptr, rt_ptr, flush_trampoline::<I>, read_trampoline::<I>);
+
+    run_result(code, context.header.tape_offset, context.header.steps, context.header.fault_op, context.error)
+}
+
+impl Drop for Executable {
+    fn drop(&mut self) {
+        // gdb may read the code while it is registered
+        self.gdb = None;
+        unsafe { free_executable(self.ptr, self.len) };
+    }
 }
 
 /// Runtime for executing JIT-compiled Brainfuck code
-pub struct Runtime<'a> {
+pub struct Runtime<I: IO> {
     tape: Vec<u8>,
-    io: Box<dyn IO<'a> + 'a>,
+    io: I,
+    fuel: u64,
     code: Vec<u8>,
+    /// Compiles the loops of lazy code
+    jit: Option<JIT>,
+    symbols: Vec<Symbol>,
+    registration: Registration,
 }
 
-impl<'a> Runtime<'a> {
+impl<I: IO> Runtime<I> {
     /// Create a new runtime with the given IO and code pointer
-    pub fn new(io: Box<dyn IO<'a> + 'a>, code: Vec<u8>) -> Self {
-        Self { tape: vec![0; 30000], io, code }
+    pub fn new(io: I, code: Vec<u8>) -> Self {
+        Self {
+            tape: vec![0; 30000],
+            io,
+            fuel: u64::MAX,
+            code,
+            jit: None,
+            symbols: vec![],
+            registration: Registration::default(),
+        }
+    }
+
+    /// Compile only the code outside of loops up front, every top-level loop is
+    /// compiled when the program first enters it
+    pub fn lazy(io: I, jit: JIT) -> Result<Self, String> {
+        let code = jit.compile_lazy()?;
+        let symbols = jit.symbols(code.len(), &[]);
+        Ok(Self {
+            tape: vec![0; 30000],
+            io,
+            fuel: u64::MAX,
+            code,
+            jit: Some(jit),
+            symbols,
+            registration: Registration::default(),
+        })
+    }
+
+    /// Like new with the code from JIT::compile_with_symbols, but the code is taken
+    /// from the cache if it has an entry for the key, and stored there otherwise
+    pub fn cached(io: I, jit: &JIT, cache: &CodeCache, key: &CacheKey) -> Result<Self, String> {
+        let (code, symbols) = match cache.load(key) {
+            Some(cached) => (cached.code, cached.symbols),
+            None => {
+                let (code, symbols) = jit.compile_with_symbols()?;
+                // Failing to store only costs the next run the compile time
+                let _ = cache.store(key, &code, &symbols);
+                (code, symbols)
+            }
+        };
+        let mut runtime = Self::new(io, code);
+        runtime.set_symbols(symbols);
+        Ok(runtime)
+    }
+
+    /// Symbols of the code, from JIT::compile_with_symbols, for registering it
+    pub fn set_symbols(&mut self, symbols: Vec<Symbol>) {
+        self.symbols = symbols;
+    }
+
+    /// Announce the code to profilers and debuggers when it runs, along with
+    /// every loop lazy code compiles
+    pub fn set_registration(&mut self, registration: Registration) {
+        self.registration = registration;
+    }
+
+    /// Limit the number of steps code from JIT::metered may run, unlimited by default
+    pub fn set_fuel(&mut self, fuel: u64) {
+        self.fuel = fuel;
     }
 
     /// Run the JIT-compiled function
-    pub fn run(&mut self) {
+    pub fn run(&mut self) -> Result<RunSummary, RunError> {
         // DEBUG: dump generated JIT code as 32-bit words
         /*println!("Generated JIT code ({} bytes):", self.code.len());
         for (i, chunk) in self.code.chunks(4).enumerate() {
@@ -55,53 +431,130 @@ impl<'a> Runtime<'a> {
             println!("{:04x}: 0x{:08x}", i * 4, word);
         }*/
 
-        // Allocate an RWX buffer in one go
-        let code_ptr = unsafe {
-            VirtualAlloc(
-                ptr::null_mut(),
-                self.code.len() as SIZE_T,
-                MEM_COMMIT | MEM_RESERVE,
-                PAGE_EXECUTE_READWRITE,
-            )
-        };
-        if code_ptr.is_null() {
-            panic!("VirtualAlloc failed");
+        let mut executable = Executable::new(&self.code);
+        if self.symbols.is_empty() {
+            let program = Symbol { name: "bf_program".to_string(), offset: 0, len: self.code.len() };
+            executable.register(&[program], self.registration);
+        } else {
+            executable.register(&self.symbols, self.registration);
         }
-        // Copy the JIT bytes into the new buffer and flush I-cache
-        unsafe {
-            ptr::copy_nonoverlapping(self.code.as_ptr(), code_ptr as *mut u8, self.code.len());
-            // Flush the instruction cache so CPU sees changes
-            FlushInstructionCache(GetCurrentProcess(), code_ptr, self.code.len() as SIZE_T);
-        }
-
-        // Cast the code pointer to the BF JIT function signature:
-        // fn(*mut u8, *mut u8, extern "C" fn(*mut u8, u8), extern "C" fn(*mut u8) -> u8) -> u8
-        let bf_fn = unsafe {
-            mem::transmute::<
-                *mut u8,
-                extern "C" fn(
-                    *mut u8,
-                    *mut u8,
-                    extern "C" fn(*mut u8, u8),
-                    extern "C" fn(*mut u8) -> u8,
-                ) -> u8,
-            >(code_ptr as *mut u8)
-        };
-        // Prepare pointers
-        let tape_ptr = self.tape.as_mut_ptr();
-        let rt_ptr = self as *mut Runtime as *mut u8;
-        // DEBUG: show pointers before JIT call
-        /*println!("[JIT RUN] tape_ptr={:p}, rt_ptr={:p}, write_fn={:p}, read_fn={:p}",
-            tape_ptr, rt_ptr,
-            write_trampoline as *const u8,
-            read_trampoline as *const u8
-        );*/
-        // Call the BF function
-        bf_fn(tape_ptr, rt_ptr, write_trampoline, read_trampoline);
+        let lazy = self.jit.as_ref().map(|jit| (jit, self.registration));
+        executable.call_with(&mut self.io, &mut self.tape, 0, self.fuel, lazy)
     }
 
     /// Consume the runtime and return the tape contents
     pub fn tape(self) -> Vec<u8> {
         self.tape
     }
-}
\ No newline at end of file
+
+    /// Consume the runtime and return its IO
+    pub fn into_io(self) -> I {
+        self.io
+    }
+}
+
+/// Copy the code into freshly allocated executable memory
+#[cfg(windows)]
+fn alloc_executable(code: &[u8]) -> *mut u8 {
+    // Allocate an RWX buffer in one go
+    let code_ptr = unsafe {
+        VirtualAlloc(
+            ptr::null_mut(),
+            code.len() as SIZE_T,
+            MEM_COMMIT | MEM_RESERVE,
+            PAGE_EXECUTE_READWRITE,
+        )
+    };
+    if code_ptr.is_null() {
+        panic!("VirtualAlloc failed");
+    }
+    // Copy the JIT bytes into the new buffer and flush I-cache
+    unsafe {
+        ptr::copy_nonoverlapping(code.as_ptr(), code_ptr as *mut u8, code.len());
+        // Flush the instruction cache so CPU sees changes
+        FlushInstructionCache(GetCurrentProcess(), code_ptr, code.len() as SIZE_T);
+    }
+    code_ptr as *mut u8
+}
+
+/// Overwrite code in memory from alloc_executable
+#[cfg(windows)]
+unsafe fn patch_executable(at: *mut u8, bytes: &[u8]) {
+    unsafe {
+        ptr::copy_nonoverlapping(bytes.as_ptr(), at, bytes.len());
+        FlushInstructionCache(GetCurrentProcess(), at as _, bytes.len() as SIZE_T);
+    }
+}
+
+#[cfg(windows)]
+unsafe fn free_executable(code_ptr: *mut u8, _len: usize) {
+    unsafe { VirtualFree(code_ptr as _, 0, MEM_RELEASE) };
+}
+
+/// Copy the code into freshly allocated executable memory
+#[cfg(unix)]
+fn alloc_executable(code: &[u8]) -> *mut u8 {
+    // Map writable first and flip to executable once the code is in place,
+    // systems enforcing W^X refuse RWX mappings
+    let code_ptr = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            code.len(),
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANON,
+            -1,
+            0,
+        )
+    };
+    if code_ptr == libc::MAP_FAILED {
+        panic!("mmap failed");
+    }
+    unsafe {
+        ptr::copy_nonoverlapping(code.as_ptr(), code_ptr as *mut u8, code.len());
+        if libc::mprotect(code_ptr, code.len(), libc::PROT_READ | libc::PROT_EXEC) != 0 {
+            panic!("mprotect failed");
+        }
+        // Flush the instruction cache so CPU sees changes
+        #[cfg(target_arch = "aarch64")]
+        {
+            unsafe extern "C" {
+                fn __clear_cache(start: *mut libc::c_char, end: *mut libc::c_char);
+            }
+            let start = code_ptr as *mut libc::c_char;
+            __clear_cache(start, start.add(code.len()));
+        }
+    }
+    code_ptr as *mut u8
+}
+
+/// Overwrite code in memory from alloc_executable, making its pages writable for
+/// as long as it takes. Nothing runs on them meanwhile, the generated code is
+/// waiting for the call that does the patching.
+#[cfg(unix)]
+unsafe fn patch_executable(at: *mut u8, bytes: &[u8]) {
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let start = at as usize & !(page_size - 1);
+    let len = at as usize + bytes.len() - start;
+    unsafe {
+        if libc::mprotect(start as *mut libc::c_void, len, libc::PROT_READ | libc::PROT_WRITE) != 0 {
+            panic!("mprotect failed");
+        }
+        ptr::copy_nonoverlapping(bytes.as_ptr(), at, bytes.len());
+        if libc::mprotect(start as *mut libc::c_void, len, libc::PROT_READ | libc::PROT_EXEC) != 0 {
+            panic!("mprotect failed");
+        }
+        #[cfg(target_arch = "aarch64")]
+        {
+            unsafe extern "C" {
+                fn __clear_cache(start: *mut libc::c_char, end: *mut libc::c_char);
+            }
+            let start = at as *mut libc::c_char;
+            __clear_cache(start, start.add(bytes.len()));
+        }
+    }
+}
+
+#[cfg(unix)]
+unsafe fn free_executable(code_ptr: *mut u8, len: usize) {
+    unsafe { libc::munmap(code_ptr as *mut libc::c_void, len) };
+}
@@ -0,0 +1,127 @@
+// Zero cell search for the scan loops [>] and [<]
+//
+// Like memchr, eight cells are checked at a time by treating them as a u64.
+// Strides that divide eight are handled the same way by masking out the cells
+// the loop would skip, other strides fall back to checking cell by cell. On
+// x86-64, strides that divide sixteen first go sixteen cells at a time with
+// SSE2, which every x86-64 CPU has. AVX2 would need a check at runtime and is
+// not used. This is the search of the interpreters, the JIT only generates
+// AArch64 code and scans with NEON there.
+
+const LOW_BITS: u64 = 0x7F7F_7F7F_7F7F_7F7F;
+
+/// The high bit of every zero byte in the word, and nothing else
+fn zero_bytes(word: u64) -> u64 {
+    !(((word & LOW_BITS).wrapping_add(LOW_BITS)) | word | LOW_BITS)
+}
+
+/// High bits of the bytes 0, stride, 2 * stride, ... of a word
+fn lane_mask(stride: usize) -> u64 {
+    let mut mask = 0;
+    let mut byte = 0;
+    while byte < 8 {
+        mask |= 0x80 << (byte * 8);
+        byte += stride;
+    }
+    mask
+}
+
+fn word_at(tape: &[u8], i: usize) -> u64 {
+    u64::from_le_bytes(tape[i..i + 8].try_into().unwrap())
+}
+
+#[cfg(target_arch = "x86_64")]
+mod sse2 {
+    use std::arch::x86_64::{__m128i, _mm_cmpeq_epi8, _mm_loadu_si128, _mm_movemask_epi8, _mm_setzero_si128};
+
+    /// Bits 0, stride, 2 * stride, ... of a chunk mask
+    pub fn lane_bits(stride: usize) -> u32 {
+        (0..16).step_by(stride).fold(0, |bits, lane| bits | 1 << lane)
+    }
+
+    /// Bit n set for every zero byte n of the sixteen starting at i
+    pub fn zero_bits(tape: &[u8], i: usize) -> u32 {
+        let chunk = &tape[i..i + 16];
+        // SSE2 is part of x86-64, and the load is unaligned and within the chunk
+        unsafe {
+            let cells = _mm_loadu_si128(chunk.as_ptr() as *const __m128i);
+            _mm_movemask_epi8(_mm_cmpeq_epi8(cells, _mm_setzero_si128())) as u32
+        }
+    }
+}
+
+/// First zero cell at from, from + stride, ... before the end of the tape
+pub fn find_zero(tape: &[u8], from: usize, stride: usize) -> Option<usize> {
+    let mut i = from;
+    #[cfg(target_arch = "x86_64")]
+    if 16 % stride == 0 {
+        let lanes = sse2::lane_bits(stride);
+        while i + 16 <= tape.len() {
+            let zeros = sse2::zero_bits(tape, i) & lanes;
+            if zeros != 0 {
+                return Some(i + zeros.trailing_zeros() as usize);
+            }
+            i += 16;
+        }
+    }
+    if 8 % stride == 0 {
+        let lanes = lane_mask(stride);
+        while i + 8 <= tape.len() {
+            let zeros = zero_bytes(word_at(tape, i)) & lanes;
+            if zeros != 0 {
+                return Some(i + zeros.trailing_zeros() as usize / 8);
+            }
+            i += 8;
+        }
+    }
+    while i < tape.len() {
+        if tape[i] == 0 {
+            return Some(i);
+        }
+        i += stride;
+    }
+    None
+}
+
+/// Last zero cell at from, from - stride, ... down to the start of the tape
+pub fn rfind_zero(tape: &[u8], from: usize, stride: usize) -> Option<usize> {
+    let mut i = from;
+    #[cfg(target_arch = "x86_64")]
+    if 16 % stride == 0 {
+        // The lanes counted down from the top byte of the chunk ending at i
+        let lanes = sse2::lane_bits(stride) << (stride - 1);
+        while i >= 15 {
+            let zeros = sse2::zero_bits(tape, i - 15) & lanes;
+            if zeros != 0 {
+                return Some(i - 15 + (31 - zeros.leading_zeros() as usize));
+            }
+            if i < 16 {
+                return None;
+            }
+            i -= 16;
+        }
+    }
+    if 8 % stride == 0 {
+        // The lanes counted down from the top byte of the word ending at i
+        let lanes = lane_mask(stride) << ((stride - 1) * 8);
+        while i >= 7 {
+            let zeros = zero_bytes(word_at(tape, i - 7)) & lanes;
+            if zeros != 0 {
+                return Some(i - 7 + (63 - zeros.leading_zeros() as usize) / 8);
+            }
+            if i < 8 {
+                return None;
+            }
+            i -= 8;
+        }
+    }
+    loop {
+        if tape[i] == 0 {
+            return Some(i);
+        }
+        if i < stride {
+            return None;
+        }
+        i -= stride;
+    }
+}
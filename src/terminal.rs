@@ -0,0 +1,137 @@
+// Raw terminal input for interactive programs
+//
+// In canonical mode the terminal only hands out input after Enter and echoes
+// every key. RawTerminal switches stdin to non-canonical, no-echo mode until it
+// is dropped. Panics inside JIT code abort instead of unwinding, so the
+// original settings are also restored from a panic hook, and from a handler for
+// the signals that end the process, e.g. Ctrl-C. The handler puts back the
+// default action and raises the signal again, so the process still ends the way
+// it would have.
+
+#[cfg(unix)]
+use std::sync::{Mutex, OnceLock};
+
+/// Signals that end the process and may come from the keyboard or a kill
+#[cfg(unix)]
+const SIGNALS: [libc::c_int; 3] = [libc::SIGINT, libc::SIGQUIT, libc::SIGTERM];
+
+#[cfg(unix)]
+struct Original {
+    termios: libc::termios,
+    // The signal actions replaced by ours
+    actions: Vec<(libc::c_int, libc::sigaction)>,
+}
+
+#[cfg(unix)]
+static ORIGINAL: Mutex<Option<Original>> = Mutex::new(None);
+
+// The signal handler can't take locks, it reads the settings from here
+#[cfg(unix)]
+static SIGNAL_TERMIOS: OnceLock<libc::termios> = OnceLock::new();
+
+pub struct RawTerminal {
+    _private: (),
+}
+
+impl RawTerminal {
+    /// Put stdin into raw mode, the error says why it can't be
+    #[cfg(unix)]
+    pub fn enable() -> Result<Self, String> {
+        unsafe {
+            if libc::isatty(libc::STDIN_FILENO) == 0 {
+                return Err("stdin is not a terminal".to_string());
+            }
+
+            let mut termios: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) != 0 {
+                return Err(format!("can't read the terminal settings: {}", std::io::Error::last_os_error()));
+            }
+            let original = termios;
+
+            // Keep ISIG so Ctrl-C still works
+            termios.c_lflag &= !(libc::ICANON | libc::ECHO);
+            termios.c_cc[libc::VMIN] = 1;
+            termios.c_cc[libc::VTIME] = 0;
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) != 0 {
+                return Err(format!("can't change the terminal settings: {}", std::io::Error::last_os_error()));
+            }
+
+            let _ = SIGNAL_TERMIOS.set(original);
+            let actions = install_signal_handlers();
+            *ORIGINAL.lock().unwrap() = Some(Original { termios: original, actions });
+        }
+
+        let default_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            restore();
+            default_hook(info);
+        }));
+
+        Ok(Self { _private: () })
+    }
+
+    #[cfg(not(unix))]
+    pub fn enable() -> Result<Self, String> {
+        Err("raw terminal mode is not supported on this platform".to_string())
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        restore();
+    }
+}
+
+#[cfg(unix)]
+fn restore() {
+    // The hook may run while the lock is held, don't panic again in that case
+    let Ok(mut original) = ORIGINAL.try_lock() else {
+        return;
+    };
+    if let Some(original) = original.take() {
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &original.termios);
+            for (signal, action) in &original.actions {
+                libc::sigaction(*signal, action, std::ptr::null_mut());
+            }
+        }
+    }
+}
+
+/// Install restore_and_raise for SIGNALS, returns the actions it replaced
+#[cfg(unix)]
+unsafe fn install_signal_handlers() -> Vec<(libc::c_int, libc::sigaction)> {
+    let mut replaced = Vec::new();
+    for signal in SIGNALS {
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = restore_and_raise as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            libc::sigemptyset(&mut action.sa_mask);
+
+            let mut old: libc::sigaction = std::mem::zeroed();
+            if libc::sigaction(signal, &action, &mut old) != 0 {
+                continue;
+            }
+            // A process started to ignore the signal, e.g. under nohup, keeps ignoring it
+            if old.sa_sigaction == libc::SIG_IGN {
+                libc::sigaction(signal, &old, std::ptr::null_mut());
+                continue;
+            }
+            replaced.push((signal, old));
+        }
+    }
+    replaced
+}
+
+/// Only calls async-signal-safe functions
+#[cfg(unix)]
+extern "C" fn restore_and_raise(signal: libc::c_int) {
+    unsafe {
+        if let Some(termios) = SIGNAL_TERMIOS.get() {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, termios);
+        }
+        libc::signal(signal, libc::SIG_DFL);
+        libc::raise(signal);
+    }
+}
@@ -0,0 +1,291 @@
+// Threaded interpreter
+//
+// Decodes the Ops once into a vector of instructions that each carry a pointer
+// to the handler running them, so dispatch is an indirect call instead of a
+// match over every Op. Common sequences are fused into superinstructions while
+// decoding, and jumps go straight to the index of their target instruction.
+// Nothing is generated at runtime, so this is the fast backend for hosts that
+// don't allow executable memory. It behaves the same as the Vm.
+
+use crate::io::IO;
+use crate::scan;
+use crate::vm::Op;
+
+/// Runs the instruction at pc and returns the index of the next one
+type Handler<I> = fn(&mut Machine<I>, &Instr<I>, usize) -> usize;
+
+struct Instr<I: IO> {
+    handler: Handler<I>,
+    /// Added to the cell, wrapping
+    add: u8,
+    /// Tape pointer move, or the stride of a scan
+    shift: isize,
+    /// Index of the instruction a jump goes to
+    target: usize,
+}
+
+impl<I: IO> Instr<I> {
+    fn new(handler: Handler<I>) -> Self {
+        Self { handler, add: 0, shift: 0, target: 0 }
+    }
+}
+
+/// The state the handlers work on
+struct Machine<I: IO> {
+    tape: Vec<u8>,
+    tp: usize,
+    io: I,
+}
+
+impl<I: IO> Machine<I> {
+    #[inline(always)]
+    fn cell(&mut self) -> &mut u8 {
+        &mut self.tape[self.tp]
+    }
+
+    #[inline(always)]
+    fn move_by(&mut self, shift: isize) {
+        if shift >= 0 {
+            self.tp += shift as usize;
+            // Grow the tape on the right as needed
+            if self.tp >= self.tape.len() {
+                self.tape.resize(self.tp + 1, 0);
+            }
+        } else {
+            let Some(tp) = self.tp.checked_sub(shift.unsigned_abs()) else {
+                panic!("Tape pointer underflow: attempted to move left {} from position {}", shift.unsigned_abs(), self.tp);
+            };
+            self.tp = tp;
+        }
+    }
+}
+
+fn add<I: IO>(m: &mut Machine<I>, instr: &Instr<I>, pc: usize) -> usize {
+    let cell = m.cell();
+    *cell = cell.wrapping_add(instr.add);
+    pc + 1
+}
+
+fn move_by<I: IO>(m: &mut Machine<I>, instr: &Instr<I>, pc: usize) -> usize {
+    m.move_by(instr.shift);
+    pc + 1
+}
+
+fn scan_right<I: IO>(m: &mut Machine<I>, instr: &Instr<I>, pc: usize) -> usize {
+    // Cells past the end are zero once the tape grows, so stop at the first one
+    let stride = instr.shift as usize;
+    m.tp = scan::find_zero(&m.tape, m.tp, stride)
+        .unwrap_or_else(|| m.tp + (m.tape.len() - m.tp).div_ceil(stride) * stride);
+    if m.tp >= m.tape.len() {
+        m.tape.resize(m.tp + 1, 0);
+    }
+    pc + 1
+}
+
+fn scan_left<I: IO>(m: &mut Machine<I>, instr: &Instr<I>, pc: usize) -> usize {
+    let stride = instr.shift.unsigned_abs();
+    m.tp = scan::rfind_zero(&m.tape, m.tp, stride).unwrap_or_else(|| {
+        panic!("Tape pointer underflow: scanning left by {} from position {} found no zero cell", stride, m.tp)
+    });
+    pc + 1
+}
+
+fn print<I: IO>(m: &mut Machine<I>, _: &Instr<I>, pc: usize) -> usize {
+    let c = *m.cell();
+    m.io.write_byte(c);
+    pc + 1
+}
+
+fn read<I: IO>(m: &mut Machine<I>, _: &Instr<I>, pc: usize) -> usize {
+    *m.cell() = m.io.read_byte();
+    pc + 1
+}
+
+fn jump_if_zero<I: IO>(m: &mut Machine<I>, instr: &Instr<I>, pc: usize) -> usize {
+    if *m.cell() == 0 { instr.target } else { pc + 1 }
+}
+
+fn jump_if_not_zero<I: IO>(m: &mut Machine<I>, instr: &Instr<I>, pc: usize) -> usize {
+    if *m.cell() != 0 { instr.target } else { pc + 1 }
+}
+
+/// [-] and [+]
+fn clear<I: IO>(m: &mut Machine<I>, _: &Instr<I>, pc: usize) -> usize {
+    *m.cell() = 0;
+    pc + 1
+}
+
+/// Add to the cell, then move
+fn add_move<I: IO>(m: &mut Machine<I>, instr: &Instr<I>, pc: usize) -> usize {
+    let cell = m.cell();
+    *cell = cell.wrapping_add(instr.add);
+    m.move_by(instr.shift);
+    pc + 1
+}
+
+/// Move, then add to the new cell
+fn move_add<I: IO>(m: &mut Machine<I>, instr: &Instr<I>, pc: usize) -> usize {
+    m.move_by(instr.shift);
+    let cell = m.cell();
+    *cell = cell.wrapping_add(instr.add);
+    pc + 1
+}
+
+/// Move, then go round the loop again unless the new cell is zero, e.g. the end of [->+<]
+fn move_jump_if_not_zero<I: IO>(m: &mut Machine<I>, instr: &Instr<I>, pc: usize) -> usize {
+    m.move_by(instr.shift);
+    if *m.cell() != 0 { instr.target } else { pc + 1 }
+}
+
+/// Move, then skip the loop starting here if the new cell is zero
+fn move_jump_if_zero<I: IO>(m: &mut Machine<I>, instr: &Instr<I>, pc: usize) -> usize {
+    m.move_by(instr.shift);
+    if *m.cell() == 0 { instr.target } else { pc + 1 }
+}
+
+/// Add, move and go round the loop again unless the new cell is zero, e.g. the +<] of [->+<]
+fn add_move_jump_if_not_zero<I: IO>(m: &mut Machine<I>, instr: &Instr<I>, pc: usize) -> usize {
+    let cell = m.cell();
+    *cell = cell.wrapping_add(instr.add);
+    m.move_by(instr.shift);
+    if *m.cell() != 0 { instr.target } else { pc + 1 }
+}
+
+/// The cell delta of an Inc or Dec
+fn delta(op: Op) -> Option<u8> {
+    match op {
+        Op::Inc(n) => Some(n),
+        Op::Dec(n) => Some(n.wrapping_neg()),
+        _ => None,
+    }
+}
+
+/// The tape pointer move of a MovR or MovL
+fn shift(op: Op) -> Option<isize> {
+    match op {
+        Op::MovR(n) => Some(n as isize),
+        Op::MovL(n) => Some(-(n as isize)),
+        _ => None,
+    }
+}
+
+/// Whether the Ops start with [-] or [+], only a step of 1 is sure to reach zero
+/// and other steps have to loop like they would in the Vm
+fn clears(program: &[Op]) -> bool {
+    matches!(program, [Op::JmpIfZ(_), Op::Inc(1) | Op::Dec(1), Op::JmpIfNZ(_), ..])
+}
+
+/// How a decoded instruction takes part in a loop
+enum Link {
+    None,
+    Open,
+    Close,
+}
+
+/// Decode the program, fusing superinstructions and linking the jumps
+fn decode<I: IO>(program: &[Op]) -> Vec<Instr<I>> {
+    let mut code: Vec<Instr<I>> = Vec::with_capacity(program.len());
+    // Indices of the instructions starting the open loops
+    let mut loops = vec![];
+    let mut i = 0;
+    while i < program.len() {
+        let op = program[i];
+        let (next, after) = (program.get(i + 1).copied(), program.get(i + 2).copied());
+        let (mut instr, len, link) = match (op, next, after) {
+            _ if clears(&program[i..]) => (Instr::new(clear), 3, Link::None),
+            (_, Some(next), Some(Op::JmpIfNZ(_))) if delta(op).is_some() && shift(next).is_some() => {
+                let instr = Instr { add: delta(op).unwrap(), shift: shift(next).unwrap(), ..Instr::new(add_move_jump_if_not_zero) };
+                (instr, 3, Link::Close)
+            }
+            (_, Some(next), _) if delta(op).is_some() && shift(next).is_some() => {
+                let instr = Instr { add: delta(op).unwrap(), shift: shift(next).unwrap(), ..Instr::new(add_move) };
+                (instr, 2, Link::None)
+            }
+            (_, Some(next), _) if shift(op).is_some() && delta(next).is_some() => {
+                let instr = Instr { add: delta(next).unwrap(), shift: shift(op).unwrap(), ..Instr::new(move_add) };
+                (instr, 2, Link::None)
+            }
+            (_, Some(Op::JmpIfNZ(_)), _) if shift(op).is_some() => {
+                (Instr { shift: shift(op).unwrap(), ..Instr::new(move_jump_if_not_zero) }, 2, Link::Close)
+            }
+            (_, Some(Op::JmpIfZ(_)), _) if shift(op).is_some() && !clears(&program[i + 1..]) => {
+                (Instr { shift: shift(op).unwrap(), ..Instr::new(move_jump_if_zero) }, 2, Link::Open)
+            }
+            (Op::Inc(_) | Op::Dec(_), _, _) => (Instr { add: delta(op).unwrap(), ..Instr::new(add) }, 1, Link::None),
+            (Op::MovR(_) | Op::MovL(_), _, _) => (Instr { shift: shift(op).unwrap(), ..Instr::new(move_by) }, 1, Link::None),
+            (Op::ScanR(n), _, _) => (Instr { shift: n as isize, ..Instr::new(scan_right) }, 1, Link::None),
+            (Op::ScanL(n), _, _) => (Instr { shift: n as isize, ..Instr::new(scan_left) }, 1, Link::None),
+            (Op::Print, _, _) => (Instr::new(print), 1, Link::None),
+            (Op::Read, _, _) => (Instr::new(read), 1, Link::None),
+            (Op::JmpIfZ(_), _, _) => (Instr::new(jump_if_zero), 1, Link::Open),
+            (Op::JmpIfNZ(_), _, _) => (Instr::new(jump_if_not_zero), 1, Link::Close),
+            (Op::Nop, _, _) => {
+                i += 1;
+                continue;
+            }
+        };
+
+        // Both ends of a loop jump to the instruction after the other end
+        match link {
+            Link::None => {}
+            Link::Open => loops.push(code.len()),
+            Link::Close => {
+                let start = loops.pop().expect("Unmatched ']'");
+                code[start].target = code.len() + 1;
+                instr.target = start + 1;
+            }
+        }
+        code.push(instr);
+        i += len;
+    }
+    assert!(loops.is_empty(), "Unmatched '['");
+    code
+}
+
+pub struct Threaded<I: IO> {
+    code: Vec<Instr<I>>,
+    machine: Machine<I>,
+}
+
+impl<I: IO> Threaded<I> {
+    pub fn new(io: I, program: Vec<Op>) -> Self {
+        Self {
+            code: decode(&program),
+            machine: Machine { tape: vec![0; 1024], tp: 0, io },
+        }
+    }
+
+    pub fn run(&mut self) {
+        let mut pc = 0;
+        while pc < self.code.len() {
+            let instr = &self.code[pc];
+            pc = (instr.handler)(&mut self.machine, instr, pc);
+        }
+    }
+
+    /// Number of decoded instructions, fewer than the Ops when superinstructions were fused
+    pub fn len(&self) -> usize {
+        self.code.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.code.is_empty()
+    }
+
+    /// Current tape pointer
+    pub fn tp(&self) -> usize {
+        self.machine.tp
+    }
+
+    pub fn tape(&self) -> &[u8] {
+        &self.machine.tape
+    }
+
+    pub fn flush_io(&mut self) {
+        self.machine.io.flush();
+    }
+
+    pub fn into_io(self) -> I {
+        self.machine.io
+    }
+}
@@ -0,0 +1,179 @@
+// Tiered execution: interpret first, compile hot loops
+//
+// The program starts out in a Vm. Every taken back-edge is counted, and once a
+// loop has gone round often enough it is compiled on its own into a function
+// that runs the loop from its start to its end. From then on, whenever the Vm
+// is about to run that loop, the compiled function runs it on the same tape and
+// IO and the Vm carries on after the loop.
+//
+// Compiled loops run on the Vm's tape as it is, and stop with a tape fault when
+// they move past its end. The fault says which op moved off the tape, so the Vm
+// grows the tape like it would have itself and carries on after that op.
+
+use crate::io::IO;
+use crate::jit::JIT;
+use crate::runtime::{Executable, RunError, RunSummary, Status};
+use crate::vm::{Op, Vm};
+
+/// Back-edges a loop takes before it is compiled
+pub const HOT_LOOP_THRESHOLD: u32 = 1000;
+
+/// Runs compiled loops for a Tiered engine
+pub trait LoopRunner<I: IO> {
+    type Loaded;
+
+    /// Whether compiled loops can run on this host at all
+    fn available(&self) -> bool {
+        true
+    }
+
+    /// Prepare machine code from the JIT for running
+    fn load(&mut self, code: Vec<u8>) -> Self::Loaded;
+
+    /// Run a loaded loop with the tape pointer at the cell tp
+    fn run(&mut self, code: &Self::Loaded, io: &mut I, tape: &mut Vec<u8>, tp: usize) -> Result<RunSummary, RunError>;
+}
+
+/// Runs compiled loops as native code, only available on AArch64
+pub struct NativeLoops;
+
+impl<I: IO> LoopRunner<I> for NativeLoops {
+    type Loaded = Executable;
+
+    fn available(&self) -> bool {
+        cfg!(target_arch = "aarch64")
+    }
+
+    fn load(&mut self, code: Vec<u8>) -> Executable {
+        Executable::new(&code)
+    }
+
+    fn run(&mut self, code: &Executable, io: &mut I, tape: &mut Vec<u8>, tp: usize) -> Result<RunSummary, RunError> {
+        code.call(io, tape, tp, u64::MAX)
+    }
+}
+
+pub struct Tiered<I: IO, R: LoopRunner<I> = NativeLoops> {
+    vm: Vm<I>,
+    runner: R,
+    threshold: u32,
+    /// Taken back-edges of each loop, by the index of its JmpIfZ
+    back_edges: Vec<u32>,
+    /// Compiled loops, by the index of their JmpIfZ
+    compiled: Vec<Option<R::Loaded>>,
+    /// Number of times a compiled loop was run
+    entries: u64,
+}
+
+impl<I: IO> Tiered<I> {
+    pub fn new(io: I, program: Vec<Op>) -> Self {
+        Self::with_runner(io, program, NativeLoops)
+    }
+}
+
+impl<I: IO, R: LoopRunner<I>> Tiered<I, R> {
+    pub fn with_runner(io: I, program: Vec<Op>, runner: R) -> Self {
+        let len = program.len();
+        Self {
+            vm: Vm::new(io, program),
+            runner,
+            threshold: HOT_LOOP_THRESHOLD,
+            back_edges: vec![0; len],
+            compiled: (0..len).map(|_| None).collect(),
+            entries: 0,
+        }
+    }
+
+    /// Compile loops once they have taken this many back-edges
+    pub fn set_threshold(&mut self, threshold: u32) {
+        self.threshold = threshold;
+    }
+
+    /// Run the program to its end. Errors of compiled loops the Vm can't carry on
+    /// from, like IO errors and reading past the end of the input, are returned.
+    pub fn run(&mut self) -> Result<(), RunError> {
+        while !self.vm.is_finished() {
+            let pc = self.vm.pc;
+            let cell = self.vm.tape[self.vm.tp];
+            match self.vm.program[pc] {
+                // Entering a compiled loop
+                Op::JmpIfZ(end) if cell != 0 && self.compiled[pc].is_some() => self.enter(pc, end as usize)?,
+                // Going round a loop again, which is the same as entering it with a non-zero cell
+                Op::JmpIfNZ(start) if cell != 0 => {
+                    let start = start as usize;
+                    self.back_edges[start] += 1;
+                    if self.back_edges[start] == self.threshold && self.runner.available() {
+                        self.compile(start, pc);
+                    }
+                    if self.compiled[start].is_some() {
+                        self.enter(start, pc)?;
+                    } else {
+                        self.vm.step();
+                    }
+                }
+                _ => {
+                    self.vm.step();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Compile the loop, or leave it to the Vm if the JIT can't
+    fn compile(&mut self, start: usize, end: usize) {
+        // The loop is a program of its own, the JIT finds the matching brackets itself
+        let mut jit = JIT::new(self.vm.program[start..=end].to_vec());
+        jit.set_fault_ops(true);
+        if let Ok(code) = jit.compile() {
+            self.compiled[start] = Some(self.runner.load(code));
+        }
+    }
+
+    /// Run the compiled loop starting at start and continue after its end, or
+    /// after the op that moved past the end of the tape
+    fn enter(&mut self, start: usize, end: usize) -> Result<(), RunError> {
+        let code = self.compiled[start].as_ref().unwrap();
+        let result = self.runner.run(code, &mut self.vm.io, &mut self.vm.tape, self.vm.tp);
+        self.entries += 1;
+        match result {
+            Ok(summary) => {
+                self.vm.tp = summary.tape_offset as usize;
+                self.vm.pc = end + 1;
+            }
+            // Off the end, where the Vm grows the tape. A right scan stops there too,
+            // as the new cell is zero.
+            Err(err) if err.summary.status == Status::TapeFault && err.summary.tape_offset >= 0 => {
+                let op = err.summary.fault_op.expect("Compiled loops report the op of a tape fault");
+                let tp = err.summary.tape_offset as usize;
+                let len = (tp + 1).max(2 * self.vm.tape.len());
+                self.vm.tape.resize(len, 0);
+                self.vm.tp = tp;
+                self.vm.pc = start + op + 1;
+            }
+            Err(err) => return Err(err),
+        }
+        Ok(())
+    }
+
+    /// Number of loops that have been compiled
+    pub fn compiled_loops(&self) -> usize {
+        self.compiled.iter().filter(|loaded| loaded.is_some()).count()
+    }
+
+    /// Number of times a compiled loop was run instead of interpreted
+    pub fn compiled_entries(&self) -> u64 {
+        self.entries
+    }
+
+    pub fn vm(&self) -> &Vm<I> {
+        &self.vm
+    }
+
+    pub fn flush_io(&mut self) {
+        self.vm.flush_io();
+    }
+
+    pub fn into_io(self) -> I {
+        self.vm.into_io()
+    }
+}
@@ -1,6 +1,6 @@
 use std::fmt;
 
-use crate::{compiler::Compiler, io::{MemoryIO, IO}};
+use crate::{compiler::Compiler, io::{MemoryIO, IO}, scan};
 
 #[derive(Debug, Clone, Copy)]
 pub enum Op {
@@ -14,6 +14,10 @@ pub enum Op {
     JmpIfZ(u16),
     /// Jump to the matching left brace if the cell is not zero
     JmpIfNZ(u16),
+    /// Move right by the stride until the cell is zero, the loop [>]
+    ScanR(u8),
+    /// Move left by the stride until the cell is zero, the loop [<]
+    ScanL(u8),
     Print,
     Read,
 }
@@ -28,28 +32,33 @@ impl fmt::Display for Op {
             Op::MovL(num) => write!(f, "MovL by {num}"),
             Op::JmpIfZ(index) => write!(f, "JmpIfZ to {index}"),
             Op::JmpIfNZ(index) => write!(f, "JmpIfNZ to {index}"),
+            Op::ScanR(num) => write!(f, "ScanR by {num}"),
+            Op::ScanL(num) => write!(f, "ScanL by {num}"),
             Op::Print => write!(f, "Print"),
             Op::Read => write!(f, "Read"),
         }
     }
 }
 
-pub struct Vm<'a> {
-    program: Vec<Op>,
+/// Cells on the tape of a new Vm, it grows to the right as needed
+pub const INITIAL_TAPE_CELLS: usize = 1024;
+
+pub struct Vm<I: IO> {
+    pub(crate) program: Vec<Op>,
     // NO WRAP AROUND -> abort on move past end of tape
-    tape: Vec<u8>,
+    pub(crate) tape: Vec<u8>,
     // Program counter
-    pc: usize,
+    pub(crate) pc: usize,
     // Tape Pointer
-    tp: usize,
-    io: Box<dyn IO<'a> + 'a>,
+    pub(crate) tp: usize,
+    pub(crate) io: I,
 }
 
-impl<'a> Vm<'a> {
-    pub fn new(io: Box<dyn IO<'a> + 'a>, program: Vec<Op>) -> Self {
+impl<I: IO> Vm<I> {
+    pub fn new(io: I, program: Vec<Op>) -> Self {
         Self {
             program,
-            tape: vec![0; 1024],
+            tape: vec![0; INITIAL_TAPE_CELLS],
             pc: 0,
             tp: 0,
             io,
@@ -59,66 +68,134 @@ impl<'a> Vm<'a> {
     pub fn run(&mut self) {
         //println!("Running program: {:?}", self.program);
 
-        loop {
-            let instruction = self.program[self.pc];
-            match instruction {
-                Op::Inc(num) => self.tape[self.tp] = self.tape[self.tp].wrapping_add(num),
-                Op::Dec(num) => self.tape[self.tp] = self.tape[self.tp].wrapping_sub(num),
-                Op::MovR(num) => {
-                    let shift = num as usize;
-                    self.tp += shift;
-                    // grow tape on the right as needed
-                    while self.tp >= self.tape.len() {
-                        self.tape.push(0);
-                    }
-                }
-                Op::MovL(num) => {
-                    let shift = num as usize;
-                    if self.tp > 0 {
-                        self.tp -= shift;
-                    } else {
-                        panic!("Tape pointer underflow: attempted to move left {} from position {}", shift, self.tp);
-                    }
+        while self.step() {}
+    }
+
+    /// Like run, but tells the IO how many steps ran before every read and write
+    pub fn run_timed(&mut self) {
+        let mut steps = 0;
+        while !self.is_finished() {
+            if let Op::Print | Op::Read = self.program[self.pc] {
+                self.io.set_step(steps);
+            }
+            self.step();
+            steps += 1;
+        }
+    }
+
+    /// Execute a single instruction, returns false once the program has finished
+    #[inline(always)]
+    pub fn step(&mut self) -> bool {
+        if self.pc >= self.program.len() {
+            return false;
+        }
+
+        let instruction = self.program[self.pc];
+        match instruction {
+            Op::Inc(num) => self.tape[self.tp] = self.tape[self.tp].wrapping_add(num),
+            Op::Dec(num) => self.tape[self.tp] = self.tape[self.tp].wrapping_sub(num),
+            Op::MovR(num) => {
+                let shift = num as usize;
+                self.tp += shift;
+                // grow tape on the right as needed
+                while self.tp >= self.tape.len() {
+                    self.tape.push(0);
                 }
-                Op::Print => self.io.write_byte(self.tape[self.tp]),
-                Op::Read => {
-                    self.tape[self.tp] = self.io.read_byte();
+            }
+            Op::MovL(num) => {
+                let shift = num as usize;
+                if self.tp > 0 {
+                    self.tp -= shift;
+                } else {
+                    panic!("Tape pointer underflow: attempted to move left {} from position {}", shift, self.tp);
                 }
-                Op::JmpIfZ(jmp_index) => {
-                    if self.tape[self.tp] == 0 {
-                        self.pc = jmp_index as usize;
-                    }
+            }
+            Op::ScanR(num) => {
+                // Cells past the end are zero once the tape grows, so stop at the first one
+                let stride = num as usize;
+                self.tp = scan::find_zero(&self.tape, self.tp, stride)
+                    .unwrap_or_else(|| self.tp + (self.tape.len() - self.tp).div_ceil(stride) * stride);
+                while self.tp >= self.tape.len() {
+                    self.tape.push(0);
                 }
-                Op::JmpIfNZ(jmp_index) => {
-                    if self.tape[self.tp] != 0 {
-                        self.pc = jmp_index as usize;
-                    }
+            }
+            Op::ScanL(num) => {
+                self.tp = scan::rfind_zero(&self.tape, self.tp, num as usize).unwrap_or_else(|| {
+                    panic!("Tape pointer underflow: scanning left by {} from position {} found no zero cell", num, self.tp)
+                });
+            }
+            Op::Print => self.io.write_byte(self.tape[self.tp]),
+            Op::Read => {
+                self.tape[self.tp] = self.io.read_byte();
+            }
+            Op::JmpIfZ(jmp_index) => {
+                if self.tape[self.tp] == 0 {
+                    self.pc = jmp_index as usize;
                 }
-                Op::Nop => (),
             }
-            self.pc += 1;
-            if self.pc >= self.program.len() {
-                break;
+            Op::JmpIfNZ(jmp_index) => {
+                if self.tape[self.tp] != 0 {
+                    self.pc = jmp_index as usize;
+                }
             }
+            Op::Nop => (),
         }
+        self.pc += 1;
+        self.pc < self.program.len()
     }
 
+    /// Index of the next instruction to execute
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// Current tape pointer
+    pub fn tp(&self) -> usize {
+        self.tp
+    }
+
+    pub fn tape(&self) -> &[u8] {
+        &self.tape
+    }
+
+    pub fn program(&self) -> &[Op] {
+        &self.program
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.pc >= self.program.len()
+    }
+
+    /// Replace the program and start over at its first instruction, keeping tape and pointer
+    pub fn load(&mut self, program: Vec<Op>) {
+        self.program = program;
+        self.pc = 0;
+    }
+
+    /// Clear the tape and move the pointer back to the first cell
+    pub fn reset(&mut self) {
+        self.tape = vec![0; INITIAL_TAPE_CELLS];
+        self.tp = 0;
+        self.pc = 0;
+    }
 
     pub fn flush_io(&mut self) {
         self.io.flush();
     }
+
+    pub fn io(&self) -> &I {
+        &self.io
+    }
+
+    /// Consume the vm and return its IO, e.g. to collect the output of a MemoryIO
+    pub fn into_io(self) -> I {
+        self.io
+    }
 }
 
 pub fn bench_run(program: &str, input: Vec<u8>) -> Vec<u8> {
     let code = Compiler::new(program).compile();
-    let mut output_buffer = Vec::with_capacity(1024);
-    let input_buffer = input;
-    
-    {
-        let io = Box::new(MemoryIO::new(&mut output_buffer, input_buffer));
-        let mut vm = Vm::new(io, code);
-        vm.run();
-    } // vm and io are dropped here, releasing the borrow on output_buffer
-    
-    output_buffer
+    let mut vm = Vm::new(MemoryIO::new(input), code);
+    vm.run();
+    vm.into_io().into_output()
 }
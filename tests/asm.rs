@@ -0,0 +1,64 @@
+use brainv::asm::{self, Target};
+use brainv::compiler::Compiler;
+
+fn emit(program: &str, target: Target) -> String {
+    let (code, offsets) = Compiler::new(program).compile_with_offsets();
+    asm::emit(program, &code, &offsets, target)
+}
+
+#[test]
+fn loops_are_labelled_after_their_bracket() {
+    for target in [Target::X86_64, Target::Aarch64] {
+        let asm = emit("++[->+<]>[-]", target);
+        for label in ["loop_2_start:", "loop_2_end:", "loop_9_start:", "loop_9_end:"] {
+            assert!(asm.lines().any(|line| line == label), "{label} missing from\n{asm}");
+        }
+        assert!(asm.contains("loop_2_end\n"));
+        assert!(asm.contains("loop_9_start\n"));
+    }
+}
+
+#[test]
+fn ops_are_commented_with_their_source() {
+    let asm = emit("++ add two\n[->+<] move it\n.", Target::X86_64);
+    let comments: Vec<&str> = asm.lines().filter_map(|line| line.trim().strip_prefix("# ")).collect();
+    assert_eq!(comments[1..], ["++", "[", "-", ">", "+", "<", "]", "."]);
+
+    let asm = emit(&"+".repeat(100), Target::Aarch64);
+    assert!(asm.contains(&format!("// {}...\n", "+".repeat(32))));
+}
+
+#[test]
+fn main_sets_up_the_tape() {
+    for target in [Target::X86_64, Target::Aarch64] {
+        let asm = emit("+.", target);
+        assert!(asm.contains(".globl main\n"));
+        assert!(asm.contains("tape:\n    .zero 30000\n"));
+        assert!(asm.contains(".section .note.GNU-stack"));
+    }
+}
+
+/// Assemble and run the x86_64 output with the system toolchain, if there is one
+#[test]
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+fn assembled_programs_run_like_the_vm() {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    let program = include_str!("../benches/bf/primes.bf");
+    let dir = std::env::temp_dir().join(format!("brainv-asm-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (source, binary) = (dir.join("primes.s"), dir.join("primes"));
+    std::fs::write(&source, emit(program, Target::X86_64)).unwrap();
+    let Ok(status) = Command::new("cc").arg(&source).arg("-o").arg(&binary).status() else {
+        eprintln!("No cc to assemble with, skipping");
+        return;
+    };
+    assert!(status.success());
+
+    let mut child = Command::new(&binary).stdin(Stdio::piped()).stdout(Stdio::piped()).spawn().unwrap();
+    child.stdin.take().unwrap().write_all(b"30\n").unwrap();
+    let output = child.wait_with_output().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(output.stdout, brainv::vm::bench_run(program, b"30\n".to_vec()));
+}
@@ -0,0 +1,94 @@
+use brainv::bytecode::{Bytecode, MAX_OPS, crc32};
+use brainv::compiler::Compiler;
+use brainv::io::MemoryIO;
+use brainv::vm::{Op, Vm, bench_run};
+
+fn bytecode(program: &str) -> Bytecode {
+    let (code, offsets) = Compiler::new(program).compile_with_offsets();
+    Bytecode::new(code, offsets).unwrap()
+}
+
+/// Change the content and fix up the checksum, to get past it to the other checks
+fn patch(bytes: &mut Vec<u8>, at: usize, value: &[u8]) {
+    bytes[at..at + value.len()].copy_from_slice(value);
+    bytes.truncate(bytes.len() - 4);
+    let checksum = crc32(bytes);
+    bytes.extend_from_slice(&checksum.to_le_bytes());
+}
+
+#[test]
+fn loaded_programs_run_like_the_source() {
+    let program = include_str!("../benches/bf/primes.bf");
+    let original = bytecode(program);
+    let bytes = original.to_bytes();
+    assert!(Bytecode::is_bytecode(&bytes));
+
+    let loaded = Bytecode::from_bytes(&bytes).unwrap();
+    assert_eq!(loaded.offsets, original.offsets);
+    assert_eq!(format!("{:?}", loaded.code), format!("{:?}", original.code));
+
+    let mut vm = Vm::new(MemoryIO::new(b"30\n".to_vec()), loaded.code);
+    vm.run();
+    assert_eq!(vm.into_io().into_output(), bench_run(program, b"30\n".to_vec()));
+}
+
+#[test]
+fn source_is_not_bytecode() {
+    assert!(!Bytecode::is_bytecode(b"+[>+]"));
+    assert!(Bytecode::from_bytes(b"+[>+]").is_err());
+}
+
+#[test]
+fn damaged_files_are_rejected() {
+    let bytes = bytecode("++[->+<]>.").to_bytes();
+
+    let mut flipped = bytes.clone();
+    flipped[20] ^= 1;
+    assert!(Bytecode::from_bytes(&flipped).unwrap_err().contains("checksum"));
+
+    assert!(Bytecode::from_bytes(&bytes[..bytes.len() - 5]).is_err());
+    assert!(Bytecode::from_bytes(&bytes[..10]).unwrap_err().contains("truncated"));
+}
+
+#[test]
+fn incompatible_files_are_rejected() {
+    let bytes = bytecode("++[->+<]>.").to_bytes();
+
+    let mut version = bytes.clone();
+    patch(&mut version, 4, &2u16.to_le_bytes());
+    assert!(Bytecode::from_bytes(&version).unwrap_err().contains("version 2"));
+
+    let mut cells = bytes.clone();
+    patch(&mut cells, 6, &[16]);
+    assert!(Bytecode::from_bytes(&cells).unwrap_err().contains("16-bit"));
+
+    let mut tape = bytes.clone();
+    patch(&mut tape, 8, &1000u32.to_le_bytes());
+    assert!(Bytecode::from_bytes(&tape).unwrap_err().contains("1000 cells"));
+}
+
+#[test]
+fn loops_have_to_match() {
+    let mut program = bytecode("++[->+<]>.");
+    let start = program.code.iter().position(|op| matches!(op, Op::JmpIfZ(_))).unwrap();
+    program.code[start] = Op::JmpIfZ(0);
+    let err = Bytecode::from_bytes(&program.to_bytes()).unwrap_err();
+    assert!(err.contains(&format!("index {start}")), "{err}");
+}
+
+#[test]
+fn programs_too_long_for_the_format_are_rejected() {
+    // Every op is a Print, so none of them needs a jump target
+    let longest = vec![Op::Print; MAX_OPS];
+    let bytes = Bytecode::new(longest, vec![0; MAX_OPS]).unwrap().to_bytes();
+    assert_eq!(Bytecode::from_bytes(&bytes).unwrap().code.len(), MAX_OPS);
+
+    let err = Bytecode::new(vec![Op::Print; MAX_OPS + 1], vec![0; MAX_OPS + 1]).unwrap_err();
+    assert_eq!(err, format!("the program has {} ops, bytecode files hold at most {MAX_OPS}", MAX_OPS + 1));
+}
+
+#[test]
+fn unmatched_jumps_are_not_saved() {
+    let code = vec![Op::JmpIfZ(1), Op::Print];
+    assert_eq!(Bytecode::new(code, vec![0, 1]).unwrap_err(), "invalid JmpIfZ to 1 at index 0");
+}
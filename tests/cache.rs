@@ -0,0 +1,107 @@
+use std::fs;
+use std::path::PathBuf;
+
+use brainv::cache::{CacheKey, CacheStats, CodeCache};
+use brainv::compiler::Compiler;
+use brainv::io::MemoryIO;
+use brainv::jit::{JIT, Symbol};
+use brainv::runtime::Runtime;
+
+/// An empty cache directory of its own for every test
+fn temp_cache(name: &str) -> CodeCache {
+    let dir = std::env::temp_dir().join(format!("brainv-cache-{}-{name}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    CodeCache::new(dir)
+}
+
+fn entry_files(cache: &CodeCache) -> Vec<PathBuf> {
+    fs::read_dir(cache.dir()).unwrap().map(|entry| entry.unwrap().path()).collect()
+}
+
+fn symbols() -> Vec<Symbol> {
+    vec![
+        Symbol { name: "bf_program".to_string(), offset: 0, len: 8 },
+        Symbol { name: "bf_loop_2_6".to_string(), offset: 8, len: 8 },
+    ]
+}
+
+#[test]
+fn stored_code_loads_back() {
+    let cache = temp_cache("roundtrip");
+    let key = CacheKey::new(b"+[-]", "jit", "");
+    assert!(cache.load(&key).is_none());
+
+    cache.store(&key, &[1, 2, 3, 4], &symbols()).unwrap();
+    let cached = cache.load(&key).unwrap();
+    assert_eq!(cached.code, vec![1, 2, 3, 4]);
+    assert_eq!(cached.symbols, symbols());
+    assert_eq!(cache.stats().unwrap(), CacheStats { entries: 1, bytes: fs::metadata(&entry_files(&cache)[0]).unwrap().len(), stale: 0 });
+
+    assert_eq!(cache.clear().unwrap(), 1);
+    assert!(cache.load(&key).is_none());
+    assert_eq!(cache.stats().unwrap(), CacheStats::default());
+}
+
+#[test]
+fn keys_cover_program_backend_and_options() {
+    let cache = temp_cache("keys");
+    cache.store(&CacheKey::new(b"+[-]", "jit", ""), &[1], &[]).unwrap();
+    assert!(cache.load(&CacheKey::new(b"+[-]>", "jit", "")).is_none());
+    assert!(cache.load(&CacheKey::new(b"+[-]", "cranelift", "")).is_none());
+    assert!(cache.load(&CacheKey::new(b"+[-]", "jit", "metered")).is_none());
+    assert!(cache.load(&CacheKey::new(b"+[-]", "jit", "")).is_some());
+    cache.clear().unwrap();
+}
+
+#[test]
+fn entries_for_another_program_with_the_same_name_are_deleted() {
+    let cache = temp_cache("collision");
+    let key = CacheKey::new(b"+[-]", "jit", "");
+    cache.store(&key, &[1], &[]).unwrap();
+    let path = entry_files(&cache).remove(0);
+
+    // Put the entry of another program where this key's entry goes, as a hash collision would
+    let other = CacheKey::new(b"-[+]", "jit", "");
+    cache.store(&other, &[2], &[]).unwrap();
+    let other_path = entry_files(&cache).into_iter().find(|entry| *entry != path).unwrap();
+    fs::rename(other_path, &path).unwrap();
+
+    assert!(cache.load(&key).is_none());
+    assert!(entry_files(&cache).is_empty());
+}
+
+#[test]
+fn damaged_entries_are_deleted() {
+    let cache = temp_cache("damaged");
+    let key = CacheKey::new(b"+[-]", "jit", "");
+    cache.store(&key, &[1, 2, 3, 4], &symbols()).unwrap();
+
+    let path = entry_files(&cache).remove(0);
+    let mut bytes = fs::read(&path).unwrap();
+    let last = bytes.len() - 5;
+    bytes[last] ^= 1;
+    fs::write(&path, bytes).unwrap();
+    assert_eq!(cache.stats().unwrap().stale, 1);
+
+    assert!(cache.load(&key).is_none());
+    assert!(entry_files(&cache).is_empty());
+}
+
+#[test]
+fn runtime_compiles_once() {
+    let cache = temp_cache("runtime");
+    let jit = JIT::new(Compiler::new("++[->+<]>.").compile());
+    let key = CacheKey::new(b"++[->+<]>.", "jit", "");
+    let (code, symbols) = jit.compile_with_symbols().unwrap();
+
+    Runtime::cached(MemoryIO::new(vec![]), &jit, &cache, &key).unwrap();
+    let cached = cache.load(&key).unwrap();
+    assert_eq!(cached.code, code);
+    assert_eq!(cached.symbols, symbols);
+
+    // A hit takes the cached code as it is, even when the JIT would generate something else now
+    cache.store(&key, &[0xd6, 0x5f, 0x03, 0xc0], &[]).unwrap();
+    Runtime::cached(MemoryIO::new(vec![]), &jit, &cache, &key).unwrap();
+    assert_eq!(cache.load(&key).unwrap().code, vec![0xd6, 0x5f, 0x03, 0xc0]);
+    cache.clear().unwrap();
+}
@@ -0,0 +1,86 @@
+#![cfg(feature = "cranelift")]
+
+use brainv::compiler::Compiler;
+use brainv::cranelift::CraneliftJIT;
+use brainv::io::MemoryIO;
+use brainv::runtime::{RunError, RunSummary, Status};
+use brainv::vm::bench_run;
+
+fn run(program: &str, input: &[u8]) -> (Vec<u8>, Vec<u8>, Result<RunSummary, RunError>) {
+    let code = CraneliftJIT::new(Compiler::new(program).compile()).compile().unwrap();
+    let mut io = MemoryIO::new(input.to_vec());
+    let mut tape = vec![0; 30000];
+    let result = code.call(&mut io, &mut tape, 0);
+    (io.into_output(), tape, result)
+}
+
+fn assert_matches_vm(program: &str, input: &[u8]) {
+    let expected = bench_run(program, input.to_vec());
+    assert!(!expected.is_empty());
+    let (output, _, result) = run(program, input);
+    result.unwrap();
+    assert_eq!(output, expected);
+}
+
+#[test]
+fn programs_match_the_vm() {
+    assert_matches_vm(
+        "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.",
+        b"",
+    );
+    assert_matches_vm(",[.,]", b"echo\0");
+    assert_matches_vm("-.+.>+[+]+.", b"");
+    assert_matches_vm(include_str!("../benches/bf/primes.bf"), b"30\n");
+    assert_matches_vm(include_str!("../benches/bf/pi-digits.bf"), b"10\n");
+}
+
+#[test]
+fn output_past_the_buffer_is_flushed() {
+    let program = format!("++[{}-]", ".".repeat(5_000));
+    let (output, _, result) = run(&program, b"");
+    result.unwrap();
+    assert_eq!(output, vec![2; 5_000].into_iter().chain(vec![1; 5_000]).collect::<Vec<_>>());
+}
+
+#[test]
+fn summary_has_the_final_tape_offset() {
+    let (_, tape, result) = run("+++>++<[->+<]>>>+[>]<", b"");
+    assert_eq!(result.unwrap(), RunSummary { status: Status::Ok, tape_offset: 3, steps: None, fault_op: None });
+    assert_eq!(&tape[..4], &[0, 5, 0, 1]);
+}
+
+#[test]
+fn moving_off_the_tape_is_a_fault() {
+    let (_, _, result) = run("+<", b"");
+    let err = result.unwrap_err();
+    assert_eq!(err.summary.status, Status::TapeFault);
+    assert_eq!(err.summary.tape_offset, -1);
+
+    let (_, _, result) = run("+[>+]", b"");
+    assert_eq!(result.unwrap_err().summary.tape_offset, 30000);
+
+    let (_, _, result) = run(">>+<+<+[<<]", b"");
+    let err = result.unwrap_err();
+    assert_eq!(err.summary.status, Status::TapeFault);
+    assert!(err.summary.tape_offset < 0);
+}
+
+#[test]
+fn reading_past_the_input_is_eof() {
+    let (output, tape, result) = run("+++.,", b"");
+    assert_eq!(result.unwrap_err().summary.status, Status::Eof);
+    assert_eq!(output, vec![3]);
+    assert_eq!(tape[0], 3);
+}
+
+#[test]
+fn output_before_a_fault_is_kept() {
+    let (output, _, result) = run("++++++++[>++++++++<-]>+.<<", b"");
+    assert_eq!(result.unwrap_err().summary.status, Status::TapeFault);
+    assert_eq!(output, b"A");
+
+    // Faults in a scan too
+    let (output, _, result) = run("+++++++[>+++++++++<-]>++.<+>[<]", b"");
+    assert_eq!(result.unwrap_err().summary.status, Status::TapeFault);
+    assert_eq!(output, b"A");
+}
@@ -0,0 +1,100 @@
+use brainv::compiler::Compiler;
+use brainv::debugger::{CellWrite, Debugger, StopReason};
+use brainv::io::MemoryIO;
+use brainv::vm::Vm;
+
+fn debugger(program: &str, input: &[u8]) -> Debugger<MemoryIO> {
+    Debugger::new(Vm::new(MemoryIO::new(input.to_vec()), Compiler::new(program).compile()))
+}
+
+fn run_to_end(debugger: &mut Debugger<MemoryIO>) {
+    while debugger.step() {}
+}
+
+#[test]
+fn reverse_steps_restore_the_tape_and_pointer() {
+    let mut debugger = debugger("++>+++<-", b"");
+    run_to_end(&mut debugger);
+    assert_eq!(&debugger.vm().tape()[..2], [1, 3]);
+    assert_eq!(debugger.vm().tp(), 0);
+
+    let steps = debugger.steps();
+    assert!(debugger.reverse_step());
+    assert_eq!(&debugger.vm().tape()[..2], [2, 3]);
+    assert!(debugger.reverse_step());
+    assert_eq!(debugger.vm().tp(), 1);
+    while debugger.reverse_step() {}
+    assert_eq!(&debugger.vm().tape()[..2], [0, 0]);
+    assert_eq!((debugger.vm().pc(), debugger.vm().tp()), (0, 0));
+
+    // Stepping forward again ends up in the same place
+    run_to_end(&mut debugger);
+    assert_eq!(debugger.steps(), steps);
+    assert_eq!(&debugger.vm().tape()[..2], [1, 3]);
+}
+
+#[test]
+fn rewound_input_is_read_again() {
+    // Only two bytes of input, so reading a third one would panic
+    let mut debugger = debugger(",>,", b"ab");
+    run_to_end(&mut debugger);
+    while debugger.reverse_step() {}
+    assert_eq!(&debugger.vm().tape()[..2], [0, 0]);
+
+    run_to_end(&mut debugger);
+    assert_eq!(&debugger.vm().tape()[..2], b"ab");
+}
+
+#[test]
+fn rewound_output_is_not_printed_twice() {
+    let mut debugger = debugger("+++.+.", b"");
+    run_to_end(&mut debugger);
+    debugger.reverse_step();
+    debugger.reverse_step();
+    debugger.reverse_step();
+    run_to_end(&mut debugger);
+    assert_eq!(debugger.into_vm().into_io().into_output(), [3, 4]);
+}
+
+#[test]
+fn continue_stops_at_breakpoints() {
+    let code = Compiler::new("++[->+<]").compile();
+    let end = code.len() - 1;
+    let mut debugger = Debugger::new(Vm::new(MemoryIO::new(vec![]), code));
+    debugger.add_breakpoint(end);
+    assert_eq!(debugger.continue_forward(), StopReason::Breakpoint(end));
+    assert_eq!(debugger.continue_forward(), StopReason::Breakpoint(end));
+    assert_eq!(&debugger.vm().tape()[..2], [0, 2]);
+
+    assert_eq!(debugger.continue_reverse(), StopReason::Breakpoint(end));
+    assert_eq!(&debugger.vm().tape()[..2], [1, 1]);
+    assert!(debugger.remove_breakpoint(end));
+    assert_eq!(debugger.continue_reverse(), StopReason::End);
+    assert_eq!(debugger.steps(), 0);
+    assert_eq!(debugger.continue_forward(), StopReason::End);
+}
+
+#[test]
+fn last_write_finds_the_step() {
+    let mut debugger = debugger("+>,<+", b"x");
+    run_to_end(&mut debugger);
+    // Nop, Inc, MovR, Read, MovL, Inc
+    assert_eq!(debugger.last_write(0), Some(CellWrite { step: 5, pc: 5, old: 1 }));
+    assert_eq!(debugger.last_write(1), Some(CellWrite { step: 3, pc: 3, old: 0 }));
+    assert_eq!(debugger.last_write(2), None);
+}
+
+#[test]
+fn history_is_capped() {
+    let mut debugger = debugger("+>+>+>+", b"");
+    debugger.set_history_limit(3);
+    run_to_end(&mut debugger);
+    assert_eq!(debugger.steps(), 3);
+    // Steps keep their number after older ones were dropped
+    assert_eq!(debugger.last_write(3), Some(CellWrite { step: 7, pc: 7, old: 0 }));
+    assert_eq!(debugger.last_write(0), None);
+
+    while debugger.reverse_step() {}
+    assert_eq!(debugger.vm().tp(), 2);
+    assert_eq!(&debugger.vm().tape()[..4], [1, 1, 0, 0]);
+}
@@ -0,0 +1,58 @@
+use brainv::compiler::Compiler;
+use brainv::debuginfo::{self, EM_AARCH64};
+use brainv::jit::{JIT, Symbol};
+
+fn symbol(name: &str, offset: usize, len: usize) -> Symbol {
+    Symbol { name: name.to_string(), offset, len }
+}
+
+#[test]
+fn symbols_are_named_after_source_offsets() {
+    let (code, offsets) = Compiler::new("+[->+<]>[-].").compile_with_offsets();
+    let mut jit = JIT::new(code);
+    jit.set_source_offsets(offsets);
+    let (machine_code, symbols) = jit.compile_with_symbols().unwrap();
+
+    let names: Vec<&str> = symbols.iter().map(|symbol| symbol.name.as_str()).collect();
+    assert_eq!(names, ["bf_program", "bf_loop_1_6", "bf_program", "bf_loop_8_10", "bf_program"]);
+    // Together they cover the code exactly once
+    let mut offset = 0;
+    for symbol in &symbols {
+        assert_eq!(symbol.offset, offset);
+        assert!(symbol.len > 0);
+        offset += symbol.len;
+    }
+    assert_eq!(offset, machine_code.len());
+}
+
+#[test]
+fn scans_keep_their_source_offset() {
+    let (code, offsets) = Compiler::new("+>+[<]+").compile_with_offsets();
+    assert_eq!(code.len(), offsets.len());
+    // Nop, Inc, MovR, Inc, ScanL, Inc
+    assert_eq!(offsets, [0, 0, 1, 2, 3, 6]);
+}
+
+#[test]
+fn perf_map_gets_a_line_per_symbol() {
+    let symbols = [symbol("bf_perf_map_test", 0, 0x40), symbol("bf_loop_perf_map_test", 0x40, 0x10)];
+    debuginfo::write_perf_map(0xabc000, &symbols).unwrap();
+    let path = format!("/tmp/perf-{}.map", std::process::id());
+    let map = std::fs::read_to_string(&path);
+    // Remove the map before checking it, so a failing check doesn't leave it behind
+    let _ = std::fs::remove_file(&path);
+    let map = map.unwrap();
+    assert!(map.lines().any(|line| line == "abc000 40 bf_perf_map_test"));
+    assert!(map.lines().any(|line| line == "abc040 10 bf_loop_perf_map_test"));
+}
+
+#[test]
+fn symbol_file_is_an_elf_object() {
+    let elf = debuginfo::symbol_file(EM_AARCH64, 0x1000, 0x80, &[symbol("bf_program", 0, 0x80)]);
+    assert_eq!(&elf[..6], b"\x7fELF\x02\x01");
+    assert_eq!(u16::from_le_bytes([elf[18], elf[19]]), EM_AARCH64);
+    // Section headers at e_shoff, 5 of them
+    let shoff = u64::from_le_bytes(elf[40..48].try_into().unwrap()) as usize;
+    assert_eq!(elf.len(), shoff + 5 * 64);
+    assert!(elf.windows(11).any(|name| name == b"bf_program\0"));
+}
@@ -0,0 +1,312 @@
+use brainv::compiler::Compiler;
+use brainv::emulator::Emulator;
+use brainv::io::{IO, MemoryIO};
+use brainv::jit::JIT;
+use brainv::runtime::{RunError, RunSummary, Status};
+use brainv::vm::{Op, Vm, bench_run};
+
+fn emulate(program: &str, input: &[u8]) -> Vec<u8> {
+    let code = JIT::new(Compiler::new(program).compile()).compile().unwrap();
+    let mut emulator = Emulator::new(MemoryIO::new(input.to_vec()), code);
+    emulator.run().unwrap();
+    emulator.result().unwrap();
+    emulator.into_io().into_output()
+}
+
+/// Run metered code with the given fuel and return how it ended
+fn run_metered(program: &str, input: &[u8], fuel: u64) -> Result<RunSummary, RunError> {
+    let code = JIT::metered(Compiler::new(program).compile()).compile().unwrap();
+    let mut emulator = Emulator::new(MemoryIO::new(input.to_vec()), code);
+    emulator.set_fuel(fuel);
+    emulator.run().unwrap();
+    emulator.result()
+}
+
+fn vm_steps(program: &str, input: &[u8]) -> u64 {
+    let mut vm = Vm::new(MemoryIO::new(input.to_vec()), Compiler::new(program).compile());
+    let mut steps = 0;
+    while !vm.is_finished() {
+        vm.step();
+        steps += 1;
+    }
+    steps
+}
+
+fn assert_matches_vm(program: &str, input: &[u8]) {
+    let expected = bench_run(program, input.to_vec());
+    assert!(!expected.is_empty());
+    assert_eq!(emulate(program, input), expected);
+}
+
+#[test]
+fn hello_world() {
+    assert_matches_vm(
+        "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.",
+        b"",
+    );
+}
+
+#[test]
+fn echo_input() {
+    assert_matches_vm(",[.,]", b"echo\0");
+}
+
+#[test]
+fn cells_wrap() {
+    assert_matches_vm("-.+.>+[+]+.", b"");
+}
+
+#[test]
+fn serptri() {
+    assert_matches_vm(include_str!("../benches/bf/serptri.bf"), b"");
+}
+
+#[test]
+fn primes() {
+    assert_matches_vm(include_str!("../benches/bf/primes.bf"), b"30\n");
+}
+
+#[test]
+fn pi_digits() {
+    assert_matches_vm(include_str!("../benches/bf/pi-digits.bf"), b"10\n");
+}
+
+#[test]
+fn relaxed_loop() {
+    let program = format!("++[{}-]", ".".repeat(100_000));
+    assert_eq!(emulate(&program, b""), vec![2; 100_000].into_iter().chain(vec![1; 100_000]).collect::<Vec<_>>());
+}
+
+#[test]
+fn tape_is_left_as_the_program_wrote_it() {
+    let code = JIT::new(Compiler::new("+++>++<[->+<]").compile()).compile().unwrap();
+    let mut emulator = Emulator::new(MemoryIO::new(vec![]), code);
+    emulator.run().unwrap();
+    assert_eq!(&emulator.tape()[..3], &[0, 5, 0]);
+    assert!(emulator.steps() > 0);
+}
+
+#[test]
+fn summary_has_the_final_tape_offset() {
+    let code = JIT::new(Compiler::new(">>>+[>]<").compile()).compile().unwrap();
+    let mut emulator = Emulator::new(MemoryIO::new(vec![]), code);
+    emulator.run().unwrap();
+    let summary = emulator.result().unwrap();
+    assert_eq!(summary, RunSummary { status: Status::Ok, tape_offset: 3, steps: None, fault_op: None });
+}
+
+#[test]
+fn metered_steps_match_the_vm() {
+    for (program, input) in [("+++[->++<]>.", &b""[..]), (include_str!("../benches/bf/primes.bf"), b"30\n")] {
+        let summary = run_metered(program, input, u64::MAX).unwrap();
+        assert_eq!(summary.steps, Some(vm_steps(program, input)));
+    }
+}
+
+#[test]
+fn infinite_loops_run_out_of_fuel() {
+    let err = run_metered("+[]", b"", 1000).unwrap_err();
+    assert_eq!(err.summary.status, Status::OutOfFuel);
+    assert!(err.summary.steps.unwrap() > 1000);
+}
+
+#[test]
+fn moving_off_the_tape_is_a_fault() {
+    let err = run_metered("+<", b"", u64::MAX).unwrap_err();
+    assert_eq!(err.summary.status, Status::TapeFault);
+    assert_eq!(err.summary.tape_offset, -1);
+
+    let err = run_metered("+[>+]", b"", u64::MAX).unwrap_err();
+    assert_eq!(err.summary.status, Status::TapeFault);
+    assert_eq!(err.summary.tape_offset, 30000);
+}
+
+/// Fails every read by panicking, with a message that mentions EOF
+struct FailingIO;
+
+impl IO for FailingIO {
+    fn write_byte(&mut self, _: u8) {}
+
+    fn try_read_byte(&mut self) -> Option<u8> {
+        panic!("device gone (EOF)");
+    }
+
+    fn flush(&mut self) {}
+}
+
+#[test]
+fn panicking_reads_are_io_errors() {
+    let code = JIT::new(Compiler::new(",").compile()).compile().unwrap();
+    let mut emulator = Emulator::new(FailingIO, code);
+    emulator.run().unwrap();
+    let err = emulator.result().unwrap_err();
+    // Only the end of the input is EOF, whatever the panic says
+    assert_eq!(err.summary.status, Status::IoError);
+    assert_eq!(err.message.as_deref(), Some("device gone (EOF)"));
+}
+
+#[test]
+fn output_before_a_fault_is_kept() {
+    let code = JIT::new(Compiler::new("++++++++[>++++++++<-]>+.<<").compile()).compile().unwrap();
+    let mut emulator = Emulator::new(MemoryIO::new(vec![]), code);
+    emulator.run().unwrap();
+    assert_eq!(emulator.result().unwrap_err().summary.status, Status::TapeFault);
+    assert_eq!(emulator.into_io().into_output(), b"A");
+
+    let code = JIT::metered(Compiler::new("++++++++[>++++++++<-]>+.+[]").compile()).compile().unwrap();
+    let mut emulator = Emulator::new(MemoryIO::new(vec![]), code);
+    emulator.set_fuel(1000);
+    emulator.run().unwrap();
+    assert_eq!(emulator.result().unwrap_err().summary.status, Status::OutOfFuel);
+    assert_eq!(emulator.into_io().into_output(), b"A");
+}
+
+/// Run the program on a 16-cell tape filled with the given value and return the
+/// op its tape fault reported
+fn fault_op(program: &str, fill: u8, report: bool) -> Option<usize> {
+    let mut jit = JIT::new(Compiler::new(program).compile());
+    jit.set_fault_ops(report);
+    let mut emulator = Emulator::with_tape(MemoryIO::new(vec![]), jit.compile().unwrap(), vec![fill; 16], 0);
+    emulator.run().unwrap();
+    let err = emulator.result().unwrap_err();
+    assert_eq!(err.summary.status, Status::TapeFault);
+    err.summary.fault_op
+}
+
+/// Index of the last op the predicate holds for
+fn last_op(program: &str, predicate: fn(&Op) -> bool) -> Option<usize> {
+    Compiler::new(program).compile().iter().rposition(predicate)
+}
+
+#[test]
+fn tape_faults_report_the_op() {
+    let program = "+>>+<<<";
+    assert_eq!(fault_op(program, 0, true), last_op(program, |op| matches!(op, Op::MovL(_))));
+    assert_eq!(fault_op(program, 0, false), None);
+
+    let program = "+[>>>>>+]";
+    assert_eq!(fault_op(program, 0, true), last_op(program, |op| matches!(op, Op::MovR(_))));
+
+    // Scans run off either end of a tape without a zero cell
+    let program = ">>[>]";
+    assert_eq!(fault_op(program, 1, true), last_op(program, |op| matches!(op, Op::ScanR(_))));
+    let program = ">>[<<]";
+    assert_eq!(fault_op(program, 1, true), last_op(program, |op| matches!(op, Op::ScanL(_))));
+
+    // Running out of fuel is not a tape fault
+    let mut jit = JIT::metered(Compiler::new("+[]").compile());
+    jit.set_fault_ops(true);
+    let mut emulator = Emulator::new(MemoryIO::new(vec![]), jit.compile().unwrap());
+    emulator.set_fuel(100);
+    emulator.run().unwrap();
+    let err = emulator.result().unwrap_err();
+    assert_eq!((err.summary.status, err.summary.fault_op), (Status::OutOfFuel, None));
+}
+
+#[test]
+fn reading_past_the_input_is_eof() {
+    let code = JIT::new(Compiler::new("+++,").compile()).compile().unwrap();
+    let mut emulator = Emulator::new(MemoryIO::new(vec![]), code);
+    emulator.run().unwrap();
+    let err = emulator.result().unwrap_err();
+    assert_eq!(err.summary.status, Status::Eof);
+    // The cell keeps its value from before the read
+    assert_eq!(emulator.tape()[0], 3);
+}
+
+#[test]
+fn scans_match_the_vm() {
+    // Runs of non-zero cells of every length around the 16-cell vector width,
+    // scanned over in both directions with every stride
+    for stride in 1..=9 {
+        for len in [0, 1, 7, 15, 16, 17, 33, 100] {
+            let right = ">".repeat(stride);
+            let left = "<".repeat(stride);
+            let program = format!(
+                "{}{}[{right}]+++.{}[{left}].",
+                "+>".repeat(len + stride),
+                "<".repeat(len + stride),
+                ">".repeat(stride * 4),
+            );
+            assert_eq!(emulate(&program, b""), bench_run(&program, vec![]), "stride {stride}, {len} cells");
+        }
+    }
+}
+
+#[test]
+fn scanning_off_the_tape_is_a_fault() {
+    // Fill every cell but the first, go back to it and scan right from the next one
+    let program = format!(">{}+[<]>[>]", "+>".repeat(29998));
+    let err = run_metered(&program, b"", u64::MAX).unwrap_err();
+    assert_eq!(err.summary.status, Status::TapeFault);
+    assert_eq!(err.summary.tape_offset, 30000);
+
+    let err = run_metered(">>+<+<+[<<]", b"", u64::MAX).unwrap_err();
+    assert_eq!(err.summary.status, Status::TapeFault);
+    assert!(err.summary.tape_offset < 0);
+}
+
+/// Run with every top-level loop compiled on first entry, metered if there is fuel
+fn run_lazy(program: &str, input: &[u8], fuel: Option<u64>) -> (Emulator<MemoryIO>, Result<RunSummary, RunError>) {
+    let code = Compiler::new(program).compile();
+    let jit = if fuel.is_some() { JIT::metered(code) } else { JIT::new(code) };
+    let mut emulator = Emulator::lazy(MemoryIO::new(input.to_vec()), jit).unwrap();
+    if let Some(fuel) = fuel {
+        emulator.set_fuel(fuel);
+    }
+    emulator.run().unwrap();
+    let result = emulator.result();
+    (emulator, result)
+}
+
+#[test]
+fn lazy_loops_match_the_vm() {
+    for (program, input) in [
+        ("++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.", &b""[..]),
+        (",[.,]", b"echo\0"),
+        (include_str!("../benches/bf/primes.bf"), b"30\n"),
+        (include_str!("../benches/bf/pi-digits.bf"), b"10\n"),
+    ] {
+        let (emulator, result) = run_lazy(program, input, None);
+        result.unwrap();
+        assert!(emulator.compiled_loops() > 0);
+        assert_eq!(emulator.into_io().into_output(), bench_run(program, input.to_vec()));
+
+        let (_, result) = run_lazy(program, input, Some(u64::MAX));
+        assert_eq!(result.unwrap().steps, Some(vm_steps(program, input)));
+    }
+}
+
+#[test]
+fn lazy_loops_are_only_compiled_when_entered() {
+    let (emulator, result) = run_lazy("+[-][.]++[>+[.-]<-]", b"", None);
+    result.unwrap();
+    // The nested loop is compiled with its top-level loop
+    assert_eq!(emulator.compiled_loops(), 2);
+}
+
+#[test]
+fn lazy_loops_past_16_bit_jump_targets() {
+    // 69007 ops, so the JmpIfZ target doesn't fit the Op
+    let program = format!("+[{}-]+.", ">+<".repeat(23000));
+    let (emulator, result) = run_lazy(&program, b"", None);
+    result.unwrap();
+    assert_eq!(emulator.compiled_loops(), 1);
+    assert_eq!(emulator.tape()[1], (23000 % 256) as u8);
+    assert_eq!(emulator.into_io().into_output(), vec![1]);
+}
+
+#[test]
+fn lazy_loops_stop_the_program() {
+    let (_, result) = run_lazy("+[>+]", b"", None);
+    let err = result.unwrap_err();
+    assert_eq!(err.summary.status, Status::TapeFault);
+    assert_eq!(err.summary.tape_offset, 30000);
+
+    let (emulator, result) = run_lazy("+[.,]", b"ab", None);
+    assert_eq!(result.unwrap_err().summary.status, Status::Eof);
+    assert_eq!(emulator.into_io().into_output(), vec![1, b'a', b'b']);
+
+    let (_, result) = run_lazy("+[]", b"", Some(1000));
+    assert_eq!(result.unwrap_err().summary.status, Status::OutOfFuel);
+}
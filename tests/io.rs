@@ -0,0 +1,298 @@
+use std::cell::RefCell;
+use std::io::{self, Cursor, Read, Write};
+use std::rc::Rc;
+
+use brainv::io::{BatchedIO, EofPolicy, FlushPolicy, IO, MemoryIO, Newline, NewlineIO, NumberFormat, NumericIO, ReadWriteIO};
+
+fn numeric(input: &str, format: NumberFormat) -> NumericIO<MemoryIO> {
+    NumericIO::new(MemoryIO::new(input.as_bytes().to_vec()), format, b" ")
+}
+
+#[test]
+fn numeric_reads_decimal() {
+    let mut io = numeric("  0 65\n255\t", NumberFormat::Decimal);
+    assert_eq!(io.try_read_byte(), Some(0));
+    assert_eq!(io.try_read_byte(), Some(65));
+    assert_eq!(io.try_read_byte(), Some(255));
+    assert_eq!(io.try_read_byte(), None);
+}
+
+#[test]
+fn numeric_reads_hex() {
+    let mut io = numeric("41 0xff 0A", NumberFormat::Hex);
+    assert_eq!(io.try_read_byte(), Some(0x41));
+    assert_eq!(io.try_read_byte(), Some(0xff));
+    assert_eq!(io.try_read_byte(), Some(0x0a));
+}
+
+#[test]
+fn numeric_reads_negative_numbers() {
+    let mut io = numeric("-1 -255 -0", NumberFormat::Decimal);
+    assert_eq!(io.try_read_byte(), Some(255));
+    assert_eq!(io.try_read_byte(), Some(1));
+    assert_eq!(io.try_read_byte(), Some(0));
+}
+
+#[test]
+fn numeric_number_can_end_at_eof() {
+    let mut io = numeric("12 42", NumberFormat::Decimal);
+    assert_eq!(io.try_read_byte(), Some(12));
+    assert_eq!(io.try_read_byte(), Some(42));
+    assert_eq!(io.try_read_byte(), None);
+}
+
+#[test]
+fn numeric_passes_on_the_inner_eof() {
+    assert_eq!(numeric("", NumberFormat::Decimal).try_read_byte(), None);
+    assert_eq!(numeric(" \n", NumberFormat::Decimal).try_read_byte(), None);
+
+    for (policy, byte) in [(EofPolicy::Zero, 0), (EofPolicy::MinusOne, 255)] {
+        let inner = ReadWriteIO::with_options(Cursor::new(b"7".to_vec()), Vec::new(), 16, policy);
+        let mut io = NumericIO::new(inner, NumberFormat::Decimal, b" ");
+        assert_eq!(io.try_read_byte(), Some(7));
+        assert_eq!(io.try_read_byte(), Some(byte));
+        assert_eq!(io.try_read_byte(), Some(byte));
+    }
+}
+
+#[test]
+fn numeric_keeps_the_byte_that_ends_a_number() {
+    let mut io = numeric("12,-3;7\0 1", NumberFormat::Decimal);
+    assert_eq!(io.try_read_byte(), Some(12));
+    assert_eq!(io.try_read_byte(), Some(b','));
+    assert_eq!(io.try_read_byte(), Some(253));
+    assert_eq!(io.try_read_byte(), Some(b';'));
+    assert_eq!(io.try_read_byte(), Some(7));
+    assert_eq!(io.try_read_byte(), Some(0));
+    assert_eq!(io.try_read_byte(), Some(1));
+    assert_eq!(io.try_read_byte(), None);
+}
+
+#[test]
+#[should_panic(expected = "300 does not fit in a cell")]
+fn numeric_rejects_numbers_above_255() {
+    numeric("300", NumberFormat::Decimal).try_read_byte();
+}
+
+#[test]
+#[should_panic(expected = "-256 does not fit in a cell")]
+fn numeric_rejects_numbers_below_minus_255() {
+    numeric("-256", NumberFormat::Decimal).try_read_byte();
+}
+
+#[test]
+#[should_panic(expected = "expected a number")]
+fn numeric_rejects_text() {
+    numeric("12a", NumberFormat::Decimal).try_read_byte();
+}
+
+#[test]
+fn numeric_writes_numbers() {
+    let mut io = numeric("", NumberFormat::Decimal);
+    for c in [0, 10, 255] {
+        io.write_byte(c);
+    }
+    assert_eq!(io.into_inner().output(), b"0 10 255 ");
+
+    let mut io = NumericIO::new(MemoryIO::new(Vec::new()), NumberFormat::Hex, b"\n");
+    for c in [0, 10, 255] {
+        io.write_byte(c);
+    }
+    assert_eq!(io.into_inner().output(), b"00\n0a\nff\n");
+}
+
+/// Everything a NewlineIO reads from input, up to the end of it
+fn read_newlines(input: &[u8], mode: Newline) -> Vec<u8> {
+    let mut io = NewlineIO::new(MemoryIO::new(input.to_vec()), mode);
+    std::iter::from_fn(|| io.try_read_byte()).collect()
+}
+
+fn write_newlines(output: &[u8], mode: Newline) -> Vec<u8> {
+    let mut io = NewlineIO::new(MemoryIO::new(Vec::new()), mode);
+    for &c in output {
+        io.write_byte(c);
+    }
+    io.into_inner().into_output()
+}
+
+#[test]
+fn newline_raw_passes_everything_through() {
+    assert_eq!(read_newlines(b"a\r\nb\rc\n\r", Newline::Raw), b"a\r\nb\rc\n\r");
+    assert_eq!(write_newlines(b"a\nb\r\n", Newline::Raw), b"a\nb\r\n");
+}
+
+#[test]
+fn newline_strip_cr_drops_every_cr() {
+    assert_eq!(read_newlines(b"a\r\nb\rc\r", Newline::StripCr), b"a\nbc");
+    assert_eq!(read_newlines(b"\r\r", Newline::StripCr), b"");
+    assert_eq!(write_newlines(b"a\r\n", Newline::StripCr), b"a\r\n");
+}
+
+#[test]
+fn newline_crlf_to_lf_keeps_lone_crs() {
+    assert_eq!(read_newlines(b"a\r\nb\rc\r\r\n", Newline::CrLfToLf), b"a\nb\rc\r\n");
+    assert_eq!(write_newlines(b"a\n", Newline::CrLfToLf), b"a\n");
+}
+
+#[test]
+fn newline_crlf_to_lf_returns_a_cr_at_eof() {
+    assert_eq!(read_newlines(b"a\r", Newline::CrLfToLf), b"a\r");
+    assert_eq!(read_newlines(b"\r", Newline::CrLfToLf), b"\r");
+}
+
+#[test]
+fn newline_lf_to_crlf_translates_output_only() {
+    assert_eq!(write_newlines(b"a\nb\n", Newline::LfToCrLf), b"a\r\nb\r\n");
+    assert_eq!(read_newlines(b"a\nb\r\n", Newline::LfToCrLf), b"a\nb\r\n");
+}
+
+#[test]
+fn newline_enter_sends_10() {
+    assert_eq!(read_newlines(b"a\rb\r\nc\n\r", Newline::EnterSends10), b"a\nb\nc\n\n");
+    assert_eq!(write_newlines(b"a\n", Newline::EnterSends10), b"a\n");
+}
+
+/// A writer that shows what reached it while the IO writing to it is still alive
+struct SharedWriter(Rc<RefCell<Vec<u8>>>);
+
+impl Write for SharedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A reader that fails with Interrupted before every successful read, like a
+/// read(2) hit by a signal
+struct InterruptedReader {
+    inner: Cursor<Vec<u8>>,
+    interrupt: bool,
+}
+
+impl Read for InterruptedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.interrupt = !self.interrupt;
+        if self.interrupt {
+            return Err(io::ErrorKind::Interrupted.into());
+        }
+        self.inner.read(buf)
+    }
+}
+
+#[test]
+fn batched_retries_interrupted_reads() {
+    let reader = InterruptedReader { inner: Cursor::new(b"hi".to_vec()), interrupt: false };
+    let mut io = BatchedIO::with_streams(reader, Vec::new(), 16, FlushPolicy::Full);
+    assert_eq!(io.try_read_byte(), Some(b'h'));
+    assert_eq!(io.try_read_byte(), Some(b'i'));
+    assert_eq!(io.try_read_byte(), None);
+}
+
+#[test]
+#[should_panic(expected = "BatchedIO::read_byte()")]
+fn batched_panics_on_other_read_errors() {
+    struct BrokenReader;
+    impl Read for BrokenReader {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+    }
+    BatchedIO::with_streams(BrokenReader, Vec::new(), 16, FlushPolicy::Full).try_read_byte();
+}
+
+#[test]
+fn batched_flushes_by_policy() {
+    let mut output = Vec::new();
+    let mut io = BatchedIO::with_streams(Cursor::new(b"x".to_vec()), &mut output, 4, FlushPolicy::BeforeRead);
+    io.write_byte(b'a');
+    assert_eq!(io.try_read_byte(), Some(b'x'));
+    io.write_byte(b'b');
+    drop(io);
+    assert_eq!(output, b"ab");
+
+    let mut output = Vec::new();
+    let mut io = BatchedIO::with_streams(io::empty(), &mut output, 4, FlushPolicy::Full);
+    for &c in b"abcdef" {
+        io.write_byte(c);
+    }
+    assert_eq!(io.try_read_byte(), None);
+    drop(io);
+    assert_eq!(output, b"abcdef");
+}
+
+#[test]
+fn batched_line_policy_flushes_at_newlines() {
+    let seen = Rc::new(RefCell::new(Vec::new()));
+    let mut io = BatchedIO::with_streams(io::empty(), SharedWriter(seen.clone()), 64, FlushPolicy::Line);
+    io.write_byte(b'a');
+    assert_eq!(*seen.borrow(), b"");
+    io.write_byte(b'\n');
+    assert_eq!(*seen.borrow(), b"a\n");
+}
+
+fn read_write(input: &[u8], eof: EofPolicy) -> ReadWriteIO<Cursor<Vec<u8>>, Vec<u8>> {
+    ReadWriteIO::with_options(Cursor::new(input.to_vec()), Vec::new(), 16, eof)
+}
+
+#[test]
+fn read_write_reports_eof_by_default() {
+    let mut io = ReadWriteIO::new(Cursor::new(b"a".to_vec()), Vec::new());
+    assert_eq!(io.try_read_byte(), Some(b'a'));
+    assert_eq!(io.try_read_byte(), None);
+}
+
+#[test]
+#[should_panic(expected = "no more input available (EOF)")]
+fn read_write_panic_policy_panics_in_read_byte() {
+    let mut io = read_write(b"", EofPolicy::Panic);
+    io.read_byte();
+}
+
+#[test]
+fn read_write_eof_policies() {
+    for (policy, byte) in [(EofPolicy::Zero, 0), (EofPolicy::MinusOne, 255)] {
+        let mut io = read_write(b"a", policy);
+        assert_eq!(io.read_byte(), b'a');
+        assert_eq!(io.read_byte(), byte);
+        assert_eq!(io.read_byte(), byte);
+    }
+}
+
+#[test]
+fn read_write_into_inner_flushes_the_output() {
+    let mut io = read_write(b"ab", EofPolicy::Panic);
+    io.write_byte(b'x');
+    assert_eq!(io.read_byte(), b'a');
+    io.write_byte(b'y');
+    let (mut reader, writer) = io.into_inner();
+    assert_eq!(writer, b"xy");
+    // Input the IO buffered is not handed back
+    let mut rest = Vec::new();
+    reader.read_to_end(&mut rest).unwrap();
+    assert_eq!(rest, b"");
+}
+
+#[test]
+fn read_write_flushes_before_reading_more_input() {
+    let seen = Rc::new(RefCell::new(Vec::new()));
+    let mut io = ReadWriteIO::with_options(Cursor::new(b"a".to_vec()), SharedWriter(seen.clone()), 16, EofPolicy::Panic);
+    io.write_byte(b'>');
+    assert_eq!(*seen.borrow(), b"");
+    assert_eq!(io.try_read_byte(), Some(b'a'));
+    assert_eq!(*seen.borrow(), b">");
+}
+
+#[test]
+fn read_write_without_buffers_passes_bytes_straight_through() {
+    let seen = Rc::new(RefCell::new(Vec::new()));
+    let mut input = Cursor::new(b"abc".to_vec());
+    let mut io = ReadWriteIO::with_options(&mut input, SharedWriter(seen.clone()), 0, EofPolicy::Panic);
+    io.write_byte(b'x');
+    assert_eq!(*seen.borrow(), b"x");
+    assert_eq!(io.try_read_byte(), Some(b'a'));
+    drop(io);
+    assert_eq!(input.position(), 1);
+}
@@ -0,0 +1,94 @@
+use brainv::compiler::Compiler;
+use brainv::jit::JIT;
+
+fn words(code: &[u8]) -> Vec<u32> {
+    code.chunks(4).map(|c| u32::from_le_bytes(c.try_into().unwrap())).collect()
+}
+
+/// Decode a cbz/cbnz w23 or a b at the given byte offset into (is a conditional branch, target offset)
+fn branch_target(word: u32, at: usize) -> Option<(bool, usize)> {
+    let (conditional, offset) = if word & 0xFE00001F == 0x34000017 {
+        // cbz/cbnz w23, imm19 in bits 5..24
+        (true, (((word >> 5) & 0x7FFFF) as i64) << 45 >> 43)
+    } else if word & 0xFC000000 == 0x14000000 {
+        // b, imm26
+        (false, ((word & 0x3FFFFFF) as i64) << 38 >> 36)
+    } else {
+        return None;
+    };
+    Some((conditional, (at as i64 + offset) as usize))
+}
+
+/// Loop branches, the cbz/cbnz on the cell and the b right after one in the far form
+fn branches(code: &[u8]) -> Vec<(usize, bool, usize)> {
+    let mut branches: Vec<(usize, bool, usize)> = vec![];
+    for (i, word) in words(code).into_iter().enumerate() {
+        let Some((conditional, target)) = branch_target(word, i * 4) else {
+            continue;
+        };
+        let follows_conditional = branches.last().is_some_and(|&(at, c, _)| c && at + 4 == i * 4);
+        if conditional || follows_conditional {
+            branches.push((i * 4, conditional, target));
+        }
+    }
+    branches
+}
+
+fn compile(program: &str) -> Vec<u8> {
+    JIT::new(Compiler::new(program).compile()).compile().unwrap()
+}
+
+#[test]
+fn short_loops_use_cbz_and_cbnz() {
+    let code = compile("+[-.]");
+    let branches = branches(&code);
+    assert_eq!(branches.len(), 2);
+
+    let (cbz, _, end) = branches[0];
+    let (cbnz, _, body) = branches[1];
+    assert_eq!(body, cbz + 4);
+    assert_eq!(end, cbnz + 4);
+}
+
+#[test]
+fn loops_larger_than_1mib_are_relaxed() {
+    // Every '.' inside the loop is ten instructions, so this is well over 1 MiB of code
+    let program = format!("+[{}-]", ".".repeat(100_000));
+    let code = compile(&program);
+    assert!(code.len() > 1 << 20);
+
+    let branches = branches(&code);
+    assert_eq!(branches.len(), 4);
+
+    // cbnz over b <end>
+    let (skip_forward, conditional, after_forward) = branches[0];
+    let (forward, _, end) = branches[1];
+    assert!(conditional);
+    assert_eq!(forward, skip_forward + 4);
+    assert_eq!(after_forward, forward + 4);
+
+    // cbz over b <body>
+    let (skip_back, conditional, after_back) = branches[2];
+    let (back, _, body) = branches[3];
+    assert!(conditional);
+    assert_eq!(back, skip_back + 4);
+    assert_eq!(after_back, back + 4);
+
+    assert_eq!(body, forward + 4);
+    assert_eq!(end, back + 4);
+    assert!(end - forward > 1 << 20);
+}
+
+#[test]
+fn inner_loops_stay_short_inside_a_relaxed_loop() {
+    let program = format!("+[[-]{}-]", ".".repeat(100_000));
+    let code = compile(&program);
+
+    let branches = branches(&code);
+    assert_eq!(branches.len(), 6);
+    // The inner loop comes right after the outer loop's cbnz/b pair
+    let (inner_cbz, _, inner_end) = branches[2];
+    let (inner_cbnz, _, inner_body) = branches[3];
+    assert_eq!(inner_body, inner_cbz + 4);
+    assert_eq!(inner_end, inner_cbnz + 4);
+}
@@ -0,0 +1,121 @@
+use brainv::compiler::Compiler;
+use brainv::emulator::Emulator;
+use brainv::io::MemoryIO;
+use brainv::object::{self, Object};
+use brainv::jit::JIT;
+use brainv::runtime::Status;
+
+fn object(program: &str, symbol: &str) -> Object {
+    let (code, offsets) = Compiler::new(program).compile_with_offsets();
+    let mut jit = JIT::new(code);
+    jit.set_source_offsets(offsets);
+    Object::new(&jit, symbol).unwrap()
+}
+
+fn u16_at(elf: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes(elf[at..at + 2].try_into().unwrap())
+}
+
+fn u32_at(elf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(elf[at..at + 4].try_into().unwrap())
+}
+
+fn u64_at(elf: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(elf[at..at + 8].try_into().unwrap())
+}
+
+/// Section headers as (name, type, offset, size, info)
+fn sections(elf: &[u8]) -> Vec<(String, u32, usize, usize, u32)> {
+    let shoff = u64_at(elf, 40) as usize;
+    let count = u16_at(elf, 60) as usize;
+    let header = |index: usize| shoff + index * 64;
+    let names = u64_at(elf, header(u16_at(elf, 62) as usize) + 24) as usize;
+    (0..count)
+        .map(|index| {
+            let at = header(index);
+            let name = &elf[names + u32_at(elf, at) as usize..];
+            let name = String::from_utf8(name[..name.iter().position(|&b| b == 0).unwrap()].to_vec()).unwrap();
+            (name, u32_at(elf, at + 4), u64_at(elf, at + 24) as usize, u64_at(elf, at + 32) as usize, u32_at(elf, at + 44))
+        })
+        .collect()
+}
+
+#[test]
+fn object_holds_the_jit_code() {
+    let program = "++++++++[>++++++++<-]>+.,.";
+    let built = object(program, object::DEFAULT_SYMBOL);
+    let elf = &built.object;
+    assert_eq!(&elf[..6], b"\x7fELF\x02\x01");
+    // ET_REL for AArch64
+    assert_eq!(u16_at(elf, 16), 1);
+    assert_eq!(u16_at(elf, 18), 183);
+
+    let sections = sections(elf);
+    let names: Vec<&str> = sections.iter().map(|section| section.0.as_str()).collect();
+    assert_eq!(names, ["", ".text", ".symtab", ".strtab", ".shstrtab", ".note.GNU-stack"]);
+    let (_, kind, offset, size, _) = sections[1];
+    // PROGBITS with the same code the JIT generates
+    assert_eq!(kind, 1);
+    let (code, _) = Compiler::new(program).compile_with_offsets();
+    assert_eq!(&elf[offset..offset + size], JIT::new(code).compile().unwrap());
+    assert_eq!(sections[5].3, 0);
+}
+
+#[test]
+fn output_before_a_tape_fault_is_written() {
+    // There is no AArch64 linker here, so run the .text as a linked program would
+    let built = object("++++++++[>++++++++<-]>+.+.<<+.", object::DEFAULT_SYMBOL);
+    let (_, _, offset, size, _) = sections(&built.object)[1];
+    let mut emulator = Emulator::new(MemoryIO::new(vec![]), built.object[offset..offset + size].to_vec());
+    emulator.run().unwrap();
+    assert_eq!(emulator.result().unwrap_err().summary.status, Status::TapeFault);
+    assert_eq!(emulator.into_io().output(), b"AB");
+}
+
+#[test]
+fn only_the_entry_point_is_global() {
+    let built = object("+[->+<]>[-].", "bf_copy");
+    let elf = &built.object;
+    let sections = sections(elf);
+    let (_, _, symtab, size, first_global) = sections[2];
+    let strtab = sections[3].2;
+
+    let symbols: Vec<(String, u8, u64, u64)> = (0..size / 24)
+        .map(|index| {
+            let at = symtab + index * 24;
+            let name = &elf[strtab + u32_at(elf, at) as usize..];
+            let name = String::from_utf8(name[..name.iter().position(|&b| b == 0).unwrap()].to_vec()).unwrap();
+            (name, elf[at + 4] >> 4, u64_at(elf, at + 8), u64_at(elf, at + 16))
+        })
+        .collect();
+    let globals: Vec<_> = symbols.iter().filter(|symbol| symbol.1 == 1).collect();
+    assert_eq!(globals.len(), 1);
+    assert_eq!(globals[0].0, "bf_copy");
+    // The whole .text, starting from the prologue
+    assert_eq!((globals[0].2, globals[0].3), (0, sections[1].3 as u64));
+    // Locals come first, the symbol table info points past them
+    assert_eq!(symbols.iter().position(|symbol| symbol.1 == 1), Some(first_global as usize));
+    assert!(symbols.iter().any(|symbol| symbol.0 == "bf_loop_1_6" && symbol.1 == 0));
+}
+
+#[test]
+fn header_declares_the_entry_point() {
+    let header = object(",[.,]", "bf_cat").header;
+    assert!(header.contains("#ifndef BRAINV_BF_CAT_H"));
+    assert!(header.contains("uint64_t bf_cat(uint8_t *tape, void *ctx, bf_write_fn write_fn, bf_read_fn read_fn);"));
+    assert!(header.contains("typedef struct bf_context {"));
+    assert!(header.contains("#define BF_TAPE_FAULT 4"));
+    // The fields in the order of the runtime's header
+    let fields = ["out_start", "out_end", "tape_start", "tape_end", "fuel", "status", "tape_offset", "steps", "compile_loop"];
+    let positions: Vec<usize> = fields.iter().map(|field| header.find(&format!(" *{field};")).or_else(|| header.find(&format!(" {field};"))).unwrap()).collect();
+    assert!(positions.windows(2).all(|pair| pair[0] < pair[1]));
+}
+
+#[test]
+fn entry_point_must_be_a_c_identifier() {
+    let jit = JIT::new(Compiler::new("+").compile());
+    assert!(Object::new(&jit, "bf-main").is_err());
+    assert!(Object::new(&jit, "1main").is_err());
+    assert!(Object::new(&jit, "").is_err());
+    assert!(Object::new(&jit, "_bf_main2").is_ok());
+}
@@ -0,0 +1,103 @@
+use brainv::io::MemoryIO;
+use brainv::repl::Repl;
+use brainv::vm::INITIAL_TAPE_CELLS;
+
+fn repl() -> Repl<MemoryIO> {
+    Repl::new(MemoryIO::new(vec![]))
+}
+
+/// Evaluate the line and return what the repl printed
+fn eval(repl: &mut Repl<MemoryIO>, line: &str) -> String {
+    let mut out = vec![];
+    assert!(repl.eval(line, &mut out).unwrap());
+    String::from_utf8(out).unwrap()
+}
+
+#[test]
+fn tape_persists_between_lines() {
+    let mut repl = repl();
+    eval(&mut repl, "+++>\n");
+    let window = eval(&mut repl, "++\n");
+    assert_eq!(&repl.tape()[..2], [3, 2]);
+    assert_eq!(repl.tp(), 1);
+    // Indices, values and a marker under the current cell
+    let lines: Vec<&str> = window.lines().collect();
+    assert!(lines[1].starts_with("    0    1    2"));
+    assert!(lines[2].starts_with("    3    2    0"));
+    assert_eq!(lines[3], "         ^");
+}
+
+#[test]
+fn open_loops_continue_on_the_next_line() {
+    let mut repl = repl();
+    assert_eq!(eval(&mut repl, "+++[->+\n"), "");
+    eval(&mut repl, "<]\n");
+    assert_eq!(&repl.tape()[..2], [0, 3]);
+    assert_eq!(eval(&mut repl, "]\n"), "error: unmatched ']'\n");
+}
+
+#[test]
+fn undo_and_reset() {
+    let mut repl = repl();
+    eval(&mut repl, "+++\n");
+    eval(&mut repl, ">++\n");
+    eval(&mut repl, ":undo");
+    assert_eq!(&repl.tape()[..2], [3, 0]);
+    assert_eq!(repl.tp(), 0);
+    eval(&mut repl, ":undo");
+    assert_eq!(repl.tape()[0], 0);
+    assert_eq!(eval(&mut repl, ":undo"), "nothing to undo\n");
+
+    eval(&mut repl, "+>>>>+\n");
+    eval(&mut repl, ":reset");
+    assert!(repl.tape().iter().all(|&cell| cell == 0));
+    assert_eq!(repl.tape().len(), INITIAL_TAPE_CELLS);
+    assert_eq!(repl.tp(), 0);
+    assert_eq!(eval(&mut repl, ":undo"), "nothing to undo\n");
+}
+
+#[test]
+fn tape_ranges() {
+    let mut repl = repl();
+    eval(&mut repl, "+>++>+++\n");
+    let cells = eval(&mut repl, ":tape 1..3");
+    assert!(cells.lines().nth(1).unwrap().starts_with("    1    2"));
+    assert!(cells.lines().nth(2).unwrap().starts_with("    2    3"));
+    assert_eq!(cells.lines().nth(1).unwrap().split_whitespace().count(), 2);
+
+    assert_eq!(eval(&mut repl, ":tape 5..2"), "error: the range 5..2 is empty\n");
+    assert_eq!(eval(&mut repl, ":tape a..b"), "error: expected a range like 0..32\n");
+    assert_eq!(eval(&mut repl, ":tape 5000"), format!("error: tape has {INITIAL_TAPE_CELLS} cells\n"));
+}
+
+#[test]
+fn load_runs_a_file() {
+    let path = std::env::temp_dir().join(format!("brainv-repl-{}.bf", std::process::id()));
+    std::fs::write(&path, "++[->+++<]").unwrap();
+    let mut repl = repl();
+    eval(&mut repl, &format!(":load {}", path.display()));
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(&repl.tape()[..2], [0, 6]);
+
+    assert!(eval(&mut repl, ":load /nonexistent.bf").starts_with("error: failed to read"));
+}
+
+#[test]
+fn failed_lines_are_rolled_back() {
+    let mut repl = repl();
+    eval(&mut repl, "++\n");
+    // Changes the tape, then moves off its left end
+    eval(&mut repl, "+++<\n");
+    assert_eq!(repl.tape()[0], 2);
+    assert_eq!(repl.tp(), 0);
+    // Nothing to undo for the failed line
+    eval(&mut repl, ":undo");
+    assert_eq!(repl.tape()[0], 0);
+}
+
+#[test]
+fn quit_and_unknown_commands() {
+    let mut repl = repl();
+    assert!(!repl.eval(":quit", &mut vec![]).unwrap());
+    assert!(eval(&mut repl, ":frobnicate").starts_with("unknown command :frobnicate"));
+}
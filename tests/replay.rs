@@ -0,0 +1,147 @@
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use brainv::compiler::Compiler;
+use brainv::emulator::Emulator;
+use brainv::io::{IO, MemoryIO};
+use brainv::jit::JIT;
+use brainv::replay::{EventKind, RecordingIO, ReplayIO, parse_log};
+use brainv::runtime::Status;
+use brainv::threaded::Threaded;
+use brainv::vm::Vm;
+
+const ECHO_UPPER: &str = ",[--------------------------------.,]";
+
+/// Run the program on the Vm with step counting and return the log
+fn record(program: &str, input: &[u8]) -> Vec<u8> {
+    let mut log = Vec::new();
+    let io = RecordingIO::new(MemoryIO::new(input.to_vec()), &mut log);
+    let mut vm = Vm::new(io, Compiler::new(program).compile());
+    vm.run_timed();
+    drop(vm);
+    log
+}
+
+/// Replay the log on the Vm, returns the output and whether every event was used
+fn replay_vm(program: &str, log: &[u8]) -> (Vec<u8>, Result<(), String>) {
+    let io = ReplayIO::new(MemoryIO::new(Vec::new()), log).unwrap();
+    let mut vm = Vm::new(io, Compiler::new(program).compile());
+    vm.run_timed();
+    let replay = vm.into_io();
+    let finished = replay.finish();
+    (replay.into_inner().into_output(), finished)
+}
+
+#[test]
+fn log_has_events_and_steps() {
+    let log = record(",+.", b"a");
+    assert_eq!(String::from_utf8(log).unwrap(), "# brainv io log v2\n0 r 97 1\n1 w 98 3\n");
+}
+
+#[test]
+fn log_without_steps_parses() {
+    let events = parse_log(&b"# brainv io log v1\n0 r 97\n1 w 98 2\n"[..]).unwrap();
+    assert_eq!(events.len(), 2);
+    assert_eq!((events[0].kind, events[0].byte, events[0].step), (EventKind::Read, b'a', None));
+    assert_eq!((events[1].kind, events[1].byte, events[1].step), (EventKind::Write, b'b', Some(2)));
+
+    assert!(parse_log(&b"1 r 97\n"[..]).is_err());
+    assert!(parse_log(&b"0 x 97\n"[..]).is_err());
+    assert!(parse_log(&b"0 r 97 1 2\n"[..]).is_err());
+}
+
+#[test]
+fn replay_round_trip() {
+    let log = record(ECHO_UPPER, b"hello\0");
+    let (output, finished) = replay_vm(ECHO_UPPER, &log);
+    assert_eq!(output, b"HELLO");
+    finished.unwrap();
+}
+
+#[test]
+fn replay_on_a_backend_without_steps() {
+    let log = record(ECHO_UPPER, b"hi\0");
+    let io = ReplayIO::new(MemoryIO::new(Vec::new()), &log[..]).unwrap();
+    let mut threaded = Threaded::new(io, Compiler::new(ECHO_UPPER).compile());
+    threaded.run();
+    let replay = threaded.into_io();
+    replay.finish().unwrap();
+    assert_eq!(replay.into_inner().into_output(), b"HI");
+}
+
+#[test]
+#[should_panic(expected = "replay diverged at event 1: the log has a write of 66 but the program wrote 67")]
+fn replay_detects_different_output() {
+    let log = record(ECHO_UPPER, b"b\0");
+    let _ = replay_vm(",[-------------------------------.,]", &log);
+}
+
+#[test]
+#[should_panic(expected = "replay diverged at event 1: the log has the Write at step 5 but the program did it at step 8")]
+fn replay_detects_different_steps() {
+    let log = record(",>+<.", b"a");
+    let _ = replay_vm(",>+<>+<.", &log);
+}
+
+#[test]
+fn replay_reports_unused_events() {
+    let log = record(",.,.", b"ab");
+    let (output, finished) = replay_vm(",.", &log);
+    assert_eq!(output, b"a");
+    assert_eq!(finished.unwrap_err(), "replay ended early, 2 events left starting at event 2");
+}
+
+/// Run the program as JIT code, which stops with Status::Eof at the end of the input
+fn run_jit<I: IO>(io: I, program: &str) -> I {
+    let mut emulator = Emulator::new(io, JIT::new(Compiler::new(program).compile()).compile().unwrap());
+    emulator.run().unwrap();
+    assert_eq!(emulator.result().unwrap_err().summary.status, Status::Eof);
+    emulator.into_io()
+}
+
+#[test]
+fn end_of_input_is_recorded_and_replayed() {
+    let mut log = Vec::new();
+    run_jit(RecordingIO::new(MemoryIO::new(b"hi".to_vec()), &mut log), ",[.,]");
+    assert_eq!(String::from_utf8(log.clone()).unwrap(), "# brainv io log v2\n0 r 104\n1 w 104\n2 r 105\n3 w 105\n4 eof\n");
+
+    let replay = run_jit(ReplayIO::new(MemoryIO::new(Vec::new()), &log[..]).unwrap(), ",[.,]");
+    replay.finish().unwrap();
+    assert_eq!(replay.into_inner().into_output(), b"hi");
+
+    let events = parse_log(&log[..]).unwrap();
+    assert_eq!((events[4].kind, events[4].step), (EventKind::Eof, None));
+    assert!(parse_log(&b"0 eof 1 2\n"[..]).is_err());
+}
+
+#[test]
+#[should_panic(expected = "replay diverged at event 1: the log has the end of the input but the program did a Write")]
+fn replay_detects_output_after_the_end_of_input() {
+    let mut io = ReplayIO::new(MemoryIO::new(Vec::new()), &b"0 r 97\n1 eof 3\n"[..]).unwrap();
+    assert_eq!(io.try_read_byte(), Some(b'a'));
+    io.write_byte(b'a');
+}
+
+/// A log file that can be looked at while the RecordingIO is still writing it
+struct SharedLog(Rc<RefCell<Vec<u8>>>);
+
+impl Write for SharedLog {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn every_event_reaches_the_log_right_away() {
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let mut io = RecordingIO::new(MemoryIO::new(b"x".to_vec()), SharedLog(log.clone()));
+    io.write_byte(b'a');
+    assert_eq!(*log.borrow(), b"# brainv io log v2\n0 w 97\n");
+    io.read_byte();
+    assert_eq!(*log.borrow(), b"# brainv io log v2\n0 w 97\n1 r 120\n");
+}
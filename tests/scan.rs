@@ -0,0 +1,50 @@
+use brainv::compiler::Compiler;
+use brainv::scan::{find_zero, rfind_zero};
+use brainv::vm::Op;
+
+fn naive_find(tape: &[u8], from: usize, stride: usize) -> Option<usize> {
+    (from..tape.len()).step_by(stride).find(|&i| tape[i] == 0)
+}
+
+fn naive_rfind(tape: &[u8], from: usize, stride: usize) -> Option<usize> {
+    (0..=from).rev().step_by(stride).find(|&i| tape[i] == 0)
+}
+
+/// Tapes of every length up to 70 with a few zeros sprinkled in
+fn tapes() -> Vec<Vec<u8>> {
+    let mut tapes = vec![];
+    for len in 1..70usize {
+        for zeros in [vec![], vec![0], vec![len - 1], vec![len / 2], vec![3, len / 3, len.saturating_sub(2)]] {
+            let mut tape = vec![1; len];
+            for i in zeros {
+                if i < len {
+                    tape[i] = 0;
+                }
+            }
+            tapes.push(tape);
+        }
+    }
+    tapes
+}
+
+#[test]
+fn find_zero_matches_a_plain_loop() {
+    for tape in tapes() {
+        for stride in 1..=17 {
+            for from in 0..tape.len() {
+                assert_eq!(find_zero(&tape, from, stride), naive_find(&tape, from, stride), "{tape:?} {from} {stride}");
+                assert_eq!(rfind_zero(&tape, from, stride), naive_rfind(&tape, from, stride), "{tape:?} {from} {stride}");
+            }
+        }
+    }
+}
+
+#[test]
+fn single_move_loops_compile_to_scans() {
+    let code = Compiler::new("+[>>>]<[<]").compile();
+    assert!(matches!(code[..], [Op::Nop, Op::Inc(1), Op::ScanR(3), Op::MovL(1), Op::ScanL(1)]), "{code:?}");
+
+    // Anything else in the loop keeps it a loop
+    let code = Compiler::new("[>+]").compile();
+    assert!(!code.iter().any(|op| matches!(op, Op::ScanR(_))));
+}
@@ -0,0 +1,66 @@
+use brainv::compiler::Compiler;
+use brainv::io::MemoryIO;
+use brainv::threaded::Threaded;
+use brainv::vm::{Vm, bench_run};
+
+fn run(program: &str, input: &[u8]) -> Threaded<MemoryIO> {
+    let mut threaded = Threaded::new(MemoryIO::new(input.to_vec()), Compiler::new(program).compile());
+    threaded.run();
+    threaded
+}
+
+fn assert_matches_vm(program: &str, input: &[u8]) {
+    let expected = bench_run(program, input.to_vec());
+    assert!(!expected.is_empty());
+    assert_eq!(run(program, input).into_io().into_output(), expected);
+}
+
+#[test]
+fn programs_match_the_vm() {
+    assert_matches_vm(
+        "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.",
+        b"",
+    );
+    assert_matches_vm(",[.,]", b"echo\0");
+    assert_matches_vm("-.+.>+[+]+.", b"");
+    assert_matches_vm(include_str!("../benches/bf/serptri.bf"), b"");
+    assert_matches_vm(include_str!("../benches/bf/primes.bf"), b"30\n");
+    assert_matches_vm(include_str!("../benches/bf/pi-digits.bf"), b"10\n");
+}
+
+#[test]
+fn tape_matches_the_vm() {
+    let program = "+++>++<[->+<]>>>+++[-]<<[>>+<<-]+>>[<]>[->+>+<<]";
+    let threaded = run(program, b"");
+    let mut vm = Vm::new(MemoryIO::new(vec![]), Compiler::new(program).compile());
+    vm.run();
+    assert_eq!(threaded.tape(), vm.tape());
+    assert_eq!(threaded.tp(), vm.tp());
+}
+
+#[test]
+fn superinstructions_are_fused() {
+    for (program, len) in [
+        // [-] is one instruction, the ->+< of the body two with the ] fused into the second
+        ("[-][->+<]", 4),
+        // The move before a loop goes with its [, unless that loop clears the cell
+        (">[<-]>[-]", 5),
+        ("+[>>+<]", 4),
+    ] {
+        let threaded = Threaded::new(MemoryIO::new(vec![]), Compiler::new(program).compile());
+        assert_eq!(threaded.len(), len, "{program}");
+    }
+}
+
+#[test]
+fn tape_grows_to_the_right() {
+    let threaded = run(&"+>".repeat(5000), b"");
+    assert!(threaded.tape().len() > 5000);
+    assert_eq!(threaded.tape()[4999], 1);
+}
+
+#[test]
+#[should_panic(expected = "Tape pointer underflow")]
+fn moving_left_of_the_tape_panics() {
+    run(">+<<", b"");
+}
@@ -0,0 +1,93 @@
+use brainv::compiler::Compiler;
+use brainv::emulator::EmulatedLoops;
+use brainv::io::MemoryIO;
+use brainv::runtime::Status;
+use brainv::tiered::Tiered;
+use brainv::vm::bench_run;
+
+/// Run with hot loops compiled after the given number of back-edges and
+/// return the output and the number of loops compiled
+fn run_tiered(program: &str, input: &[u8], threshold: u32) -> (Vec<u8>, usize) {
+    let mut tiered = Tiered::with_runner(MemoryIO::new(input.to_vec()), Compiler::new(program).compile(), EmulatedLoops);
+    tiered.set_threshold(threshold);
+    tiered.run().unwrap();
+    let compiled = tiered.compiled_loops();
+    (tiered.into_io().into_output(), compiled)
+}
+
+fn assert_matches_vm(program: &str, input: &[u8]) {
+    let expected = bench_run(program, input.to_vec());
+    assert!(!expected.is_empty());
+    for threshold in [1, 2, 10] {
+        let (output, compiled) = run_tiered(program, input, threshold);
+        assert_eq!(output, expected, "threshold {threshold}");
+        assert!(compiled > 0, "threshold {threshold}");
+    }
+}
+
+#[test]
+fn hello_world() {
+    assert_matches_vm(
+        "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.",
+        b"",
+    );
+}
+
+#[test]
+fn echo_input() {
+    assert_matches_vm(",[.,]", b"echo echo echo echo\0");
+}
+
+#[test]
+fn primes() {
+    assert_matches_vm(include_str!("../benches/bf/primes.bf"), b"30\n");
+}
+
+#[test]
+fn serptri() {
+    assert_matches_vm(include_str!("../benches/bf/serptri.bf"), b"");
+}
+
+#[test]
+fn cold_loops_stay_in_the_vm() {
+    let (output, compiled) = run_tiered("+++[->++<]>.", b"", 1000);
+    assert_eq!(output, vec![6]);
+    assert_eq!(compiled, 0);
+}
+
+/// Moves a counter of n to the right by stride cells at a time, counting it
+/// down on the way, then prints an 'A' where it ends up
+fn walk_right(n: usize, stride: usize) -> String {
+    let right = ">".repeat(stride);
+    let left = "<".repeat(stride);
+    format!("{}[[-{right}+{left}]{right}-]{}.", "+".repeat(n), "+".repeat(65))
+}
+
+#[test]
+fn hot_loops_run_past_the_end_of_the_tape() {
+    // Ends up at cell 250 * 150 = 37500, past the 30000 cells of the JIT's tape
+    assert_matches_vm(&walk_right(250, 150), b"");
+}
+
+#[test]
+fn hot_scans_run_past_the_end_of_the_tape() {
+    // Every fifth cell from cell 10 on is set, one more each time round, and the
+    // scans over them reach cell 1255, past the 1024 cells the Vm's tape starts with
+    let program = format!(">>>>>{}[>>>>>[>>>>>]+<<<<<[<<<<<]>>>>>-]{}.", "+".repeat(250), "+".repeat(66));
+    assert_matches_vm(&program, b"");
+}
+
+#[test]
+fn errors_in_hot_loops_are_returned() {
+    // The first time round is interpreted, the rest compiled and reads past the input
+    let mut tiered = Tiered::with_runner(MemoryIO::new(b"ab".to_vec()), Compiler::new("+[.,]").compile(), EmulatedLoops);
+    tiered.set_threshold(1);
+    assert_eq!(tiered.run().unwrap_err().summary.status, Status::Eof);
+    assert_eq!(tiered.into_io().into_output(), vec![1, b'a', b'b']);
+
+    let mut tiered = Tiered::with_runner(MemoryIO::new(vec![]), Compiler::new(">>+[<+]").compile(), EmulatedLoops);
+    tiered.set_threshold(1);
+    let err = tiered.run().unwrap_err();
+    assert_eq!(err.summary.status, Status::TapeFault);
+    assert_eq!(err.summary.tape_offset, -1);
+}